@@ -0,0 +1,204 @@
+use clap::builder::BoolishValueParser;
+use clap::{ArgAction, Args, Parser, Subcommand, ValueEnum};
+use std::net::{IpAddr, SocketAddr};
+use std::path::PathBuf;
+
+use crate::config::ServerConfig;
+
+/// Live lap times from a Race|Result decoder.
+#[derive(Debug, Parser)]
+#[command(name = "rrclivelaps", version, about)]
+pub struct Cli {
+    #[command(flatten)]
+    pub options: Options,
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+/// Settings every subcommand understands. Each can also be set through its
+/// environment variable, which is handy for Docker and kiosk setups.
+#[derive(Debug, Args)]
+pub struct Options {
+    /// Settings imported when the database has none yet [default: config.json next to the executable]
+    #[arg(long, global = true, env = "RRCLIVELAPS_CONFIG", value_name = "FILE")]
+    pub config: Option<PathBuf>,
+    /// Transponder names imported when the database is created [default: mapping.json next to the executable]
+    #[arg(long, global = true, env = "RRCLIVELAPS_MAPPING", value_name = "FILE")]
+    pub mapping: Option<PathBuf>,
+    /// Passing journal used to recover the session after a restart [default: journal.jsonl next to the executable]
+    #[arg(long, global = true, env = "RRCLIVELAPS_JOURNAL", value_name = "FILE")]
+    pub journal: Option<PathBuf>,
+    /// History database [default: rrclivelaps.db next to the executable]
+    #[arg(long, global = true, env = "RRCLIVELAPS_DATABASE", value_name = "FILE")]
+    pub database: Option<PathBuf>,
+    /// Write PDF and HTML results sheets into this folder whenever a session finishes
+    #[arg(long, global = true, env = "RRCLIVELAPS_RESULTS_DIR", value_name = "DIR")]
+    pub results_dir: Option<PathBuf>,
+    /// Web server address as IP or IP:PORT, overriding the config file
+    #[arg(long, global = true, env = "RRCLIVELAPS_BIND", value_name = "ADDR", value_parser = parse_bind)]
+    pub bind: Option<BindOverride>,
+    /// Don't open a browser on startup
+    #[arg(long, global = true, env = "RRCLIVELAPS_NO_BROWSER", action = ArgAction::SetTrue, value_parser = BoolishValueParser::new())]
+    pub no_browser: bool,
+    /// Log the raw JSON received in TCP server mode
+    #[arg(long, global = true, env = "RRCLIVELAPS_DEBUG", action = ArgAction::SetTrue, value_parser = BoolishValueParser::new())]
+    pub debug: bool,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// Run the live timing server with the configured source (the default)
+    Serve,
+    /// Run the live timing server fed from a recorded JSON-lines file
+    Replay {
+        /// One passing per line, or feed messages as saved from /ws or /events
+        file: PathBuf,
+        /// Playback speed; 2 plays twice as fast, 0 sends everything at once, the slowest is 0.01
+        #[arg(long, default_value_t = 1.0, value_parser = parse_speed)]
+        speed: f64,
+    },
+    /// Write data out for other tools
+    Export {
+        #[command(subcommand)]
+        what: ExportCommand,
+    },
+    /// Read data into the database
+    Import {
+        #[command(subcommand)]
+        what: ImportCommand,
+    },
+    /// List serial ports, or open one and show what the timing box sends
+    ProbeSerial {
+        /// Port to open, e.g. /dev/ttyUSB0 or COM3
+        port: Option<String>,
+        /// How long to listen
+        #[arg(long, default_value_t = 10)]
+        seconds: u64,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+pub enum ExportCommand {
+    /// Transponder names as CSV
+    Mapping {
+        /// Output file [default: stdout]
+        #[arg(long, short)]
+        output: Option<PathBuf>,
+    },
+    /// Settings as JSON, in the same form `import config` reads
+    Config {
+        /// Output file [default: stdout]
+        #[arg(long, short)]
+        output: Option<PathBuf>,
+    },
+    /// Results of a finished session
+    Results {
+        /// Session id, as listed by /api/sessions [default: the last finished session]
+        session: Option<i64>,
+        #[arg(long, value_enum, default_value = "csv")]
+        format: ResultsFormat,
+        /// The lap-by-lap table instead, for CSV (the other formats have both)
+        #[arg(long)]
+        laps: bool,
+        /// Output file [default: stdout]
+        #[arg(long, short)]
+        output: Option<PathBuf>,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+pub enum ImportCommand {
+    /// Replace the settings with a JSON config file, e.g. one edited after `export config`
+    Config {
+        file: PathBuf,
+    },
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, ValueEnum)]
+pub enum ResultsFormat {
+    Csv,
+    Xlsx,
+    /// Printable results sheet with a lap chart
+    Pdf,
+    /// The same sheet as a self-contained web page
+    Html,
+}
+
+/// `--bind` either replaces just the address or address and port.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct BindOverride {
+    pub ip: IpAddr,
+    pub port: Option<u16>,
+}
+
+impl BindOverride {
+    pub fn apply(&self, server: &mut ServerConfig) {
+        server.bind = self.ip;
+        if let Some(port) = self.port {
+            server.port = port;
+        }
+    }
+}
+
+fn parse_bind(s: &str) -> Result<BindOverride, String> {
+    if let Ok(addr) = s.parse::<SocketAddr>() {
+        return Ok(BindOverride { ip: addr.ip(), port: Some(addr.port()) });
+    }
+    s.parse::<IpAddr>()
+        .map(|ip| BindOverride { ip, port: None })
+        .map_err(|_| format!("'{}' is not an IP address or IP:PORT", s))
+}
+
+/// Slowest replay speed; slower ones would stretch the gaps beyond what a timer can wait.
+const MIN_SPEED: f64 = 0.01;
+
+fn parse_speed(s: &str) -> Result<f64, String> {
+    match s.parse::<f64>() {
+        Ok(speed) if speed == 0.0 || (speed.is_finite() && speed >= MIN_SPEED) => Ok(speed),
+        _ => Err(format!("'{}' is not 0 or a speed of at least {}", s, MIN_SPEED)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_options_after_subcommand() {
+        let cli = Cli::try_parse_from(["rrclivelaps", "replay", "race.jsonl", "--speed", "4", "--bind", "0.0.0.0:9000", "--no-browser"]).unwrap();
+        assert!(cli.options.no_browser);
+        assert_eq!(cli.options.bind, Some(BindOverride { ip: "0.0.0.0".parse().unwrap(), port: Some(9000) }));
+        assert!(matches!(cli.command, Some(Command::Replay { speed, .. }) if speed == 4.0));
+
+        let cli = Cli::try_parse_from(["rrclivelaps", "--bind", "::"]).unwrap();
+        assert_eq!(cli.options.bind.unwrap().port, None);
+        assert!(cli.command.is_none());
+
+        assert!(Cli::try_parse_from(["rrclivelaps", "replay", "race.jsonl", "--speed", "-1"]).is_err());
+        assert!(Cli::try_parse_from(["rrclivelaps", "replay", "race.jsonl", "--speed", "1e-300"]).is_err());
+        assert!(Cli::try_parse_from(["rrclivelaps", "replay", "race.jsonl", "--speed", "0"]).is_ok());
+
+        let cli = Cli::try_parse_from(["rrclivelaps", "export", "results", "--format", "xlsx", "-o", "heat.xlsx"]).unwrap();
+        assert!(matches!(
+            cli.command,
+            Some(Command::Export { what: ExportCommand::Results { session: None, format: ResultsFormat::Xlsx, laps: false, .. } })
+        ));
+
+        let cli = Cli::try_parse_from(["rrclivelaps", "import", "config", "edited.json"]).unwrap();
+        assert!(matches!(cli.command, Some(Command::Import { what: ImportCommand::Config { file } }) if file.as_os_str() == "edited.json"));
+    }
+
+    #[test]
+    fn test_boolean_flags_from_environment() {
+        // Only this test sets these, and only to valid values, so parsing elsewhere is unaffected
+        for (value, expected) in [("1", true), ("yes", true), ("true", true), ("0", false), ("no", false)] {
+            std::env::set_var("RRCLIVELAPS_NO_BROWSER", value);
+            std::env::set_var("RRCLIVELAPS_DEBUG", value);
+            let cli = Cli::try_parse_from(["rrclivelaps"]).unwrap();
+            assert_eq!((cli.options.no_browser, cli.options.debug), (expected, expected), "{}", value);
+            assert!(Cli::try_parse_from(["rrclivelaps", "--no-browser"]).unwrap().options.no_browser);
+        }
+        std::env::remove_var("RRCLIVELAPS_NO_BROWSER");
+        std::env::remove_var("RRCLIVELAPS_DEBUG");
+    }
+}
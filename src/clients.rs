@@ -0,0 +1,81 @@
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+
+use crate::messages::{self, timestamp, Timestamp, WireFormat};
+
+#[derive(Clone, Debug, Serialize)]
+pub struct ClientInfo {
+    pub id: u64,
+    pub addr: Option<SocketAddr>,
+    pub user_agent: Option<String>,
+    pub format: WireFormat,
+    #[serde(with = "timestamp")]
+    pub connected_since: Timestamp,
+    /// Last frame (including pongs) received from the client.
+    #[serde(with = "timestamp")]
+    pub last_seen: Timestamp,
+}
+
+/// Live WebSocket clients, for `/api/clients`.
+#[derive(Clone, Default)]
+pub struct ClientRegistry {
+    inner: Arc<Mutex<Registry>>,
+}
+
+#[derive(Default)]
+struct Registry {
+    next_id: u64,
+    clients: BTreeMap<u64, ClientInfo>,
+}
+
+impl ClientRegistry {
+    /// Adds a client; it is removed again when the returned guard is dropped.
+    pub fn register(&self, addr: Option<SocketAddr>, user_agent: Option<String>, format: WireFormat) -> ClientGuard {
+        let mut registry = self.inner.lock().unwrap();
+        registry.next_id += 1;
+        let id = registry.next_id;
+        let now = messages::now();
+        registry.clients.insert(
+            id,
+            ClientInfo {
+                id,
+                addr,
+                user_agent,
+                format,
+                connected_since: now,
+                last_seen: now,
+            },
+        );
+
+        ClientGuard { id, registry: self.clone() }
+    }
+
+    pub fn list(&self) -> Vec<ClientInfo> {
+        self.inner.lock().unwrap().clients.values().cloned().collect()
+    }
+}
+
+pub struct ClientGuard {
+    id: u64,
+    registry: ClientRegistry,
+}
+
+impl ClientGuard {
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    pub fn touch(&self) {
+        if let Some(client) = self.registry.inner.lock().unwrap().clients.get_mut(&self.id) {
+            client.last_seen = messages::now();
+        }
+    }
+}
+
+impl Drop for ClientGuard {
+    fn drop(&mut self) {
+        self.registry.inner.lock().unwrap().clients.remove(&self.id);
+    }
+}
@@ -0,0 +1,188 @@
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tokio::sync::broadcast;
+
+use crate::config::Groups;
+use crate::messages::WsMessage;
+use crate::session::SessionSettings;
+use crate::state::{self, SessionControl, SharedState};
+use crate::subscription::{Subscription, SubscriptionRequest};
+
+/// Commands a WebSocket client can send, framed like outgoing messages:
+/// `{"id": 1, "type": "ack_alert", "data": {"id": 3}}`.
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", content = "data", rename_all = "snake_case")]
+pub enum Command {
+    /// Starts the current session over with the same settings.
+    ResetSession,
+    NewSession(SessionSettings),
+    /// Starts a waiting session, whatever its start trigger.
+    StartSession,
+    ChequeredFlag,
+    /// Ends the session without waiting for everyone to cross the line.
+    FinishSession,
+    RequestSnapshot,
+    AckAlert { id: u64 },
+    /// Replaces the client's filters; see [`SubscriptionRequest`].
+    Subscribe(SubscriptionRequest),
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CommandRequest {
+    /// Echoed back in the reply so the client can correlate it.
+    #[serde(default)]
+    pub id: Option<Value>,
+    #[serde(flatten)]
+    pub command: Command,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct CommandReply {
+    pub id: Option<Value>,
+    pub ok: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub data: Option<Value>,
+}
+
+impl CommandReply {
+    fn ok(id: Option<Value>, data: Option<Value>) -> Self {
+        Self { id, ok: true, error: None, data }
+    }
+
+    fn error(id: Option<Value>, error: impl ToString) -> Self {
+        Self { id, ok: false, error: Some(error.to_string()), data: None }
+    }
+}
+
+pub fn parse(text: &str) -> Result<CommandRequest, CommandReply> {
+    serde_json::from_str(text).map_err(|e| {
+        // Still try to correlate the error with the request
+        let id = serde_json::from_str::<Value>(text)
+            .ok()
+            .and_then(|v| v.get("id").cloned());
+        CommandReply::error(id, format!("Invalid command: {}", e))
+    })
+}
+
+/// Parses and executes one client command, returning the reply for that client.
+pub fn handle(
+    text: &str,
+    state: &SharedState,
+    tx: &broadcast::Sender<WsMessage>,
+    groups: &Groups,
+    subscription: &mut Subscription,
+) -> CommandReply {
+    let request = match parse(text) {
+        Ok(request) => request,
+        Err(reply) => return reply,
+    };
+
+    match execute(request.command, state, tx, groups, subscription) {
+        Ok(data) => CommandReply::ok(request.id, data),
+        Err(e) => CommandReply::error(request.id, e),
+    }
+}
+
+fn execute(
+    command: Command,
+    state: &SharedState,
+    tx: &broadcast::Sender<WsMessage>,
+    groups: &Groups,
+    subscription: &mut Subscription,
+) -> Result<Option<Value>, String> {
+    match command {
+        Command::ResetSession => {
+            let settings = state.lock().unwrap().session().settings.clone();
+            state::control_session(state, tx, SessionControl::New(settings))?;
+            println!("Session reset by client");
+            Ok(None)
+        }
+        Command::NewSession(settings) => session_reply(state, tx, SessionControl::New(settings)),
+        Command::StartSession => session_reply(state, tx, SessionControl::Start),
+        Command::ChequeredFlag => session_reply(state, tx, SessionControl::Chequered),
+        Command::FinishSession => session_reply(state, tx, SessionControl::Finish),
+        Command::RequestSnapshot => {
+            let snapshot = state.lock().unwrap().snapshot();
+            serde_json::to_value(snapshot)
+                .map(Some)
+                .map_err(|e| e.to_string())
+        }
+        Command::AckAlert { id } => {
+            let alert = state
+                .lock()
+                .unwrap()
+                .acknowledge_alert(id)
+                .ok_or_else(|| format!("Unknown alert {}", id))?;
+            let _ = tx.send(WsMessage::Alert(alert));
+            Ok(None)
+        }
+        Command::Subscribe(request) => {
+            *subscription = request.resolve(groups)?;
+            serde_json::to_value(&*subscription)
+                .map(Some)
+                .map_err(|e| e.to_string())
+        }
+    }
+}
+
+fn session_reply(state: &SharedState, tx: &broadcast::Sender<WsMessage>, control: SessionControl) -> Result<Option<Value>, String> {
+    let session = state::control_session(state, tx, control)?;
+    serde_json::to_value(session)
+        .map(Some)
+        .map_err(|e| e.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::LiveState;
+
+    #[test]
+    fn test_unit_command_without_data() {
+        let request = parse(r#"{"id": "a1", "type": "reset_session"}"#).unwrap();
+        assert_eq!(request.id, Some(Value::from("a1")));
+        assert!(matches!(request.command, Command::ResetSession));
+    }
+
+    #[test]
+    fn test_invalid_command_keeps_id() {
+        let reply = parse(r#"{"id": 7, "type": "launch_rockets"}"#).unwrap_err();
+        assert!(!reply.ok);
+        assert_eq!(reply.id, Some(Value::from(7)));
+    }
+
+    #[test]
+    fn test_subscribe_and_unknown_alert() {
+        let state = LiveState::shared();
+        let (tx, _rx) = broadcast::channel(10);
+        let groups = Groups::from([("junior".to_string(), vec!["0000002".to_string()])]);
+        let mut subscription = Subscription::default();
+
+        let reply = handle(
+            r#"{"id": 1, "type": "subscribe", "data": {"types": ["passing"], "transponders": ["0000001"], "groups": ["junior"]}}"#,
+            &state,
+            &tx,
+            &groups,
+            &mut subscription,
+        );
+        assert!(reply.ok);
+        assert_eq!(reply.data.unwrap()["transponders"], serde_json::json!(["0000001", "0000002"]));
+        let alert = WsMessage::Alert(state.lock().unwrap().raise_alert("x".to_string()));
+        assert!(subscription.apply(&alert).is_none());
+
+        let reply = handle(
+            r#"{"id": 2, "type": "subscribe", "data": {"groups": ["senior"]}}"#,
+            &state,
+            &tx,
+            &groups,
+            &mut subscription,
+        );
+        assert_eq!(reply.error.as_deref(), Some("Unknown group 'senior'"));
+
+        let reply = handle(r#"{"id": 3, "type": "ack_alert", "data": {"id": 99}}"#, &state, &tx, &groups, &mut subscription);
+        assert!(!reply.ok);
+        assert_eq!(reply.error.as_deref(), Some("Unknown alert 99"));
+    }
+}
@@ -1,50 +1,562 @@
 use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use std::collections::BTreeMap;
+use std::fmt;
 use std::fs;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::path::Path;
+
+use crate::db::Database;
+
+/// Schema version of a stored config; older ones are migrated on load.
+pub const CONFIG_VERSION: u32 = 2;
+
+/// Named sets of transponders (e.g. a class), group name -> transponder ids.
+pub type Groups = BTreeMap<String, Vec<String>>;
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 #[serde(tag = "mode", rename_all = "lowercase")]
 pub enum AppMode {
     Tcp {
+        /// Host name, IPv4 or IPv6 address, optionally with its own `:port`.
         host: String,
         port: u16,
+        /// Tried in order when `host` can't be resolved or reached.
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        fallback_hosts: Vec<String>,
     },
     Usb {
         port_path: String,
     },
+    #[serde(rename = "tcp_server", alias = "tcpserver")]
     TcpServer {
         port: u16,
     },
 }
 
+/// One decoder address to try: `host`, `host:port`, an IPv6 literal or `[v6]:port`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Target {
+    pub host: String,
+    pub port: u16,
+}
+
+impl Target {
+    pub fn parse(s: &str, default_port: u16) -> Result<Self, String> {
+        let s = s.trim();
+        if s.is_empty() {
+            return Err("must not be empty".to_string());
+        }
+        if let Ok(addr) = s.parse::<SocketAddr>() {
+            return Target::with_port(addr.ip().to_string(), addr.port());
+        }
+        if let Ok(ip) = s.trim_start_matches('[').trim_end_matches(']').parse::<IpAddr>() {
+            return Ok(Target { host: ip.to_string(), port: default_port });
+        }
+
+        let (host, port) = match s.rsplit_once(':') {
+            Some((host, port)) => match port.parse() {
+                Ok(port) => (host, port),
+                Err(_) => return Err(format!("'{}' has an invalid port", s)),
+            },
+            None => (s, default_port),
+        };
+        let valid = !host.is_empty()
+            && host.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '.' | '_'));
+        if !valid {
+            return Err(format!("'{}' is not a valid host name or IP address", host));
+        }
+        Target::with_port(host.to_string(), port)
+    }
+
+    fn with_port(host: String, port: u16) -> Result<Self, String> {
+        if port == 0 {
+            return Err("port must be between 1 and 65535".to_string());
+        }
+        Ok(Target { host, port })
+    }
+}
+
+impl fmt::Display for Target {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.host.contains(':') {
+            write!(f, "[{}]:{}", self.host, self.port)
+        } else {
+            write!(f, "{}:{}", self.host, self.port)
+        }
+    }
+}
+
+/// `host` followed by `fallback_hosts`, in the order they should be tried.
+pub fn tcp_targets(host: &str, port: u16, fallback_hosts: &[String]) -> Result<Vec<Target>, String> {
+    std::iter::once(host)
+        .chain(fallback_hosts.iter().map(String::as_str))
+        .map(|h| Target::parse(h, port))
+        .collect()
+}
+
+impl AppMode {
+    /// Human-readable source name used in status reports.
+    pub fn describe(&self) -> String {
+        match self {
+            AppMode::Tcp { host, port, fallback_hosts } => {
+                let target = Target::parse(host, *port).map_or_else(|_| format!("{}:{}", host, port), |t| t.to_string());
+                match fallback_hosts.len() {
+                    0 => format!("tcp {}", target),
+                    n => format!("tcp {} (+{} fallback)", target, n),
+                }
+            }
+            AppMode::Usb { port_path } => format!("usb {}", port_path),
+            AppMode::TcpServer { port } => format!("tcp_server :{}", port),
+        }
+    }
+}
+
+/// Where the web server listens. Bind to `0.0.0.0` so other devices on the
+/// network can show the live timing.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ServerConfig {
+    pub bind: IpAddr,
+    pub port: u16,
+}
+
+impl ServerConfig {
+    pub fn addr(&self) -> SocketAddr {
+        SocketAddr::new(self.bind, self.port)
+    }
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        ServerConfig {
+            bind: IpAddr::V4(Ipv4Addr::LOCALHOST),
+            port: 8080,
+        }
+    }
+}
+
+/// Which reads count as lap crossings. Times are in seconds; zero disables a rule.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+#[serde(default)]
+pub struct LapRules {
+    /// Laps shorter than this are not counted (e.g. a kart cutting back over the loop).
+    pub min_lap_time: f64,
+    /// Reads of a transponder this soon after its crossing are the same crossing.
+    pub dead_time: f64,
+    /// Reads with fewer hits are ignored. Leave at 0 if the source doesn't report hits.
+    pub min_hits: u32,
+    /// Reads with a weaker signal (RSSI) are ignored. Leave at 0 if the source doesn't report it.
+    pub min_strength: u32,
+}
+
+impl Default for LapRules {
+    fn default() -> Self {
+        LapRules {
+            min_lap_time: 0.0,
+            dead_time: 0.5,
+            min_hits: 0,
+            min_strength: 0,
+        }
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct Config {
+    pub version: u32,
     pub mode: AppMode,
+    #[serde(default)]
+    pub server: ServerConfig,
+    /// Messages buffered per display before a slow one is resynced.
+    #[serde(default = "default_broadcast_capacity")]
+    pub broadcast_capacity: usize,
+    #[serde(default)]
+    pub groups: Groups,
+    #[serde(default)]
+    pub rules: LapRules,
+}
+
+fn default_broadcast_capacity() -> usize {
+    100
 }
 
 impl Default for Config {
     fn default() -> Self {
         Config {
+            version: CONFIG_VERSION,
             mode: AppMode::Tcp {
                 host: "127.0.0.1".to_string(),
                 port: 3601,
+                fallback_hosts: Vec::new(),
             },
+            server: ServerConfig::default(),
+            broadcast_capacity: default_broadcast_capacity(),
+            groups: Groups::new(),
+            rules: LapRules::default(),
         }
     }
 }
 
-pub fn load_config(path: &str) -> Config {
-    if let Ok(content) = fs::read_to_string(path) {
-        if let Ok(config) = serde_json::from_str(&content) {
-            return config;
+impl Config {
+    /// Checks values that parse fine but can never work.
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        let mut errors = Vec::new();
+        let mut error = |field: &str, message: &str| errors.push(FieldError::new(field, message));
+
+        match &self.mode {
+            AppMode::Tcp { host, port, fallback_hosts } => {
+                if let Err(e) = Target::parse(host, *port) {
+                    error("mode.host", &e);
+                }
+                if *port == 0 {
+                    error("mode.port", "must be between 1 and 65535");
+                }
+                for (i, fallback) in fallback_hosts.iter().enumerate() {
+                    if let Err(e) = Target::parse(fallback, *port) {
+                        error(&format!("mode.fallback_hosts.{}", i), &e);
+                    }
+                }
+            }
+            AppMode::Usb { port_path } => {
+                if port_path.trim().is_empty() {
+                    error("mode.port_path", "must not be empty");
+                }
+            }
+            AppMode::TcpServer { port } => {
+                if *port == 0 {
+                    error("mode.port", "must be between 1 and 65535");
+                }
+            }
+        }
+        if self.server.port == 0 {
+            error("server.port", "must be between 1 and 65535");
+        }
+        for (field, value) in [("rules.min_lap_time", self.rules.min_lap_time), ("rules.dead_time", self.rules.dead_time)] {
+            if !value.is_finite() || value < 0.0 {
+                error(field, "must be 0 or more seconds");
+            }
+        }
+        if self.broadcast_capacity == 0 {
+            error("broadcast_capacity", "must be at least 1");
         }
+        for (name, members) in &self.groups {
+            if name.trim().is_empty() {
+                error("groups", "group names must not be empty");
+            }
+            if members.iter().any(|id| id.trim().is_empty()) {
+                error(&format!("groups.{}", name), "transponder ids must not be empty");
+            }
+        }
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(ConfigError::Invalid(errors))
+        }
+    }
+}
+
+/// A problem with one setting, reported to the manage page.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct FieldError {
+    /// Dotted path such as `mode.port`; empty if the problem is not tied to one field.
+    pub field: String,
+    pub message: String,
+}
+
+impl FieldError {
+    pub fn new(field: &str, message: &str) -> Self {
+        FieldError { field: field.to_string(), message: message.to_string() }
     }
-    let default_config = Config::default();
-    save_config(path, &default_config);
-    default_config
 }
 
-pub fn save_config(path: &str, config: &Config) {
-    if let Ok(json) = serde_json::to_string_pretty(config) {
-        let _ = fs::write(path, json);
+#[derive(Debug)]
+pub enum ConfigError {
+    Io(io::Error),
+    Db(rusqlite::Error),
+    Invalid(Vec<FieldError>),
+    /// Written by a newer release; refuse rather than drop settings we don't know.
+    TooNew(u32),
+}
+
+impl ConfigError {
+    pub fn field_errors(&self) -> Vec<FieldError> {
+        match self {
+            ConfigError::Invalid(errors) => errors.clone(),
+            other => vec![FieldError::new("", &other.to_string())],
+        }
+    }
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ConfigError::Io(e) => write!(f, "{}", e),
+            ConfigError::Db(e) => write!(f, "{}", e),
+            ConfigError::Invalid(errors) => {
+                write!(f, "invalid configuration")?;
+                for (i, e) in errors.iter().enumerate() {
+                    let sep = if i == 0 { ": " } else { "; " };
+                    match e.field.as_str() {
+                        "" => write!(f, "{}{}", sep, e.message)?,
+                        field => write!(f, "{}{}: {}", sep, field, e.message)?,
+                    }
+                }
+                Ok(())
+            }
+            ConfigError::TooNew(version) => write!(
+                f,
+                "config version {} is newer than this release supports ({})",
+                version, CONFIG_VERSION
+            ),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+impl From<io::Error> for ConfigError {
+    fn from(e: io::Error) -> Self {
+        ConfigError::Io(e)
+    }
+}
+
+impl From<rusqlite::Error> for ConfigError {
+    fn from(e: rusqlite::Error) -> Self {
+        ConfigError::Db(e)
+    }
+}
+
+/// Upgrades from version `i + 1` to `i + 2`, in order.
+const MIGRATIONS: &[fn(&mut Map<String, Value>)] = &[migrate_v1_tcp_server_mode];
+
+/// Version 1 spelled the TCP server mode `tcpserver`.
+fn migrate_v1_tcp_server_mode(config: &mut Map<String, Value>) {
+    if let Some(mode) = config.get_mut("mode").and_then(Value::as_object_mut) {
+        if mode.get("mode").and_then(Value::as_str) == Some("tcpserver") {
+            mode.insert("mode".to_string(), Value::from("tcp_server"));
+        }
+    }
+}
+
+/// Brings `value` up to `CONFIG_VERSION` and returns the version it had.
+fn migrate(value: &mut Value) -> Result<u32, ConfigError> {
+    let config = value
+        .as_object_mut()
+        .ok_or_else(|| ConfigError::Invalid(vec![FieldError::new("", "expected a JSON object")]))?;
+
+    // Files from before versioning have no version field
+    let version = match config.get("version") {
+        None => 1,
+        Some(v) => v
+            .as_u64()
+            .and_then(|v| u32::try_from(v).ok())
+            .filter(|v| *v >= 1)
+            .ok_or_else(|| ConfigError::Invalid(vec![FieldError::new("version", "must be a positive integer")]))?,
+    };
+    if version > CONFIG_VERSION {
+        return Err(ConfigError::TooNew(version));
+    }
+
+    for migration in &MIGRATIONS[(version - 1) as usize..] {
+        migration(config);
+    }
+    config.insert("version".to_string(), Value::from(CONFIG_VERSION));
+    Ok(version)
+}
+
+/// Migrates, deserializes and validates a config, e.g. one posted to `/api/config`.
+pub fn parse_value(mut value: Value) -> Result<Config, ConfigError> {
+    migrate(&mut value)?;
+    from_migrated(value)
+}
+
+fn from_migrated(value: Value) -> Result<Config, ConfigError> {
+    let mode_error = value.get("mode").and_then(mode_field_error);
+    let config: Config = serde_path_to_error::deserialize(value).map_err(|e| {
+        let path = e.path().to_string();
+        let error = match (path.as_str(), mode_error) {
+            ("mode", Some(error)) => error,
+            (".", _) => FieldError::new("", &e.into_inner().to_string()),
+            (path, _) => FieldError::new(path, &e.into_inner().to_string()),
+        };
+        ConfigError::Invalid(vec![error])
+    })?;
+    config.validate()?;
+    Ok(config)
+}
+
+/// `AppMode` is internally tagged, so serde can't say which of its fields is
+/// wrong; check the variant's fields on their own.
+fn mode_field_error(mode: &Value) -> Option<FieldError> {
+    #[derive(Deserialize)]
+    #[allow(dead_code)]
+    struct Tcp {
+        host: String,
+        port: u16,
+        #[serde(default)]
+        fallback_hosts: Vec<String>,
+    }
+    #[derive(Deserialize)]
+    #[allow(dead_code)]
+    struct Usb {
+        port_path: String,
+    }
+    #[derive(Deserialize)]
+    #[allow(dead_code)]
+    struct TcpServer {
+        port: u16,
+    }
+
+    let mut fields = mode.as_object()?.clone();
+    let tag = fields.remove("mode")?;
+    let fields = Value::Object(fields);
+    let result = match tag.as_str()? {
+        "tcp" => serde_path_to_error::deserialize::<_, Tcp>(fields).map(drop),
+        "usb" => serde_path_to_error::deserialize::<_, Usb>(fields).map(drop),
+        "tcp_server" | "tcpserver" => serde_path_to_error::deserialize::<_, TcpServer>(fields).map(drop),
+        _ => return None,
+    };
+    let e = result.err()?;
+    let field = match e.path().to_string().as_str() {
+        "." => "mode".to_string(),
+        path => format!("mode.{}", path),
+    };
+    Some(FieldError { field, message: e.into_inner().to_string() })
+}
+
+fn parse(content: &str) -> Result<Config, ConfigError> {
+    let value: Value = serde_json::from_str(content).map_err(|e| {
+        ConfigError::Invalid(vec![FieldError::new("", &e.to_string())])
+    })?;
+    parse_value(value)
+}
+
+/// Reads a config file, e.g. a `config.json` from an older release. `None`
+/// if there is no file; the file itself is never written.
+pub fn read_config(path: &Path) -> Result<Option<Config>, ConfigError> {
+    match fs::read_to_string(path) {
+        Ok(content) => parse(&content).map(Some),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// The config stored in `db`. Until one has been stored, the file at
+/// `import_path` is imported (or the defaults used if there is none); a file
+/// that fails to parse or validate is reported and nothing is stored, so it
+/// can still be fixed.
+pub fn load_config(db: &Database, import_path: &Path) -> Result<Config, ConfigError> {
+    if let Some(value) = db.config()? {
+        return parse_value(value);
+    }
+
+    let config = match read_config(import_path)? {
+        Some(config) => {
+            println!("Importing settings from {:?} into the database", import_path);
+            config
+        }
+        None => Config::default(),
+    };
+    db.save_config(&config)?;
+    Ok(config)
+}
+
+/// The stored config, or the file `load_config` would import, as JSON and
+/// migrated as far as it goes, so the manage page can fix one it rejects
+/// without losing the settings it does not show. `None` if it isn't JSON.
+pub fn raw_config(db: &Database, import_path: &Path) -> Option<Value> {
+    let mut value = match db.config().ok()? {
+        Some(value) => value,
+        None => serde_json::from_str(&fs::read_to_string(import_path).ok()?).ok()?,
+    };
+    let _ = migrate(&mut value);
+    Some(value)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_path(name: &str) -> std::path::PathBuf {
+        std::env::temp_dir().join(format!("rrclivelaps-{}-{}.json", name, std::process::id()))
+    }
+
+    #[test]
+    fn test_imports_and_migrates_unversioned_tcpserver_config() {
+        let path = temp_path("migrate");
+        let content = r#"{"mode":{"mode":"tcpserver","port":3602}}"#;
+        fs::write(&path, content).unwrap();
+
+        let db = Database::in_memory().unwrap();
+        let config = load_config(&db, &path).unwrap();
+        assert!(matches!(config.mode, AppMode::TcpServer { port: 3602 }));
+        assert_eq!(config.version, CONFIG_VERSION);
+
+        let stored = db.config().unwrap().unwrap();
+        assert_eq!(stored["mode"]["mode"], "tcp_server");
+        assert_eq!(stored["version"], CONFIG_VERSION);
+        assert_eq!(fs::read_to_string(&path).unwrap(), content);
+
+        // Imported once; later edits to the file are not picked up
+        fs::write(&path, r#"{"mode":{"mode":"tcp_server","port":4000}}"#).unwrap();
+        assert!(matches!(load_config(&db, &path).unwrap().mode, AppMode::TcpServer { port: 3602 }));
+
+        let _ = fs::remove_file(&path);
+    }
+
+    #[test]
+    fn test_invalid_config_is_reported_and_not_imported() {
+        let path = temp_path("invalid");
+        let content = r#"{"version":2,"mode":{"mode":"tcp","host":"10.0.0.1","port":"3601"}}"#;
+        fs::write(&path, content).unwrap();
+
+        let db = Database::in_memory().unwrap();
+        let errors = load_config(&db, &path).unwrap_err().field_errors();
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].field, "mode.port");
+        assert!(db.config().unwrap().is_none());
+        assert_eq!(fs::read_to_string(&path).unwrap(), content);
+
+        let _ = fs::remove_file(&path);
+    }
+
+    #[test]
+    fn test_defaults_without_a_file() {
+        let db = Database::in_memory().unwrap();
+        let config = load_config(&db, &temp_path("missing")).unwrap();
+        assert!(matches!(config.mode, AppMode::Tcp { .. }));
+        assert!(db.config().unwrap().is_some());
+    }
+
+    #[test]
+    fn test_parse_targets() {
+        let targets = tcp_targets("converter.local", 3601, &["fe80::1".to_string(), "[::1]:3700".to_string(), "10.0.0.2:3602".to_string()]).unwrap();
+        let shown: Vec<String> = targets.iter().map(|t| t.to_string()).collect();
+        assert_eq!(shown, vec!["converter.local:3601", "[fe80::1]:3601", "[::1]:3700", "10.0.0.2:3602"]);
+
+        assert!(Target::parse("converter.local:0", 3601).is_err());
+        assert!(Target::parse("converter.local:abc", 3601).is_err());
+        assert!(Target::parse("http://converter", 3601).is_err());
+    }
+
+    #[test]
+    fn test_validation_reports_every_field() {
+        let value = serde_json::json!({
+            "mode": { "mode": "tcp", "host": "", "port": 0 },
+            "broadcast_capacity": 0,
+        });
+        let fields: Vec<String> = parse_value(value).unwrap_err().field_errors().into_iter().map(|e| e.field).collect();
+        assert_eq!(fields, vec!["mode.host", "mode.port", "broadcast_capacity"]);
+
+        let value = serde_json::json!({
+            "mode": { "mode": "tcp", "host": "converter.local", "port": 3601, "fallback_hosts": ["10.0.0.2", "bad host"] },
+        });
+        let errors = parse_value(value).unwrap_err().field_errors();
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].field, "mode.fallback_hosts.1");
+
+        let too_new = serde_json::json!({ "version": CONFIG_VERSION + 1, "mode": { "mode": "usb", "port_path": "COM3" } });
+        assert!(matches!(parse_value(too_new), Err(ConfigError::TooNew(_))));
     }
 }
@@ -1,15 +1,20 @@
 // use chrono::{NaiveDate, NaiveTime, NaiveDateTime};
 use futures::{SinkExt, StreamExt};
-use serde::{Deserialize, Serialize};
 use std::net::IpAddr;
 use std::time::Duration;
-use tokio::net::TcpStream;
-use tokio::sync::broadcast;
+use tokio::net::{lookup_host, TcpStream};
 use tokio::time::interval;
 use tokio_util::codec::{Framed, LinesCodec};
-use std::sync::{atomic::{AtomicBool, Ordering}, Arc};
 
-use crate::messages::{WsMessage, Passing};
+use crate::config::Target;
+use crate::messages::{self, Passing};
+use crate::state::PassingSender;
+use crate::status::{SourceState, StatusHandle};
+
+/// Delay between reconnection attempts.
+const RETRY_DELAY: Duration = Duration::from_secs(5);
+/// Per address, so an unreachable primary doesn't hold up the fallbacks for long.
+pub const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
 
 // Remove local definitions
 /*
@@ -22,54 +27,79 @@ pub enum WsMessage { ... }
 */
 
 pub struct Decoder {
-    ip: IpAddr,
-    port: u16,
+    /// Primary first, then the fallbacks.
+    targets: Vec<Target>,
 }
 
 impl Decoder {
-    pub fn new(ip: IpAddr, port: u16) -> Self {
-        Self { ip, port }
+    pub fn new(targets: Vec<Target>) -> Self {
+        Self { targets }
     }
 
-    pub async fn run(&self, tx: broadcast::Sender<WsMessage>, is_connected: Arc<AtomicBool>) {
-        println!("Connecting to decoder at {}:{}", self.ip, self.port);
-        match TcpStream::connect((self.ip, self.port)).await {
-            Ok(socket) => {
-                println!("Connected to decoder");
-                
-                // Status: Connected
-                println!("Decoder connection established at {}:{}", self.ip, self.port);
-                is_connected.store(true, Ordering::SeqCst);
-                let msg = WsMessage::Status { event: "connected".to_string() };
-                if let Err(e) = tx.send(msg) {
-                    eprintln!("Failed to broadcast connected status: {}", e);
-                } else {
-                     println!("Broadcasted 'connected' status to WebSocket");
+    pub async fn run(&self, passings: PassingSender, status: StatusHandle) {
+        loop {
+            status.set_state(SourceState::Connecting);
+            match self.connect().await {
+                Ok((socket, target)) => {
+                    println!("Decoder connection established at {}", target);
+
+                    if let Err(e) = self.handle_connection(socket, &passings, &status).await {
+                        eprintln!("Connection error: {}", e);
+                        status.set_error(SourceState::Retrying, format!("{}: {}", target, e));
+                    }
                 }
-
-                if let Err(e) = self.handle_connection(socket, &tx).await {
-                    eprintln!("Connection error: {}", e);
+                Err(e) => {
+                    eprintln!("Failed to connect: {}", e);
+                    status.set_error(SourceState::Retrying, e);
                 }
             }
-            Err(e) => {
-                eprintln!("Failed to connect: {}", e);
-            }
+
+            tokio::time::sleep(RETRY_DELAY).await;
         }
+    }
 
-        // Status: Disconnected
-        // Only send if we were previously connected (or just ensure state is false)
-        if is_connected.load(Ordering::SeqCst) {
-            is_connected.store(false, Ordering::SeqCst);
-            let _ = tx.send(WsMessage::Status { event: "disconnected".to_string() });
+    /// Tries each target in order, and every address a name resolves to,
+    /// until one connects. Starts from the primary again on every attempt.
+    async fn connect(&self) -> Result<(TcpStream, &Target), String> {
+        let mut errors = Vec::new();
+        for target in &self.targets {
+            let addrs: Vec<_> = match lookup_host((target.host.as_str(), target.port)).await {
+                Ok(addrs) => addrs.collect(),
+                Err(e) => {
+                    errors.push(format!("{}: could not resolve host ({})", target, e));
+                    continue;
+                }
+            };
+            if addrs.is_empty() {
+                errors.push(format!("{}: host has no addresses", target));
+            }
+
+            for addr in addrs {
+                // Only mention the address when it isn't already in the target
+                let label = if target.host.parse::<IpAddr>().is_ok() {
+                    target.to_string()
+                } else {
+                    format!("{} ({})", target, addr)
+                };
+                println!("Connecting to decoder at {}", label);
+                match tokio::time::timeout(CONNECT_TIMEOUT, TcpStream::connect(addr)).await {
+                    Ok(Ok(socket)) => return Ok((socket, target)),
+                    Ok(Err(e)) => errors.push(format!("{}: {}", label, e)),
+                    Err(_) => errors.push(format!("{}: connection timed out", label)),
+                }
+            }
         }
+        Err(errors.join("; "))
     }
 
     async fn handle_connection(
         &self,
         socket: TcpStream,
-        tx: &broadcast::Sender<WsMessage>,
+        passings: &PassingSender,
+        status: &StatusHandle,
     ) -> Result<(), Box<dyn std::error::Error>> {
         let mut framed = Framed::new(socket, LinesCodec::new());
+        status.set_state(SourceState::Handshaking);
 
         // Initialize protocol
         framed.send("SETPROTOCOL;2.0").await?;
@@ -98,6 +128,8 @@ impl Decoder {
             return Err("Connection closed during initialization".into());
         }
 
+        status.set_state(SourceState::Connected);
+
         // Ping interval
         let mut ping_interval = interval(Duration::from_secs(30));
 
@@ -106,7 +138,7 @@ impl Decoder {
                 line = framed.next() => {
                     match line {
                         Some(Ok(msg)) => {
-                            self.process_message(&msg, tx);
+                            self.process_message(&msg, passings, status);
                         }
                         Some(Err(e)) => return Err(Box::new(e)),
                         None => return Err("Connection closed".into()),
@@ -119,7 +151,7 @@ impl Decoder {
         }
     }
 
-    fn process_message(&self, msg: &str, tx: &broadcast::Sender<WsMessage>) {
+    fn process_message(&self, msg: &str, passings: &PassingSender, status: &StatusHandle) {
         if msg.starts_with("#P") {
              println!("Received Passing: {}", msg);
         }
@@ -144,24 +176,33 @@ impl Decoder {
                     let transponder = get_part(2);
                     let date_str = get_part(3);
                     let time_str = get_part(4);
-                    
-                    // Combine date and time for ISO string
-                    let iso_date = format!("{}T{}", date_str, time_str);
+
+                    // Decoder reports local wall-clock date and time without an offset
+                    let timestamp = chrono::NaiveDateTime::parse_from_str(
+                        &format!("{}T{}", date_str, time_str),
+                        "%Y-%m-%dT%H:%M:%S%.f",
+                    )
+                    .ok()
+                    .and_then(messages::local_timestamp)
+                    .unwrap_or_else(|| {
+                        eprintln!("Invalid passing time '{} {}', using receive time. Original data: {}", date_str, time_str, msg);
+                        messages::now()
+                    });
 
                     let passing = Passing {
                         passing_number: passing_number.parse().unwrap_or(0),
-                        transponder: transponder,
-                        date: iso_date,
-                        time: time_str.clone(),
-                        rtc_time: format!("{}T{}", date_str, time_str), // Using rtc_time as iso_date for now? or add field
+                        transponder,
+                        timestamp,
                         strength: get_part(7).parse().unwrap_or(0), // max_rssi
                         tran_code: get_part(8), // internal_data?
                         noise: 0,
                         hits: get_part(6).parse().unwrap_or(0),
+                        timing_point: Some(get_part(11)).filter(|loop_id| !loop_id.is_empty()),
                     };
-                    
-                    if let Err(e) = tx.send(WsMessage::Passing(passing)) {
-                        eprintln!("Error broadcasting passing: {}. Original data: {}", e, msg);
+
+                    status.record_passing(&passing);
+                    if let Err(e) = passings.send(passing) {
+                        eprintln!("Error recording passing: {}. Original data: {}", e, msg);
                     } else {
                         println!("Broadcasted passing {} to WebSocket", passing_number);
                     }
@@ -0,0 +1,579 @@
+use rusqlite::{params, params_from_iter, Connection, OptionalExtension, Row};
+use serde::Serialize;
+use serde_json::Value;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+use crate::config::Config;
+use crate::laps::Lap;
+use crate::messages::{self, seconds, timestamp, Passing, Timestamp};
+use crate::session::{Session, SessionSettings};
+use crate::standings::Standings;
+
+pub type SharedDatabase = Arc<Database>;
+
+/// Schema changes in order; `PRAGMA user_version` records how many have run.
+/// Never edit a released entry, add a new one instead.
+const MIGRATIONS: &[&str] = &[
+    // 1: initial schema
+    "CREATE TABLE sessions (
+        id INTEGER PRIMARY KEY,
+        name TEXT NOT NULL,
+        kind TEXT NOT NULL,
+        settings TEXT NOT NULL,
+        phase TEXT NOT NULL,
+        created_at TEXT NOT NULL UNIQUE,
+        started_at TEXT,
+        chequered_at TEXT,
+        finished_at TEXT,
+        finished TEXT NOT NULL DEFAULT '[]'
+    );
+    CREATE TABLE passings (
+        id INTEGER PRIMARY KEY,
+        session_id INTEGER REFERENCES sessions(id),
+        passing_number INTEGER NOT NULL,
+        transponder TEXT NOT NULL,
+        timestamp TEXT NOT NULL,
+        strength INTEGER NOT NULL,
+        hits INTEGER NOT NULL,
+        noise INTEGER NOT NULL,
+        tran_code TEXT NOT NULL,
+        timing_point TEXT
+    );
+    CREATE INDEX passings_session ON passings(session_id);
+    CREATE TABLE laps (
+        id INTEGER PRIMARY KEY,
+        session_id INTEGER NOT NULL REFERENCES sessions(id),
+        transponder TEXT NOT NULL,
+        lap_number INTEGER NOT NULL,
+        lap_time REAL NOT NULL,
+        is_best INTEGER NOT NULL,
+        total_time REAL NOT NULL,
+        timestamp TEXT NOT NULL,
+        timing_point TEXT
+    );
+    CREATE INDEX laps_session ON laps(session_id, transponder);
+    CREATE TABLE competitors (
+        transponder TEXT PRIMARY KEY,
+        name TEXT NOT NULL,
+        updated_at TEXT NOT NULL
+    );
+    CREATE TABLE mapping_changes (
+        id INTEGER PRIMARY KEY,
+        at TEXT NOT NULL,
+        transponder TEXT NOT NULL,
+        old_name TEXT,
+        new_name TEXT
+    );
+    CREATE TABLE events (
+        id INTEGER PRIMARY KEY,
+        at TEXT NOT NULL,
+        session_id INTEGER REFERENCES sessions(id),
+        kind TEXT NOT NULL,
+        detail TEXT NOT NULL
+    );",
+    // 2: latest standings of each session, for history and results
+    "ALTER TABLE sessions ADD COLUMN standings TEXT;",
+    // 3: settings, which used to live in config.json
+    "CREATE TABLE config (
+        id INTEGER PRIMARY KEY CHECK (id = 1),
+        body TEXT NOT NULL,
+        saved_at TEXT NOT NULL
+    );",
+];
+
+/// Most rows a history query returns at once.
+pub const MAX_PAGE: u32 = 500;
+
+/// Time window and page for history queries. Times compare as instants, so
+/// any UTC offset works.
+#[derive(Clone, Debug, Default)]
+pub struct HistoryFilter {
+    pub from: Option<Timestamp>,
+    pub to: Option<Timestamp>,
+    pub limit: u32,
+    pub offset: u32,
+}
+
+/// One page of a history query.
+#[derive(Debug, Serialize)]
+pub struct Page<T> {
+    /// Rows matching the filter, across all pages.
+    pub total: u32,
+    pub limit: u32,
+    pub offset: u32,
+    pub items: Vec<T>,
+}
+
+/// A stored session.
+#[derive(Debug, Serialize)]
+pub struct SessionRecord {
+    pub id: i64,
+    #[serde(with = "timestamp")]
+    pub created_at: Timestamp,
+    #[serde(flatten)]
+    pub session: Session,
+    /// Laps stored for the session.
+    pub laps: u32,
+    /// Transponders with at least one lap.
+    pub competitors: u32,
+    /// Standings when the session was last updated; only for a single session.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub standings: Option<Standings>,
+}
+
+#[derive(Debug)]
+pub enum DbError {
+    Sqlite(rusqlite::Error),
+    /// Written by a newer release; refuse rather than corrupt it.
+    TooNew(u32),
+}
+
+impl fmt::Display for DbError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DbError::Sqlite(e) => write!(f, "{}", e),
+            DbError::TooNew(version) => write!(
+                f,
+                "database schema version {} is newer than this release supports ({})",
+                version,
+                MIGRATIONS.len()
+            ),
+        }
+    }
+}
+
+impl std::error::Error for DbError {}
+
+impl From<rusqlite::Error> for DbError {
+    fn from(e: rusqlite::Error) -> Self {
+        DbError::Sqlite(e)
+    }
+}
+
+/// Local SQLite store for sessions, passings, laps, competitor names and an
+/// event log. Shared by the live state (which writes as things happen) and
+/// the API (which reads history).
+pub struct Database {
+    conn: Mutex<Connection>,
+    created: bool,
+}
+
+impl Database {
+    /// Opens the database at `path`, creating it and running any pending
+    /// migrations.
+    pub fn open(path: &Path) -> Result<Database, DbError> {
+        Self::setup(Connection::open(path)?)
+    }
+
+    /// A database that lasts only as long as the process.
+    pub fn in_memory() -> Result<Database, DbError> {
+        Self::setup(Connection::open_in_memory()?)
+    }
+
+    fn setup(mut conn: Connection) -> Result<Database, DbError> {
+        // WAL lets the CLI read while the server writes
+        conn.pragma_update(None, "journal_mode", "WAL")?;
+        conn.pragma_update(None, "foreign_keys", true)?;
+
+        let version: u32 = conn.pragma_query_value(None, "user_version", |row| row.get(0))?;
+        if version as usize > MIGRATIONS.len() {
+            return Err(DbError::TooNew(version));
+        }
+        for (i, migration) in MIGRATIONS.iter().enumerate().skip(version as usize) {
+            let tx = conn.transaction()?;
+            tx.execute_batch(migration)?;
+            tx.pragma_update(None, "user_version", i as u32 + 1)?;
+            tx.commit()?;
+        }
+
+        Ok(Database { conn: Mutex::new(conn), created: version == 0 })
+    }
+
+    /// Whether this run created the database, e.g. to import old files once.
+    pub fn created(&self) -> bool {
+        self.created
+    }
+
+    /// Row id of the session created at `created_at`, adding it if needed.
+    pub fn session_id(&self, session: &Session, created_at: Timestamp) -> rusqlite::Result<i64> {
+        let conn = self.conn.lock().unwrap();
+        let created_at = timestamp::format(&created_at);
+        let existing = conn
+            .query_row("SELECT id FROM sessions WHERE created_at = ?1", [&created_at], |row| row.get(0))
+            .optional()?;
+        if let Some(id) = existing {
+            return Ok(id);
+        }
+        conn.execute(
+            "INSERT INTO sessions (name, kind, settings, phase, created_at) VALUES (?1, ?2, ?3, ?4, ?5)",
+            params![
+                session.settings.name,
+                to_text(&session.settings.kind),
+                serde_json::to_string(&session.settings).unwrap_or_default(),
+                session.phase.as_str(),
+                created_at
+            ],
+        )?;
+        Ok(conn.last_insert_rowid())
+    }
+
+    pub fn update_session(&self, id: i64, session: &Session, standings: &Standings) -> rusqlite::Result<()> {
+        self.conn.lock().unwrap().execute(
+            "UPDATE sessions SET phase = ?2, started_at = ?3, chequered_at = ?4, finished_at = ?5, finished = ?6, standings = ?7
+             WHERE id = ?1",
+            params![
+                id,
+                session.phase.as_str(),
+                session.started_at.as_ref().map(timestamp::format),
+                session.chequered_at.as_ref().map(timestamp::format),
+                session.finished_at.as_ref().map(timestamp::format),
+                serde_json::to_string(&session.finished).unwrap_or_default(),
+                serde_json::to_string(standings).unwrap_or_default()
+            ],
+        )?;
+        Ok(())
+    }
+
+    /// Sessions created within the filter's window, most recently added first.
+    pub fn sessions(&self, filter: &HistoryFilter) -> rusqlite::Result<Page<SessionRecord>> {
+        let conn = self.conn.lock().unwrap();
+        let (condition, args) = time_window("s.created_at", filter, Vec::new());
+        let total = conn.query_row(
+            &format!("SELECT count(*) FROM sessions s WHERE {}", condition),
+            params_from_iter(&args),
+            |row| row.get(0),
+        )?;
+        let mut stmt = conn.prepare(&format!(
+            "{} WHERE {} ORDER BY s.id DESC LIMIT {} OFFSET {}",
+            SESSION_QUERY, condition, filter.limit, filter.offset
+        ))?;
+        let items = stmt
+            .query_map(params_from_iter(&args), |row| session_record(row, false))?
+            .collect::<rusqlite::Result<_>>()?;
+        Ok(Page { total, limit: filter.limit, offset: filter.offset, items })
+    }
+
+    /// A session with its standings.
+    pub fn session(&self, id: i64) -> rusqlite::Result<Option<SessionRecord>> {
+        self.conn
+            .lock()
+            .unwrap()
+            .query_row(&format!("{} WHERE s.id = ?1", SESSION_QUERY), [id], |row| session_record(row, true))
+            .optional()
+    }
+
+    /// Most recently added session that has finished.
+    pub fn last_finished_session(&self) -> rusqlite::Result<Option<i64>> {
+        self.conn
+            .lock()
+            .unwrap()
+            .query_row("SELECT max(id) FROM sessions WHERE phase = 'finished'", [], |row| row.get(0))
+    }
+
+    /// Every lap of a session, in the order they were completed.
+    pub fn all_laps(&self, session_id: i64) -> rusqlite::Result<Vec<Lap>> {
+        let filter = HistoryFilter { limit: u32::MAX, ..Default::default() };
+        Ok(self.laps(session_id, None, &filter)?.items)
+    }
+
+    /// Laps of a session within the filter's window, in the order they were
+    /// completed, optionally for one transponder. `best_lap` is the best up
+    /// to each lap, as it was broadcast.
+    pub fn laps(&self, session_id: i64, transponder: Option<&str>, filter: &HistoryFilter) -> rusqlite::Result<Page<Lap>> {
+        let conn = self.conn.lock().unwrap();
+        let mut condition = "session_id = ?1".to_string();
+        let mut args = vec![session_id.to_string()];
+        if let Some(transponder) = transponder {
+            args.push(transponder.to_string());
+            condition.push_str(&format!(" AND transponder = ?{}", args.len()));
+        }
+        let (window, args) = time_window("timestamp", filter, args);
+        let condition = format!("{} AND {}", condition, window);
+
+        let total = conn.query_row(
+            &format!("SELECT count(*) FROM laps WHERE {}", condition),
+            params_from_iter(&args),
+            |row| row.get(0),
+        )?;
+        // The running best is taken over all the session's laps, before the window applies
+        let mut stmt = conn.prepare(&format!(
+            "SELECT transponder, lap_number, lap_time, best_lap, is_best, total_time, timestamp, timing_point
+             FROM (SELECT *, min(lap_time) OVER (PARTITION BY transponder ORDER BY lap_number) AS best_lap
+                   FROM laps WHERE session_id = ?1)
+             WHERE {} ORDER BY julianday(timestamp), id LIMIT {} OFFSET {}",
+            condition, filter.limit, filter.offset
+        ))?;
+        let items = stmt
+            .query_map(params_from_iter(&args), |row| {
+                Ok(Lap {
+                    transponder: row.get(0)?,
+                    lap_number: row.get(1)?,
+                    lap_time: seconds::from_secs(row.get(2)?),
+                    best_lap: seconds::from_secs(row.get(3)?),
+                    is_best: row.get(4)?,
+                    total_time: seconds::from_secs(row.get(5)?),
+                    timestamp: parse_timestamp(row, 6)?,
+                    timing_point: row.get(7)?,
+                })
+            })?
+            .collect::<rusqlite::Result<_>>()?;
+        Ok(Page { total, limit: filter.limit, offset: filter.offset, items })
+    }
+
+    pub fn insert_passing(&self, session_id: Option<i64>, passing: &Passing) -> rusqlite::Result<()> {
+        self.conn.lock().unwrap().execute(
+            "INSERT INTO passings (session_id, passing_number, transponder, timestamp, strength, hits, noise, tran_code, timing_point)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)",
+            params![
+                session_id,
+                passing.passing_number,
+                passing.transponder,
+                timestamp::format(&passing.timestamp),
+                passing.strength,
+                passing.hits,
+                passing.noise,
+                passing.tran_code,
+                passing.timing_point
+            ],
+        )?;
+        Ok(())
+    }
+
+    pub fn insert_lap(&self, session_id: i64, lap: &Lap) -> rusqlite::Result<()> {
+        self.conn.lock().unwrap().execute(
+            "INSERT INTO laps (session_id, transponder, lap_number, lap_time, is_best, total_time, timestamp, timing_point)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+            params![
+                session_id,
+                lap.transponder,
+                lap.lap_number,
+                seconds::to_secs(&lap.lap_time),
+                lap.is_best,
+                seconds::to_secs(&lap.total_time),
+                timestamp::format(&lap.timestamp),
+                lap.timing_point
+            ],
+        )?;
+        Ok(())
+    }
+
+    /// Adds an entry to the event log, e.g. `config_saved` with the new config.
+    pub fn log_event(&self, session_id: Option<i64>, kind: &str, detail: &Value) -> rusqlite::Result<()> {
+        self.conn.lock().unwrap().execute(
+            "INSERT INTO events (at, session_id, kind, detail) VALUES (?1, ?2, ?3, ?4)",
+            params![timestamp::format(&messages::now()), session_id, kind, detail.to_string()],
+        )?;
+        Ok(())
+    }
+
+    /// The stored config as it was saved, or `None` before the first save.
+    /// Left as JSON so an older version can be migrated on load.
+    pub fn config(&self) -> rusqlite::Result<Option<Value>> {
+        let conn = self.conn.lock().unwrap();
+        conn.query_row("SELECT body FROM config WHERE id = 1", [], |row| from_json(row, 0))
+            .optional()
+    }
+
+    /// Replaces the stored config and logs it as a `config_saved` event.
+    pub fn save_config(&self, config: &Config) -> rusqlite::Result<()> {
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction()?;
+        let now = timestamp::format(&messages::now());
+        let body = serde_json::to_string(config).unwrap_or_default();
+        tx.execute(
+            "INSERT INTO config (id, body, saved_at) VALUES (1, ?1, ?2)
+             ON CONFLICT(id) DO UPDATE SET body = excluded.body, saved_at = excluded.saved_at",
+            params![body, now],
+        )?;
+        tx.execute(
+            "INSERT INTO events (at, session_id, kind, detail) VALUES (?1, NULL, 'config_saved', ?2)",
+            params![now, body],
+        )?;
+        tx.commit()
+    }
+
+    /// Transponder names, as served to the pages as `mapping.json`.
+    pub fn mapping(&self) -> rusqlite::Result<BTreeMap<String, String>> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare("SELECT transponder, name FROM competitors")?;
+        let rows = stmt.query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?;
+        rows.collect()
+    }
+
+    /// Replaces all transponder names, recording what changed. Returns the
+    /// number of changes.
+    pub fn save_mapping(&self, mapping: &HashMap<String, String>) -> rusqlite::Result<usize> {
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction()?;
+        let now = timestamp::format(&messages::now());
+
+        let old: BTreeMap<String, String> = {
+            let mut stmt = tx.prepare("SELECT transponder, name FROM competitors")?;
+            let rows = stmt.query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?;
+            rows.collect::<rusqlite::Result<_>>()?
+        };
+
+        let mut changes = 0;
+        for (transponder, old_name) in &old {
+            if !mapping.contains_key(transponder) {
+                tx.execute("DELETE FROM competitors WHERE transponder = ?1", [transponder])?;
+                tx.execute(
+                    "INSERT INTO mapping_changes (at, transponder, old_name, new_name) VALUES (?1, ?2, ?3, NULL)",
+                    params![now, transponder, old_name],
+                )?;
+                changes += 1;
+            }
+        }
+        for (transponder, name) in mapping {
+            let old_name = old.get(transponder);
+            if old_name == Some(name) {
+                continue;
+            }
+            tx.execute(
+                "INSERT INTO competitors (transponder, name, updated_at) VALUES (?1, ?2, ?3)
+                 ON CONFLICT(transponder) DO UPDATE SET name = excluded.name, updated_at = excluded.updated_at",
+                params![transponder, name, now],
+            )?;
+            tx.execute(
+                "INSERT INTO mapping_changes (at, transponder, old_name, new_name) VALUES (?1, ?2, ?3, ?4)",
+                params![now, transponder, old_name, name],
+            )?;
+            changes += 1;
+        }
+
+        tx.commit()?;
+        Ok(changes)
+    }
+}
+
+const SESSION_QUERY: &str = "SELECT s.id, s.created_at, s.settings, s.phase, s.started_at, s.chequered_at, s.finished_at, s.finished,
+        (SELECT count(*) FROM laps l WHERE l.session_id = s.id),
+        (SELECT count(DISTINCT transponder) FROM laps l WHERE l.session_id = s.id),
+        s.standings
+    FROM sessions s";
+
+fn session_record(row: &Row, with_standings: bool) -> rusqlite::Result<SessionRecord> {
+    let settings: SessionSettings = from_json(row, 2)?;
+    let mut session = Session::new(settings);
+    session.phase = from_text(row, 3)?;
+    session.started_at = parse_optional_timestamp(row, 4)?;
+    session.chequered_at = parse_optional_timestamp(row, 5)?;
+    session.finished_at = parse_optional_timestamp(row, 6)?;
+    session.finished = from_json(row, 7)?;
+
+    let standings = match row.get::<_, Option<String>>(10)? {
+        Some(text) if with_standings => Some(serde_json::from_str(&text).map_err(|e| conversion_error(10, e))?),
+        _ => None,
+    };
+    Ok(SessionRecord {
+        id: row.get(0)?,
+        created_at: parse_timestamp(row, 1)?,
+        session,
+        laps: row.get(8)?,
+        competitors: row.get(9)?,
+        standings,
+    })
+}
+
+/// SQL condition keeping `column` within the filter's window, with its
+/// arguments appended to `args`.
+fn time_window(column: &str, filter: &HistoryFilter, mut args: Vec<String>) -> (String, Vec<String>) {
+    let mut conditions = vec!["1".to_string()];
+    if let Some(from) = &filter.from {
+        args.push(timestamp::format(from));
+        conditions.push(format!("julianday({}) >= julianday(?{})", column, args.len()));
+    }
+    if let Some(to) = &filter.to {
+        args.push(timestamp::format(to));
+        conditions.push(format!("julianday({}) < julianday(?{})", column, args.len()));
+    }
+    (conditions.join(" AND "), args)
+}
+
+fn parse_timestamp(row: &Row, idx: usize) -> rusqlite::Result<Timestamp> {
+    let text: String = row.get(idx)?;
+    chrono::DateTime::parse_from_rfc3339(&text).map_err(|e| conversion_error(idx, e))
+}
+
+fn parse_optional_timestamp(row: &Row, idx: usize) -> rusqlite::Result<Option<Timestamp>> {
+    match row.get::<_, Option<String>>(idx)? {
+        Some(text) => chrono::DateTime::parse_from_rfc3339(&text).map(Some).map_err(|e| conversion_error(idx, e)),
+        None => Ok(None),
+    }
+}
+
+fn from_json<T: serde::de::DeserializeOwned>(row: &Row, idx: usize) -> rusqlite::Result<T> {
+    let text: String = row.get(idx)?;
+    serde_json::from_str(&text).map_err(|e| conversion_error(idx, e))
+}
+
+/// Reads a value stored with [`to_text`].
+fn from_text<T: serde::de::DeserializeOwned>(row: &Row, idx: usize) -> rusqlite::Result<T> {
+    let text: String = row.get(idx)?;
+    serde_json::from_value(Value::String(text)).map_err(|e| conversion_error(idx, e))
+}
+
+fn conversion_error(idx: usize, e: impl std::error::Error + Send + Sync + 'static) -> rusqlite::Error {
+    rusqlite::Error::FromSqlConversionFailure(idx, rusqlite::types::Type::Text, Box::new(e))
+}
+
+/// Serde name of a unit enum value, e.g. `"race"`.
+fn to_text(value: &impl serde::Serialize) -> String {
+    match serde_json::to_value(value) {
+        Ok(Value::String(s)) => s,
+        _ => String::new(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::session::{SessionKind, SessionSettings};
+
+    #[test]
+    fn test_migrations_and_reopen() {
+        let path = std::path::PathBuf::from("test_rrclivelaps.db");
+        let _ = std::fs::remove_file(&path);
+
+        let db = Database::open(&path).unwrap();
+        assert!(db.created());
+        let settings = SessionSettings { name: "Heat 1".to_string(), kind: SessionKind::Race, ..Default::default() };
+        let created_at = messages::now();
+        let id = db.session_id(&Session::new(settings.clone()), created_at).unwrap();
+        drop(db);
+
+        let db = Database::open(&path).unwrap();
+        assert!(!db.created());
+        // Same session is found again rather than added twice
+        assert_eq!(db.session_id(&Session::new(settings), created_at).unwrap(), id);
+        drop(db);
+
+        let conn = Connection::open(&path).unwrap();
+        conn.pragma_update(None, "user_version", 99).unwrap();
+        drop(conn);
+        assert!(matches!(Database::open(&path), Err(DbError::TooNew(99))));
+
+        for suffix in ["", "-wal", "-shm"] {
+            let _ = std::fs::remove_file(format!("{}{}", path.display(), suffix));
+        }
+    }
+
+    #[test]
+    fn test_save_mapping_records_changes() {
+        let db = Database::in_memory().unwrap();
+        let mut mapping = HashMap::from([("0000001".to_string(), "Max".to_string()), ("0000002".to_string(), "Lewis".to_string())]);
+        assert_eq!(db.save_mapping(&mapping).unwrap(), 2);
+
+        mapping.remove("0000002");
+        mapping.insert("0000001".to_string(), "Max V".to_string());
+        assert_eq!(db.save_mapping(&mapping).unwrap(), 2);
+        assert_eq!(db.save_mapping(&mapping).unwrap(), 0);
+
+        assert_eq!(db.mapping().unwrap(), BTreeMap::from([("0000001".to_string(), "Max V".to_string())]));
+        let changes: i64 = db.conn.lock().unwrap().query_row("SELECT count(*) FROM mapping_changes", [], |row| row.get(0)).unwrap();
+        assert_eq!(changes, 4);
+    }
+}
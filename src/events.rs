@@ -0,0 +1,206 @@
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use tokio::sync::broadcast::{self, error::TryRecvError};
+
+use crate::messages::WsMessage;
+use crate::state::{SharedState, Snapshot};
+
+/// Number of sequenced messages kept so reconnecting clients can catch up.
+const HISTORY: usize = 1000;
+
+#[derive(Clone, Debug)]
+pub struct Sequenced {
+    pub seq: u64,
+    pub msg: WsMessage,
+}
+
+/// Where a resuming client should continue from.
+pub struct Resume {
+    /// Messages after the client's last id, or `None` if they are no longer
+    /// buffered (or the id is unknown) and the client needs a full resync.
+    pub backlog: Option<Vec<Sequenced>>,
+    /// The live state as of `last_seq`, for the resync when there is no backlog.
+    pub snapshot: Option<Snapshot>,
+    pub rx: broadcast::Receiver<Sequenced>,
+    /// Sequence number of the newest message sent so far (0 before any).
+    pub last_seq: u64,
+}
+
+struct History {
+    next_seq: u64,
+    messages: VecDeque<Sequenced>,
+    /// Broadcast messages not numbered yet.
+    rx: broadcast::Receiver<WsMessage>,
+}
+
+impl History {
+    /// Numbers everything sent on the channel so far.
+    fn catch_up(&mut self, seq_tx: &broadcast::Sender<Sequenced>) {
+        loop {
+            let msg = match self.rx.try_recv() {
+                Ok(msg) => msg,
+                Err(TryRecvError::Lagged(n)) => {
+                    // The skipped messages keep their numbers, so the gap shows; nothing
+                    // from before it is enough to resume from any more
+                    eprintln!("Event log lagged behind by {} messages", n);
+                    self.next_seq += n;
+                    self.messages.clear();
+                    continue;
+                }
+                Err(TryRecvError::Empty | TryRecvError::Closed) => return,
+            };
+            let event = Sequenced { seq: self.next_seq, msg };
+            self.next_seq += 1;
+
+            if self.messages.len() == HISTORY {
+                self.messages.pop_front();
+            }
+            self.messages.push_back(event.clone());
+            // Sent under the lock so `resume` never sees a message twice or not at all
+            let _ = seq_tx.send(event);
+        }
+    }
+}
+
+/// Numbers every broadcast message and keeps the most recent ones.
+#[derive(Clone)]
+pub struct EventLog {
+    history: Arc<Mutex<History>>,
+    seq_tx: broadcast::Sender<Sequenced>,
+}
+
+impl EventLog {
+    /// Subscribes to `tx` before returning so nothing sent afterwards is missed.
+    pub fn spawn(tx: &broadcast::Sender<WsMessage>, capacity: usize) -> Self {
+        let (seq_tx, _) = broadcast::channel(capacity.max(1));
+        let log = Self {
+            history: Arc::new(Mutex::new(History {
+                next_seq: 1,
+                messages: VecDeque::with_capacity(HISTORY),
+                rx: tx.subscribe(),
+            })),
+            seq_tx,
+        };
+
+        // Only a wake-up call; `catch_up` reads the messages themselves
+        let mut wakeups = tx.subscribe();
+        let sequencer = log.clone();
+        tokio::spawn(async move {
+            while !matches!(wakeups.recv().await, Err(broadcast::error::RecvError::Closed)) {
+                sequencer.history.lock().unwrap().catch_up(&sequencer.seq_tx);
+            }
+        });
+
+        log
+    }
+
+    /// Picks a client up after `last_event_id`. Everything the live state has
+    /// sent so far is numbered first, under its lock, so the snapshot matches
+    /// `last_seq` exactly; see [`crate::state::subscribe`].
+    pub fn resume(&self, last_event_id: Option<u64>, state: &SharedState) -> Resume {
+        let state = state.lock().unwrap();
+        let mut history = self.history.lock().unwrap();
+        history.catch_up(&self.seq_tx);
+        let rx = self.seq_tx.subscribe();
+        let last_seq = history.next_seq - 1;
+        let oldest = history.messages.front().map_or(history.next_seq, |e| e.seq);
+
+        let backlog: Option<Vec<Sequenced>> = last_event_id
+            .filter(|id| *id <= last_seq && id + 1 >= oldest)
+            .map(|id| history.messages.iter().filter(|e| e.seq > id).cloned().collect());
+        let snapshot = backlog.is_none().then(|| state.snapshot());
+
+        Resume { backlog, snapshot, rx, last_seq }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::LiveState;
+    use crate::status::{SourceState, SourceStatus};
+    use crate::testing::passing;
+    use std::time::Duration;
+
+    fn status_message() -> WsMessage {
+        WsMessage::Status(SourceStatus {
+            source: "test".to_string(),
+            state: SourceState::Connected,
+            last_error: None,
+            since: crate::messages::now(),
+            last_passing: None,
+        })
+    }
+
+    fn laps(snapshot: &Snapshot) -> u32 {
+        snapshot.transponders.iter().map(|t| t.laps).sum()
+    }
+
+    #[tokio::test]
+    async fn test_resume_from_last_event_id() {
+        let (tx, _rx) = broadcast::channel(16);
+        let state = LiveState::shared();
+        let log = EventLog::spawn(&tx, 16);
+        for _ in 0..3 {
+            tx.send(status_message()).unwrap();
+        }
+
+        let resume = log.resume(Some(1), &state);
+        assert_eq!(resume.last_seq, 3);
+        assert!(resume.snapshot.is_none());
+        let seqs: Vec<u64> = resume.backlog.unwrap().iter().map(|e| e.seq).collect();
+        assert_eq!(seqs, vec![2, 3]);
+
+        // Ids from before a restart (or never sent) need a full resync
+        assert!(log.resume(Some(42), &state).backlog.is_none());
+        let resume = log.resume(None, &state);
+        assert!(resume.backlog.is_none());
+        assert!(resume.snapshot.is_some());
+    }
+
+    #[tokio::test]
+    async fn test_messages_lost_to_a_lag_are_not_skipped_over() {
+        let (tx, _rx) = broadcast::channel(4);
+        let state = LiveState::shared();
+        let log = EventLog::spawn(&tx, 16);
+        for _ in 0..10 {
+            tx.send(status_message()).unwrap();
+        }
+
+        // The first 6 were dropped before they were numbered
+        let resume = log.resume(Some(1), &state);
+        assert_eq!(resume.last_seq, 10);
+        assert!(resume.backlog.is_none());
+        let seqs: Vec<u64> = log.resume(Some(8), &state).backlog.unwrap().iter().map(|e| e.seq).collect();
+        assert_eq!(seqs, vec![9, 10]);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_resync_sees_each_lap_once() {
+        let (tx, _rx) = broadcast::channel(8192);
+        let state = LiveState::shared();
+        let passings = crate::state::spawn(tx.clone(), state.clone());
+        let log = EventLog::spawn(&tx, 8192);
+
+        for secs in 0..2000 {
+            passings.send(passing("1", secs * 10_000)).unwrap();
+        }
+        // Resync while the live state works through them, as a lagging SSE client would
+        while laps(&state.lock().unwrap().snapshot()) < 500 {
+            tokio::task::yield_now().await;
+        }
+        let mut resume = log.resume(None, &state);
+        while laps(&state.lock().unwrap().snapshot()) < 1999 {
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+        // Numbers whatever is still waiting
+        log.resume(None, &state);
+
+        let mut laps_received = 0;
+        while let Ok(event) = resume.rx.try_recv() {
+            assert!(event.seq > resume.last_seq);
+            laps_received += matches!(event.msg, WsMessage::Lap(_)) as u32;
+        }
+        assert_eq!(laps(&resume.snapshot.unwrap()) + laps_received, 1999);
+    }
+}
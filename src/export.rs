@@ -0,0 +1,313 @@
+use rust_xlsxwriter::{Format, Workbook, Worksheet, XlsxError};
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, Write};
+use std::path::Path;
+
+use crate::db::{Database, SessionRecord};
+use crate::laps::Lap;
+use crate::messages::{seconds, timestamp, LapTime};
+use crate::session::SessionPhase;
+use crate::standings::{Standing, Standings};
+
+/// Writes to `output`, or stdout when no file is given.
+pub fn to_output(output: Option<&Path>, write: impl FnOnce(&mut dyn Write) -> Result<(), Box<dyn Error>>) -> Result<(), Box<dyn Error>> {
+    match output {
+        Some(path) => {
+            let mut file = File::create(path)?;
+            write(&mut file)?;
+            file.sync_all()?;
+            println!("Wrote {}", path.display());
+        }
+        None => write(&mut io::stdout().lock())?,
+    }
+    Ok(())
+}
+
+/// Transponder names as `transponder,name` rows, sorted by transponder.
+pub fn mapping_csv(mapping: &BTreeMap<String, String>, out: &mut dyn Write) -> Result<(), Box<dyn Error>> {
+    let mut writer = csv::Writer::from_writer(out);
+    writer.write_record(["transponder", "name"])?;
+    for (transponder, name) in mapping {
+        writer.write_record([transponder, name])?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+#[derive(Debug)]
+pub enum ResultsError {
+    NotFound(i64),
+    /// Results are only final once the session is finished.
+    NotFinished(i64),
+    NoFinishedSession,
+    Db(rusqlite::Error),
+}
+
+impl fmt::Display for ResultsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ResultsError::NotFound(id) => write!(f, "no session {}", id),
+            ResultsError::NotFinished(id) => write!(f, "session {} is not finished yet", id),
+            ResultsError::NoFinishedSession => write!(f, "no session has finished yet"),
+            ResultsError::Db(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl Error for ResultsError {}
+
+impl From<rusqlite::Error> for ResultsError {
+    fn from(e: rusqlite::Error) -> Self {
+        ResultsError::Db(e)
+    }
+}
+
+/// Everything a results sheet shows for a finished session.
+pub struct SessionResults {
+    pub record: SessionRecord,
+    pub standings: Standings,
+    /// In the order they were completed.
+    pub laps: Vec<Lap>,
+    /// Transponder names at the time of export.
+    pub names: BTreeMap<String, String>,
+}
+
+impl SessionResults {
+    /// Loads session `id`, or the last finished session.
+    pub fn load(db: &Database, id: Option<i64>) -> Result<Self, ResultsError> {
+        let id = match id {
+            Some(id) => id,
+            None => db.last_finished_session()?.ok_or(ResultsError::NoFinishedSession)?,
+        };
+        let mut record = db.session(id)?.ok_or(ResultsError::NotFound(id))?;
+        if record.session.phase != SessionPhase::Finished {
+            return Err(ResultsError::NotFinished(id));
+        }
+        Ok(SessionResults {
+            standings: record.standings.take().unwrap_or_default(),
+            laps: db.all_laps(id)?,
+            names: db.mapping()?,
+            record,
+        })
+    }
+
+    /// File name for an export, e.g. `session-3-heat-1-results.csv`.
+    pub fn file_name(&self, suffix: &str) -> String {
+        let mut name = format!("session-{}", self.record.id);
+        let slug: String = self
+            .record
+            .session
+            .settings
+            .name
+            .to_lowercase()
+            .chars()
+            .map(|c| if c.is_ascii_alphanumeric() { c } else { '-' })
+            .collect();
+        for word in slug.split('-').filter(|w| !w.is_empty()) {
+            name.push('-');
+            name.push_str(word);
+        }
+        format!("{}-{}", name, suffix)
+    }
+
+    pub fn name(&self, transponder: &str) -> &str {
+        self.names.get(transponder).map_or("", String::as_str)
+    }
+
+    /// Each classified competitor's laps, in finishing order.
+    pub fn laps_by_competitor(&self) -> Vec<(&Standing, Vec<&Lap>)> {
+        self.standings
+            .entries
+            .iter()
+            .map(|standing| (standing, self.laps.iter().filter(|lap| lap.transponder == standing.transponder).collect()))
+            .collect()
+    }
+}
+
+/// A value in an exported table: text in CSV, typed in a workbook.
+enum Cell {
+    Text(String),
+    Number(f64),
+    Time(Option<LapTime>),
+    Flag(bool),
+}
+
+impl Cell {
+    fn to_csv(&self) -> String {
+        match self {
+            Cell::Text(text) => text.clone(),
+            Cell::Number(n) => n.to_string(),
+            Cell::Time(Some(time)) => format!("{:.3}", seconds::to_secs(time)),
+            Cell::Time(None) => String::new(),
+            Cell::Flag(flag) => flag.to_string(),
+        }
+    }
+}
+
+const CLASSIFICATION_HEADERS: [&str; 12] = [
+    "position", "transponder", "name", "laps", "race_time", "best_lap", "gap", "interval", "laps_down", "ranked_time", "counted_laps", "finished",
+];
+
+fn classification_rows(results: &SessionResults) -> Vec<Vec<Cell>> {
+    results
+        .standings
+        .entries
+        .iter()
+        .map(|s| {
+            vec![
+                Cell::Number(s.position.into()),
+                Cell::Text(s.transponder.clone()),
+                Cell::Text(results.name(&s.transponder).to_string()),
+                Cell::Number(s.laps.into()),
+                Cell::Time(s.race_time),
+                Cell::Time(s.best_lap),
+                Cell::Time(s.gap),
+                Cell::Time(s.interval),
+                Cell::Number(s.laps_down.into()),
+                Cell::Time(s.ranked_time),
+                Cell::Text(lap_range(&s.counted_laps)),
+                Cell::Flag(s.finished),
+            ]
+        })
+        .collect()
+}
+
+const LAP_HEADERS: [&str; 9] = ["position", "transponder", "name", "lap", "lap_time", "total_time", "is_best", "timestamp", "timing_point"];
+
+fn lap_rows(results: &SessionResults) -> Vec<Vec<Cell>> {
+    let mut rows = Vec::new();
+    for (standing, laps) in results.laps_by_competitor() {
+        for lap in laps {
+            rows.push(vec![
+                Cell::Number(standing.position.into()),
+                Cell::Text(lap.transponder.clone()),
+                Cell::Text(results.name(&lap.transponder).to_string()),
+                Cell::Number(lap.lap_number.into()),
+                Cell::Time(Some(lap.lap_time)),
+                Cell::Time(Some(lap.total_time)),
+                Cell::Flag(lap.is_best),
+                Cell::Text(timestamp::format(&lap.timestamp)),
+                Cell::Text(lap.timing_point.clone().unwrap_or_default()),
+            ]);
+        }
+    }
+    rows
+}
+
+/// `3` for one lap, `3-5` for a run of laps.
+pub fn lap_range(laps: &[u32]) -> String {
+    match (laps.first(), laps.last()) {
+        (Some(first), Some(last)) if first != last => format!("{}-{}", first, last),
+        (Some(first), _) => first.to_string(),
+        _ => String::new(),
+    }
+}
+
+fn write_csv(headers: &[&str], rows: Vec<Vec<Cell>>, out: &mut dyn Write) -> Result<(), Box<dyn Error>> {
+    let mut writer = csv::Writer::from_writer(out);
+    writer.write_record(headers)?;
+    for row in rows {
+        writer.write_record(row.iter().map(Cell::to_csv))?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+/// One row per classified competitor, times in seconds.
+pub fn classification_csv(results: &SessionResults, out: &mut dyn Write) -> Result<(), Box<dyn Error>> {
+    write_csv(&CLASSIFICATION_HEADERS, classification_rows(results), out)
+}
+
+/// One row per lap, grouped by competitor in finishing order.
+pub fn laps_csv(results: &SessionResults, out: &mut dyn Write) -> Result<(), Box<dyn Error>> {
+    write_csv(&LAP_HEADERS, lap_rows(results), out)
+}
+
+/// A workbook with the classification and the laps on separate sheets.
+/// Times are spreadsheet durations, so they sort and sum.
+pub fn results_xlsx(results: &SessionResults, out: &mut dyn Write) -> Result<(), Box<dyn Error>> {
+    let mut workbook = Workbook::new();
+    write_sheet(workbook.add_worksheet().set_name("Classification")?, &CLASSIFICATION_HEADERS, classification_rows(results))?;
+    write_sheet(workbook.add_worksheet().set_name("Laps")?, &LAP_HEADERS, lap_rows(results))?;
+    out.write_all(&workbook.save_to_buffer()?)?;
+    Ok(())
+}
+
+fn write_sheet(sheet: &mut Worksheet, headers: &[&str], rows: Vec<Vec<Cell>>) -> Result<(), XlsxError> {
+    let bold = Format::new().set_bold();
+    let time = Format::new().set_num_format("[m]:ss.000");
+    for (col, header) in headers.iter().enumerate() {
+        sheet.write_string_with_format(0, col as u16, *header, &bold)?;
+    }
+    for (i, row) in rows.iter().enumerate() {
+        let r = i as u32 + 1;
+        for (col, cell) in row.iter().enumerate() {
+            let col = col as u16;
+            match cell {
+                Cell::Text(text) => sheet.write_string(r, col, text)?,
+                Cell::Number(n) => sheet.write_number(r, col, *n)?,
+                Cell::Time(Some(t)) => sheet.write_number_with_format(r, col, seconds::to_secs(t) / 86_400.0, &time)?,
+                Cell::Time(None) => sheet,
+                Cell::Flag(flag) => sheet.write_boolean(r, col, *flag)?,
+            };
+        }
+    }
+    sheet.set_freeze_panes(1, 0)?;
+    sheet.autofit();
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::finished_race;
+
+    #[test]
+    fn test_results_csv_and_xlsx() {
+        let db = finished_race();
+        let results = SessionResults::load(&db, None).unwrap();
+        assert_eq!(results.file_name("results.csv"), format!("session-{}-heat-1-final-results.csv", results.record.id));
+
+        let mut out = Vec::new();
+        classification_csv(&results, &mut out).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "position,transponder,name,laps,race_time,best_lap,gap,interval,laps_down,ranked_time,counted_laps,finished\n\
+             1,1,Max & Co,2,58.000,28.000,,,0,,,true\n\
+             2,2,,2,59.000,27.000,1.000,1.000,0,,,true\n"
+        );
+
+        let mut out = Vec::new();
+        laps_csv(&results, &mut out).unwrap();
+        let csv = String::from_utf8(out).unwrap();
+        let lines: Vec<&str> = csv.lines().collect();
+        assert_eq!(lines.len(), 5);
+        assert_eq!(lines[1], "1,1,Max & Co,1,30.000,30.000,true,2024-01-12T09:00:30.000000+01:00,");
+        assert_eq!(lines[4], "2,2,,2,27.000,58.000,true,2024-01-12T09:00:59.000000+01:00,");
+
+        let mut out = Vec::new();
+        results_xlsx(&results, &mut out).unwrap();
+        assert!(out.starts_with(b"PK"));
+    }
+
+    #[test]
+    fn test_results_need_a_finished_session() {
+        let db = Database::in_memory().unwrap();
+        assert!(matches!(SessionResults::load(&db, None), Err(ResultsError::NoFinishedSession)));
+        assert!(matches!(SessionResults::load(&db, Some(7)), Err(ResultsError::NotFound(7))));
+    }
+
+    #[test]
+    fn test_mapping_csv() {
+        let mut out = Vec::new();
+        let mapping = BTreeMap::from([
+            ("0000002".to_string(), "Hamilton, Lewis".to_string()),
+            ("0000001".to_string(), "Max".to_string()),
+        ]);
+        mapping_csv(&mapping, &mut out).unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(), "transponder,name\n0000001,Max\n0000002,\"Hamilton, Lewis\"\n");
+    }
+}
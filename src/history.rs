@@ -0,0 +1,317 @@
+use serde::Deserialize;
+use warp::http::StatusCode;
+use warp::Filter;
+
+use crate::config::FieldError;
+use crate::db::{Database, HistoryFilter, SharedDatabase, MAX_PAGE};
+use crate::export::{self, ResultsError, SessionResults};
+use crate::messages::{self, Timestamp};
+use crate::sheets;
+
+const DEFAULT_PAGE: u32 = 50;
+
+/// Query string shared by the history routes.
+#[derive(Debug, Default, Deserialize)]
+pub struct HistoryQuery {
+    /// RFC 3339 time, or a date meaning local midnight at its start.
+    pub from: Option<String>,
+    /// RFC 3339 time (exclusive), or a date meaning the end of that day.
+    pub to: Option<String>,
+    pub limit: Option<u32>,
+    pub offset: Option<u32>,
+    /// Only laps of this transponder.
+    pub transponder: Option<String>,
+}
+
+impl HistoryQuery {
+    pub fn filter(&self) -> Result<HistoryFilter, FieldError> {
+        let limit = self.limit.unwrap_or(DEFAULT_PAGE);
+        if limit == 0 || limit > MAX_PAGE {
+            return Err(FieldError::new("limit", &format!("must be between 1 and {}", MAX_PAGE)));
+        }
+        Ok(HistoryFilter {
+            from: parse_time("from", self.from.as_deref(), false)?,
+            to: parse_time("to", self.to.as_deref(), true)?,
+            limit,
+            offset: self.offset.unwrap_or(0),
+        })
+    }
+}
+
+fn parse_time(field: &str, value: Option<&str>, end_of_day: bool) -> Result<Option<Timestamp>, FieldError> {
+    let Some(value) = value else {
+        return Ok(None);
+    };
+    if let Ok(ts) = chrono::DateTime::parse_from_rfc3339(value) {
+        return Ok(Some(ts));
+    }
+    chrono::NaiveDate::parse_from_str(value, "%Y-%m-%d")
+        .ok()
+        .and_then(|date| if end_of_day { date.succ_opt() } else { Some(date) })
+        .and_then(|date| messages::local_timestamp(date.and_hms_opt(0, 0, 0)?))
+        .map(Some)
+        .ok_or_else(|| FieldError::new(field, "must be an RFC 3339 time or a YYYY-MM-DD date"))
+}
+
+/// `GET /api/sessions`, `/api/sessions/{id}` and `/api/sessions/{id}/laps`,
+/// plus the results of a finished session as files. Queries and exports run
+/// on the blocking pool.
+pub fn history_routes(db: SharedDatabase) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+    let sessions = warp::path("api").and(warp::path("sessions")).and(warp::get());
+    let with_db = warp::any().map(move || db.clone());
+
+    let list_route = sessions
+        .and(warp::path::end())
+        .and(warp::query::<HistoryQuery>())
+        .and(with_db.clone())
+        .and_then(|query: HistoryQuery, db: SharedDatabase| {
+            blocking(db, move |db| match query.filter() {
+                Ok(filter) => reply(db.sessions(&filter)),
+                Err(e) => bad_query(e),
+            })
+        });
+
+    let session_route = sessions
+        .and(warp::path::param::<i64>())
+        .and(warp::path::end())
+        .and(with_db.clone())
+        .and_then(|id: i64, db: SharedDatabase| {
+            blocking(db, move |db| match db.session(id) {
+                Ok(None) => not_found(id),
+                result => reply(result),
+            })
+        });
+
+    let laps_route = sessions
+        .and(warp::path::param::<i64>())
+        .and(warp::path("laps"))
+        .and(warp::path::end())
+        .and(warp::query::<HistoryQuery>())
+        .and(with_db.clone())
+        .and_then(|id: i64, query: HistoryQuery, db: SharedDatabase| {
+            blocking(db, move |db| {
+                let filter = match query.filter() {
+                    Ok(filter) => filter,
+                    Err(e) => return bad_query(e),
+                };
+                match db.session(id) {
+                    Ok(Some(_)) => reply(db.laps(id, query.transponder.as_deref(), &filter)),
+                    Ok(None) => not_found(id),
+                    Err(e) => reply::<()>(Err(e)),
+                }
+            })
+        });
+
+    let results_route = sessions
+        .and(warp::path::param::<i64>())
+        .and(warp::path::param::<String>())
+        .and(warp::path::end())
+        .and(with_db)
+        .and_then(|id: i64, file: String, db: SharedDatabase| async move {
+            let (content_type, write): (&str, Writer) = match file.as_str() {
+                "results.csv" => ("text/csv; charset=utf-8", export::classification_csv),
+                "laps.csv" => ("text/csv; charset=utf-8", export::laps_csv),
+                "results.xlsx" => ("application/vnd.openxmlformats-officedocument.spreadsheetml.sheet", export::results_xlsx),
+                "results.pdf" => ("application/pdf", sheets::pdf),
+                "results.html" => ("text/html; charset=utf-8", sheets::html),
+                _ => return Err(warp::reject::not_found()),
+            };
+            blocking(db, move |db| results_file(db, id, &file, content_type, write)).await
+        });
+
+    list_route.or(session_route).or(laps_route).or(results_route)
+}
+
+/// Runs `query` on the blocking pool, so reading the database and rendering
+/// files doesn't hold up the async runtime.
+async fn blocking<R: warp::Reply + 'static>(db: SharedDatabase, query: impl FnOnce(&Database) -> R + Send + 'static) -> Result<Box<dyn warp::Reply>, warp::Rejection> {
+    match tokio::task::spawn_blocking(move || query(&db)).await {
+        Ok(reply) => Ok(Box::new(reply)),
+        Err(e) => {
+            eprintln!("Failed to answer a history request: {}", e);
+            let body = serde_json::json!({ "error": e.to_string() });
+            Ok(Box::new(warp::reply::with_status(warp::reply::json(&body), StatusCode::INTERNAL_SERVER_ERROR)))
+        }
+    }
+}
+
+type Writer = fn(&SessionResults, &mut dyn std::io::Write) -> Result<(), Box<dyn std::error::Error>>;
+
+/// The session's results as an attachment named after the session.
+fn results_file(db: &Database, id: i64, file: &str, content_type: &str, write: Writer) -> Box<dyn warp::Reply> {
+    let results = match SessionResults::load(db, Some(id)) {
+        Ok(results) => results,
+        Err(e) => {
+            let code = match e {
+                ResultsError::NotFound(_) => StatusCode::NOT_FOUND,
+                ResultsError::NotFinished(_) | ResultsError::NoFinishedSession => StatusCode::CONFLICT,
+                ResultsError::Db(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            };
+            let body = serde_json::json!({ "error": e.to_string() });
+            return Box::new(warp::reply::with_status(warp::reply::json(&body), code));
+        }
+    };
+    let mut content = Vec::new();
+    if let Err(e) = write(&results, &mut content) {
+        eprintln!("Failed to export session {}: {}", id, e);
+        let body = serde_json::json!({ "error": e.to_string() });
+        return Box::new(warp::reply::with_status(warp::reply::json(&body), StatusCode::INTERNAL_SERVER_ERROR));
+    }
+    let disposition = format!("attachment; filename=\"{}\"", results.file_name(file));
+    Box::new(
+        warp::http::Response::builder()
+            .header("content-type", content_type)
+            .header("content-disposition", disposition)
+            .body(content)
+            .unwrap_or_else(|_| warp::http::Response::new(vec![])),
+    )
+}
+
+type JsonReply = warp::reply::WithStatus<warp::reply::Json>;
+
+/// The value, or 500 with `{"error"}` if the database could not be read.
+fn reply<T: serde::Serialize>(result: rusqlite::Result<T>) -> JsonReply {
+    match result {
+        Ok(value) => warp::reply::with_status(warp::reply::json(&value), StatusCode::OK),
+        Err(e) => {
+            eprintln!("Failed to read history: {}", e);
+            let body = serde_json::json!({ "error": e.to_string() });
+            warp::reply::with_status(warp::reply::json(&body), StatusCode::INTERNAL_SERVER_ERROR)
+        }
+    }
+}
+
+/// 400 with `{"error", "errors": [{"field", "message"}]}`.
+fn bad_query(e: FieldError) -> JsonReply {
+    let body = serde_json::json!({ "error": format!("{}: {}", e.field, e.message), "errors": [e] });
+    warp::reply::with_status(warp::reply::json(&body), StatusCode::BAD_REQUEST)
+}
+
+fn not_found(id: i64) -> JsonReply {
+    let body = serde_json::json!({ "error": format!("no session {}", id) });
+    warp::reply::with_status(warp::reply::json(&body), StatusCode::NOT_FOUND)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::Database;
+    use crate::session::{Limit, SessionKind, SessionSettings};
+    use crate::state::{LiveState, SessionControl};
+    use crate::testing::passing;
+    use std::sync::Arc;
+
+    /// Two finished 2-lap races stored through the live state.
+    fn race_day() -> SharedDatabase {
+        let db: SharedDatabase = Arc::new(Database::in_memory().unwrap());
+        let mut state = LiveState::default();
+        state.attach_database(db.clone()).unwrap();
+        for (heat, hour) in [("Heat 1", 0), ("Heat 2", 1)] {
+            let settings = SessionSettings { name: heat.to_string(), kind: SessionKind::Race, limit: Some(Limit::Laps(2)), ..Default::default() };
+            state.control_session(SessionControl::New(settings)).unwrap();
+            for (transponder, second) in [("1", 0), ("2", 1), ("1", 30), ("2", 32), ("1", 58), ("2", 59)] {
+                state.record_passing(&passing(transponder, (hour * 3600 + second) * 1000));
+            }
+        }
+        db
+    }
+
+    #[tokio::test]
+    async fn test_lists_sessions_and_laps() {
+        let filter = history_routes(race_day());
+
+        let resp = warp::test::request().path("/api/sessions?limit=2").reply(&filter).await;
+        assert_eq!(resp.status(), 200);
+        let page: serde_json::Value = serde_json::from_slice(resp.body()).unwrap();
+        // The session before the first New counts too
+        assert_eq!(page["total"], 3);
+        assert_eq!(page["items"][0]["name"], "Heat 2");
+        assert_eq!(page["items"][0]["phase"], "finished");
+        assert_eq!(page["items"][0]["laps"], 4);
+        assert_eq!(page["items"][0]["competitors"], 2);
+        assert!(page["items"][0].get("standings").is_none());
+        let id = page["items"][1]["id"].as_i64().unwrap();
+
+        let resp = warp::test::request().path(&format!("/api/sessions/{}", id)).reply(&filter).await;
+        let session: serde_json::Value = serde_json::from_slice(resp.body()).unwrap();
+        assert_eq!(session["name"], "Heat 1");
+        assert_eq!(session["finished"], serde_json::json!(["1", "2"]));
+        assert_eq!(session["standings"]["entries"][0]["transponder"], "1");
+        assert_eq!(session["standings"]["entries"][1]["finished"], true);
+
+        let resp = warp::test::request().path(&format!("/api/sessions/{}/laps?transponder=2", id)).reply(&filter).await;
+        let laps: serde_json::Value = serde_json::from_slice(resp.body()).unwrap();
+        assert_eq!(laps["total"], 2);
+        assert_eq!(laps["items"][0]["lap_time"], 31.0);
+        assert_eq!(laps["items"][1]["lap_time"], 27.0);
+        assert_eq!(laps["items"][1]["best_lap"], 27.0);
+        assert_eq!(laps["items"][1]["is_best"], true);
+
+        // Lap time windows compare instants, whatever the offset
+        let resp = warp::test::request()
+            .path(&format!("/api/sessions/{}/laps?from=2024-01-12T08:00:31Z&limit=1&offset=1", id))
+            .reply(&filter)
+            .await;
+        let laps: serde_json::Value = serde_json::from_slice(resp.body()).unwrap();
+        assert_eq!(laps["total"], 3);
+        assert_eq!(laps["items"][0]["transponder"], "1");
+        assert_eq!(laps["items"][0]["lap_number"], 2);
+    }
+
+    #[tokio::test]
+    async fn test_rejects_bad_queries_and_unknown_sessions() {
+        let filter = history_routes(race_day());
+
+        let resp = warp::test::request().path("/api/sessions?from=yesterday").reply(&filter).await;
+        assert_eq!(resp.status(), 400);
+        let body: serde_json::Value = serde_json::from_slice(resp.body()).unwrap();
+        assert_eq!(body["errors"][0]["field"], "from");
+
+        let resp = warp::test::request().path("/api/sessions?limit=0").reply(&filter).await;
+        assert_eq!(resp.status(), 400);
+
+        let resp = warp::test::request().path("/api/sessions/99").reply(&filter).await;
+        assert_eq!(resp.status(), 404);
+        let resp = warp::test::request().path("/api/sessions/99/laps").reply(&filter).await;
+        assert_eq!(resp.status(), 404);
+
+        // The session before the first New never ran
+        let resp = warp::test::request().path("/api/sessions/1/results.csv").reply(&filter).await;
+        assert_eq!(resp.status(), 409);
+        let resp = warp::test::request().path("/api/sessions/2/results.txt").reply(&filter).await;
+        assert_eq!(resp.status(), 404);
+    }
+
+    #[tokio::test]
+    async fn test_downloads_results() {
+        let filter = history_routes(race_day());
+
+        let resp = warp::test::request().path("/api/sessions/2/results.csv").reply(&filter).await;
+        assert_eq!(resp.status(), 200);
+        assert_eq!(resp.headers()["content-disposition"], "attachment; filename=\"session-2-heat-1-results.csv\"");
+        assert!(String::from_utf8_lossy(resp.body()).starts_with("position,transponder,name,laps,"));
+
+        let resp = warp::test::request().path("/api/sessions/3/laps.csv").reply(&filter).await;
+        assert_eq!(String::from_utf8_lossy(resp.body()).lines().count(), 5);
+
+        let resp = warp::test::request().path("/api/sessions/3/results.xlsx").reply(&filter).await;
+        assert_eq!(resp.headers()["content-type"], "application/vnd.openxmlformats-officedocument.spreadsheetml.sheet");
+        assert!(resp.body().starts_with(b"PK"));
+
+        let resp = warp::test::request().path("/api/sessions/3/results.pdf").reply(&filter).await;
+        assert_eq!(resp.headers()["content-disposition"], "attachment; filename=\"session-3-heat-2-results.pdf\"");
+        assert!(resp.body().starts_with(b"%PDF"));
+        let resp = warp::test::request().path("/api/sessions/3/results.html").reply(&filter).await;
+        assert!(String::from_utf8_lossy(resp.body()).contains("<h1>Heat 2</h1>"));
+    }
+
+    #[test]
+    fn test_dates_cover_whole_local_days() {
+        let query = HistoryQuery { from: Some("2024-01-12".to_string()), to: Some("2024-01-12".to_string()), ..Default::default() };
+        let filter = query.filter().unwrap();
+        let (from, to) = (filter.from.unwrap(), filter.to.unwrap());
+        assert_eq!(to - from, chrono::Duration::days(1));
+        assert_eq!(from.naive_local(), chrono::NaiveDate::from_ymd_opt(2024, 1, 12).unwrap().and_hms_opt(0, 0, 0).unwrap());
+        assert_eq!(filter.limit, DEFAULT_PAGE);
+    }
+}
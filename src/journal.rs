@@ -0,0 +1,115 @@
+use serde::{Deserialize, Serialize};
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+use crate::config::LapRules;
+use crate::messages::{timestamp, Passing, Timestamp};
+use crate::state::SessionControl;
+
+/// One line of the journal, framed like feed messages:
+/// `{"type": "passing", "data": {...}}`.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(tag = "type", content = "data", rename_all = "snake_case")]
+pub enum JournalEntry {
+    Passing(Passing),
+    /// The configured lap rules, from here on.
+    Rules(LapRules),
+    /// A session control, with the session time it was applied at.
+    Session {
+        #[serde(with = "timestamp")]
+        at: Timestamp,
+        control: SessionControl,
+    },
+}
+
+/// Append-only log of everything that shapes the live state, one JSON entry
+/// per line, so the session can be rebuilt after a crash or power loss. Each
+/// entry is synced to disk as it is written; the live state queues them (see
+/// [`crate::recorder::Recorder`]), so a passing already counted and shown can
+/// still be lost if the machine goes down before its entry is written. Nothing is ever removed; a
+/// new session is just another entry, and replay starts from the last one.
+pub struct Journal {
+    path: PathBuf,
+    file: File,
+}
+
+impl Journal {
+    /// Opens (or creates) the journal and returns the entries already in it.
+    /// Lines that can't be read, such as one cut off by a crash, are skipped.
+    pub fn open(path: &Path) -> io::Result<(Journal, Vec<JournalEntry>)> {
+        let mut file = OpenOptions::new().read(true).append(true).create(true).open(path)?;
+
+        let mut entries = Vec::new();
+        for (i, line) in BufReader::new(&file).lines().enumerate() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            match serde_json::from_str(&line) {
+                Ok(entry) => entries.push(entry),
+                Err(e) => eprintln!("Skipping line {} of {}: {}", i + 1, path.display(), e),
+            }
+        }
+
+        // Start on a fresh line after a torn write
+        let len = file.seek(SeekFrom::End(0))?;
+        if len > 0 {
+            let mut last = [0u8];
+            file.seek(SeekFrom::Start(len - 1))?;
+            file.read_exact(&mut last)?;
+            if last[0] != b'\n' {
+                file.write_all(b"\n")?;
+            }
+        }
+
+        Ok((Journal { path: path.to_path_buf(), file }, entries))
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Writes the entry and waits until it is on disk.
+    pub fn append(&mut self, entry: &JournalEntry) -> io::Result<()> {
+        let mut line = serde_json::to_vec(entry)?;
+        line.push(b'\n');
+        self.file.write_all(&line)?;
+        self.file.sync_data()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::session::SessionSettings;
+    use crate::testing::passing;
+
+    #[test]
+    fn test_reopen_reads_entries_and_skips_torn_line() {
+        let path = PathBuf::from("test_journal.jsonl");
+        let _ = std::fs::remove_file(&path);
+
+        let (mut journal, entries) = Journal::open(&path).unwrap();
+        assert!(entries.is_empty());
+        let at = chrono::DateTime::parse_from_rfc3339("2024-01-12T09:00:00.123456+01:00").unwrap();
+        journal.append(&JournalEntry::Session { at, control: SessionControl::New(SessionSettings::default()) }).unwrap();
+        journal.append(&JournalEntry::Passing(passing("0000001", 0))).unwrap();
+        drop(journal);
+
+        // Crash halfway through a write
+        OpenOptions::new().append(true).open(&path).unwrap().write_all(br#"{"type":"passing","da"#).unwrap();
+
+        let (mut journal, entries) = Journal::open(&path).unwrap();
+        assert_eq!(entries.len(), 2);
+        assert!(matches!(&entries[0], JournalEntry::Session { at: t, control: SessionControl::New(_) } if *t == at));
+        journal.append(&JournalEntry::Passing(passing("0000002", 0))).unwrap();
+        drop(journal);
+
+        let (_, entries) = Journal::open(&path).unwrap();
+        assert_eq!(entries.len(), 3);
+        assert!(matches!(&entries[2], JournalEntry::Passing(p) if p.transponder == "0000002"));
+
+        let _ = std::fs::remove_file(path);
+    }
+}
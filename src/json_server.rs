@@ -1,12 +1,15 @@
+use chrono::{Datelike, TimeZone, Utc};
 use serde::Deserialize;
 use tokio::net::TcpListener;
 use tokio::io::{AsyncBufReadExt, BufReader};
-use tokio::sync::broadcast;
-use std::sync::{atomic::{AtomicBool, Ordering}, Arc};
-use crate::messages::{WsMessage, Passing};
+use tokio::task::JoinSet;
+use std::sync::{atomic::{AtomicUsize, Ordering}, Arc};
+use crate::messages::{self, Passing, Timestamp};
+use crate::state::PassingSender;
+use crate::status::{SourceState, StatusHandle};
 
 #[derive(Debug, Deserialize)]
-#[allow(non_snake_case)]
+#[allow(non_snake_case, dead_code)]
 struct JsonPassingInner {
     Transponder: String,
     Hits: Option<i64>,
@@ -27,17 +30,23 @@ struct JsonPassingWrapper {
     Time: Option<f64>,
 }
 
-pub async fn run_server(tx: broadcast::Sender<WsMessage>, port: u16, is_connected: Arc<AtomicBool>, debug: bool) {
+pub async fn run_server(passings: PassingSender, port: u16, status: StatusHandle, debug: bool) {
     let addr = format!("0.0.0.0:{}", port);
     let listener = match TcpListener::bind(&addr).await {
         Ok(l) => l,
         Err(e) => {
             eprintln!("Failed to bind JSON server to {}: {}", addr, e);
+            status.set_error(SourceState::Failed, format!("Failed to bind {}: {}", addr, e));
             return;
         }
     };
 
     println!("JSON Server listening on {}", addr);
+    // Waiting for a client counts as connecting
+    status.set_state(SourceState::Connecting);
+    let clients = Arc::new(AtomicUsize::new(0));
+    // Owned here so client connections are closed when the server task is aborted
+    let mut connections = JoinSet::new();
 
     loop {
         let (socket, addr) = match listener.accept().await {
@@ -48,16 +57,20 @@ pub async fn run_server(tx: broadcast::Sender<WsMessage>, port: u16, is_connecte
             }
         };
 
+        // Forget connections that have already finished
+        while connections.try_join_next().is_some() {}
+
         println!("New JSON client connection from {}", addr);
         
         // Mark as connected when a client connects
-        is_connected.store(true, Ordering::SeqCst);
-        let _ = tx.send(WsMessage::Status { event: "connected".to_string() });
+        clients.fetch_add(1, Ordering::SeqCst);
+        status.set_state(SourceState::Connected);
 
-        let tx = tx.clone();
-        let is_connected = is_connected.clone();
+        let passings = passings.clone();
+        let status = status.clone();
+        let clients = clients.clone();
 
-        tokio::spawn(async move {
+        connections.spawn(async move {
             let reader = BufReader::new(socket);
             let mut lines = reader.lines();
 
@@ -72,51 +85,24 @@ pub async fn run_server(tx: broadcast::Sender<WsMessage>, port: u16, is_connecte
 
                 match serde_json::from_str::<JsonPassingWrapper>(&line) {
                     Ok(wrapper) => {
-                        let time_val = wrapper.Time;
                         let inner = wrapper.Passing;
-                        
-                        // Parse UTCTime to date and time
-                        // Format: "2024-01-12T09:06:35.944Z"
-                        let (mut date_str, mut time_str) = if let Some((d, t)) = inner.UTCTime.split_once('T') {
-                            (d.to_string(), t.trim_end_matches('Z').to_string())
-                        } else {
-                            ("".to_string(), "".to_string())
-                        };
-
-                        // Always prioritize Time object if available
-                        if let Some(seconds_since_midnight) = time_val {
-                            // Calculate time from seconds
-                            let seconds = seconds_since_midnight as u32;
-                            let millis = ((seconds_since_midnight - seconds as f64) * 1000.0) as u32;
-                            let hours = seconds / 3600;
-                            let minutes = (seconds % 3600) / 60;
-                            let secs = seconds % 60;
-                            
-                            time_str = format!("{:02}:{:02}:{:02}.{:03}", hours, minutes, secs, millis);
-                            
-                            // If date_str is invalid or empty, use current date
-                            if date_str == "0001-01-01" || date_str.is_empty() {
-                                let now = chrono::Local::now();
-                                date_str = now.format("%Y-%m-%d").to_string();
+                        let timestamp = match passing_timestamp(&inner.UTCTime, wrapper.Time) {
+                            Some(ts) => ts,
+                            None => {
+                                eprintln!("Invalid passing time '{}', using receive time", inner.UTCTime);
+                                messages::now()
                             }
-                        }
-
-                        let full_iso_date = if !date_str.is_empty() && !time_str.is_empty() {
-                            format!("{}T{}", date_str, time_str)
-                        } else {
-                            inner.UTCTime.clone()
                         };
 
                         let passing = Passing {
                             passing_number: inner.PassingNo.map(|v| v as u32).unwrap_or(0),
                             transponder: inner.Transponder,
-                            date: full_iso_date.clone(),
-                            time: time_str.clone(),
-                            rtc_time: full_iso_date,
+                            timestamp,
                             strength: inner.RSSI.map(|v| v as u32).unwrap_or(0),
                             tran_code: inner.InternalData.unwrap_or_default(),
                             noise: 0, 
                             hits: inner.Hits.map(|v| v as u32).unwrap_or(0),
+                            timing_point: inner.LoopID.map(|v| v.to_string()),
                         };
 
                         if debug {
@@ -125,8 +111,9 @@ pub async fn run_server(tx: broadcast::Sender<WsMessage>, port: u16, is_connecte
                              // println!("JSON Passing received");
                         }
 
-                        if let Err(e) = tx.send(WsMessage::Passing(passing)) {
-                            eprintln!("Error broadcasting passing: {}", e);
+                        status.record_passing(&passing);
+                        if let Err(e) = passings.send(passing) {
+                            eprintln!("Error recording passing: {}", e);
                         }
                     }
                     Err(e) => {
@@ -136,8 +123,93 @@ pub async fn run_server(tx: broadcast::Sender<WsMessage>, port: u16, is_connecte
             }
 
             println!("JSON client {} disconnected", addr);
-            is_connected.store(false, Ordering::SeqCst);
-            let _ = tx.send(WsMessage::Status { event: "disconnected".to_string() });
+            // Only fall back once the last client has gone
+            if clients.fetch_sub(1, Ordering::SeqCst) == 1 {
+                status.set_state(SourceState::Connecting);
+            }
         });
     }
 }
+
+/// Resolves a passing time from `UTCTime` ("2024-01-12T09:06:35.944Z") and the
+/// optional `Time` field (local seconds since midnight). `Time` always wins for
+/// the time of day; the local date is taken from `UTCTime`, or today if unset.
+fn passing_timestamp(utc_time: &str, time_of_day: Option<f64>) -> Option<Timestamp> {
+    passing_timestamp_in(&chrono::Local, utc_time, time_of_day).map(messages::to_local)
+}
+
+/// [`passing_timestamp`] with `Time` read on the clock of `tz`.
+fn passing_timestamp_in<Tz: TimeZone>(tz: &Tz, utc_time: &str, time_of_day: Option<f64>) -> Option<Timestamp> {
+    let utc = chrono::DateTime::parse_from_rfc3339(utc_time).ok();
+
+    let Some(seconds_since_midnight) = time_of_day else {
+        return utc;
+    };
+
+    let secs = seconds_since_midnight.trunc() as u32;
+    let nanos = ((seconds_since_midnight - secs as f64) * 1_000_000_000.0).round() as u32;
+    let time = chrono::NaiveTime::from_num_seconds_from_midnight_opt(secs, nanos.min(999_999_999))?;
+
+    // `Time` is on the local clock, so the date has to be too. Of the days
+    // around the local date of `UTCTime`, take the one that lands closest to
+    // it, so a reading just after midnight isn't a day off when the clocks differ slightly.
+    let reference = utc
+        .filter(|dt| dt.year() > 1) // 0001-01-01 means unset
+        .map(|dt| dt.with_timezone(&Utc))
+        .unwrap_or_else(Utc::now);
+    let date = reference.with_timezone(tz).date_naive();
+    [date.pred_opt(), Some(date), date.succ_opt()]
+        .into_iter()
+        .flatten()
+        .filter_map(|day| tz.from_local_datetime(&day.and_time(time)).earliest())
+        .min_by_key(|dt| (dt.with_timezone(&Utc) - reference).abs())
+        .map(|dt| dt.fixed_offset())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::FixedOffset;
+
+    fn plus_two() -> FixedOffset {
+        FixedOffset::east_opt(2 * 3600).unwrap()
+    }
+
+    fn at(s: &str) -> Timestamp {
+        chrono::DateTime::parse_from_rfc3339(s).unwrap()
+    }
+
+    #[test]
+    fn test_passing_timestamp_uses_local_time_of_day() {
+        let ts = passing_timestamp_in(&plus_two(), "2024-01-12T09:06:35.944Z", Some(11.0 * 3600.0 + 6.0 * 60.0 + 35.944));
+        assert_eq!(ts, Some(at("2024-01-12T11:06:35.944+02:00")));
+    }
+
+    #[test]
+    fn test_passing_timestamp_after_local_midnight() {
+        // 22:00:05Z is already the 13th at +02:00
+        let ts = passing_timestamp_in(&plus_two(), "2024-01-12T22:00:05Z", Some(5.0));
+        assert_eq!(ts, Some(at("2024-01-13T00:00:05+02:00")));
+
+        // decoder clock slightly behind: UTCTime still before midnight, Time just after
+        let ts = passing_timestamp_in(&plus_two(), "2024-01-12T21:59:59.900Z", Some(0.2));
+        assert_eq!(ts, Some(at("2024-01-13T00:00:00.200+02:00")));
+
+        // and the other way round
+        let ts = passing_timestamp_in(&plus_two(), "2024-01-12T22:00:00.100Z", Some(86_399.8));
+        assert_eq!(ts, Some(at("2024-01-12T23:59:59.800+02:00")));
+    }
+
+    #[test]
+    fn test_passing_timestamp_without_time_of_day() {
+        let ts = passing_timestamp_in(&plus_two(), "2024-01-12T22:00:05Z", None);
+        assert_eq!(ts, Some(at("2024-01-12T22:00:05Z")));
+    }
+
+    #[test]
+    fn test_passing_timestamp_with_unset_utc_time() {
+        let ts = passing_timestamp_in(&plus_two(), "0001-01-01T00:00:00Z", Some(3600.0)).unwrap();
+        assert_eq!(ts.time(), chrono::NaiveTime::from_hms_opt(1, 0, 0).unwrap());
+        assert!((ts.with_timezone(&Utc) - Utc::now()).abs() <= chrono::TimeDelta::hours(12));
+    }
+}
@@ -0,0 +1,366 @@
+use serde::Serialize;
+use std::collections::BTreeMap;
+
+use crate::config::LapRules;
+use crate::messages::{seconds, timestamp, LapTime, Passing, Timestamp};
+
+/// Start signal transponder; restarts every lap instead of being shown.
+pub const IMPULSE_MARKER: &str = "00000127";
+
+/// A completed lap, broadcast as `WsMessage::Lap`.
+#[derive(Clone, Debug, Serialize)]
+pub struct Lap {
+    pub transponder: String,
+    /// 1 for the first completed lap.
+    pub lap_number: u32,
+    #[serde(with = "seconds")]
+    pub lap_time: LapTime,
+    /// Best lap so far, including this one.
+    #[serde(with = "seconds")]
+    pub best_lap: LapTime,
+    /// This lap is a new personal best.
+    pub is_best: bool,
+    /// Sum of all completed laps.
+    #[serde(with = "seconds")]
+    pub total_time: LapTime,
+    /// Crossing that completed the lap.
+    #[serde(with = "timestamp")]
+    pub timestamp: Timestamp,
+    pub timing_point: Option<String>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SuppressReason {
+    /// Within the dead time of the transponder's last crossing.
+    DeadTime,
+    /// Would have made a lap shorter than the minimum lap time.
+    MinLapTime,
+    LowHits,
+    LowStrength,
+    /// Older than the transponder's last crossing.
+    OutOfOrder,
+}
+
+/// A read the rules kept from counting, broadcast as `WsMessage::Suppressed`
+/// so it can still be reviewed.
+#[derive(Clone, Debug, Serialize)]
+pub struct SuppressedRead {
+    pub transponder: String,
+    pub passing_number: u32,
+    #[serde(with = "timestamp")]
+    pub timestamp: Timestamp,
+    pub timing_point: Option<String>,
+    pub reason: SuppressReason,
+    /// Human-readable explanation, e.g. for the log.
+    pub detail: String,
+}
+
+/// What the engine made of a passing.
+#[derive(Debug)]
+pub enum ReadOutcome {
+    /// A first crossing, or the impulse marker; no lap completed.
+    Started,
+    Lap(Lap),
+    Suppressed(SuppressedRead),
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct TransponderSummary {
+    pub transponder: String,
+    pub passings: u32,
+    pub laps: u32,
+    /// Reads the rules kept from counting.
+    pub suppressed: u32,
+    #[serde(with = "seconds::option")]
+    pub last_lap: Option<LapTime>,
+    #[serde(with = "seconds::option")]
+    pub best_lap: Option<LapTime>,
+    #[serde(with = "seconds")]
+    pub total_time: LapTime,
+    #[serde(with = "timestamp")]
+    pub last_passing: Timestamp,
+    /// Start of the lap in progress; moved by the impulse marker.
+    #[serde(with = "timestamp")]
+    pub lap_started: Timestamp,
+}
+
+/// Turns passings into laps per transponder.
+///
+/// A transponder's first passing opens its first lap, every later crossing
+/// completes a lap and opens the next. Reads that break the `LapRules` are
+/// reported as suppressed and don't move the lap start. The impulse marker
+/// starts everyone's current lap over without being counted itself.
+#[derive(Default)]
+pub struct LapEngine {
+    rules: LapRules,
+    transponders: BTreeMap<String, TransponderSummary>,
+    /// Every counted lap per transponder, in order.
+    history: BTreeMap<String, Vec<Lap>>,
+}
+
+impl LapEngine {
+    /// Applies to reads from now on; laps already counted stay.
+    pub fn set_rules(&mut self, rules: LapRules) {
+        self.rules = rules;
+    }
+
+    pub fn record(&mut self, passing: &Passing) -> ReadOutcome {
+        let ts = passing.timestamp;
+
+        if passing.transponder == IMPULSE_MARKER {
+            for t in self.transponders.values_mut() {
+                t.lap_started = ts;
+            }
+            return ReadOutcome::Started;
+        }
+
+        if let Some((reason, detail)) = self.check_signal(passing) {
+            if let Some(t) = self.transponders.get_mut(&passing.transponder) {
+                t.passings += 1;
+                t.suppressed += 1;
+            }
+            return suppress(passing, reason, detail);
+        }
+
+        let Some(t) = self.transponders.get_mut(&passing.transponder) else {
+            self.transponders.insert(
+                passing.transponder.clone(),
+                TransponderSummary {
+                    transponder: passing.transponder.clone(),
+                    passings: 1,
+                    laps: 0,
+                    suppressed: 0,
+                    last_lap: None,
+                    best_lap: None,
+                    total_time: LapTime::zero(),
+                    last_passing: ts,
+                    lap_started: ts,
+                },
+            );
+            return ReadOutcome::Started;
+        };
+
+        t.passings += 1;
+        t.last_passing = t.last_passing.max(ts);
+
+        let lap_time = ts - t.lap_started;
+        let rejected = if lap_time < LapTime::zero() {
+            Some((SuppressReason::OutOfOrder, format!("{:.3}s before the lap started", -seconds::to_secs(&lap_time))))
+        } else if lap_time <= duration(self.rules.dead_time) {
+            Some((SuppressReason::DeadTime, format!("{:.3}s after the last crossing", seconds::to_secs(&lap_time))))
+        } else if lap_time < duration(self.rules.min_lap_time) {
+            Some((
+                SuppressReason::MinLapTime,
+                format!("{:.3}s lap is under the {}s minimum", seconds::to_secs(&lap_time), self.rules.min_lap_time),
+            ))
+        } else {
+            None
+        };
+        if let Some((reason, detail)) = rejected {
+            t.suppressed += 1;
+            return suppress(passing, reason, detail);
+        }
+
+        let is_best = t.best_lap.is_none_or(|best| lap_time < best);
+        let best_lap = if is_best { lap_time } else { t.best_lap.unwrap_or(lap_time) };
+        t.laps += 1;
+        t.last_lap = Some(lap_time);
+        t.best_lap = Some(best_lap);
+        t.total_time += lap_time;
+        t.lap_started = ts;
+
+        let lap = Lap {
+            transponder: passing.transponder.clone(),
+            lap_number: t.laps,
+            lap_time,
+            best_lap,
+            is_best,
+            total_time: t.total_time,
+            timestamp: ts,
+            timing_point: passing.timing_point.clone(),
+        };
+        self.history.entry(passing.transponder.clone()).or_default().push(lap.clone());
+        ReadOutcome::Lap(lap)
+    }
+
+    /// Hits and strength thresholds, which apply to first crossings too.
+    fn check_signal(&self, passing: &Passing) -> Option<(SuppressReason, String)> {
+        if passing.hits < self.rules.min_hits {
+            return Some((SuppressReason::LowHits, format!("{} hits, minimum is {}", passing.hits, self.rules.min_hits)));
+        }
+        if passing.strength < self.rules.min_strength {
+            return Some((
+                SuppressReason::LowStrength,
+                format!("strength {}, minimum is {}", passing.strength, self.rules.min_strength),
+            ));
+        }
+        None
+    }
+
+    pub fn reset(&mut self) {
+        self.transponders.clear();
+        self.history.clear();
+    }
+
+    pub fn summaries(&self) -> Vec<TransponderSummary> {
+        self.transponders.values().cloned().collect()
+    }
+
+    pub fn transponders(&self) -> impl Iterator<Item = &TransponderSummary> {
+        self.transponders.values()
+    }
+
+    /// Counted laps of one transponder, first lap first.
+    pub fn history(&self, transponder: &str) -> &[Lap] {
+        self.history.get(transponder).map_or(&[], Vec::as_slice)
+    }
+}
+
+fn suppress(passing: &Passing, reason: SuppressReason, detail: String) -> ReadOutcome {
+    ReadOutcome::Suppressed(SuppressedRead {
+        transponder: passing.transponder.clone(),
+        passing_number: passing.passing_number,
+        timestamp: passing.timestamp,
+        timing_point: passing.timing_point.clone(),
+        reason,
+        detail,
+    })
+}
+
+pub(crate) fn duration(secs: f64) -> LapTime {
+    LapTime::microseconds((secs * 1_000_000.0) as i64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::passing;
+
+    impl ReadOutcome {
+        fn lap(self) -> Option<Lap> {
+            match self {
+                ReadOutcome::Lap(lap) => Some(lap),
+                _ => None,
+            }
+        }
+
+        fn reason(self) -> Option<SuppressReason> {
+            match self {
+                ReadOutcome::Suppressed(read) => Some(read.reason),
+                _ => None,
+            }
+        }
+    }
+
+    #[test]
+    fn test_first_passing_opens_lap() {
+        let mut engine = LapEngine::default();
+        assert!(matches!(engine.record(&passing("1", 0)), ReadOutcome::Started));
+
+        let lap = engine.record(&passing("1", 30_000)).lap().unwrap();
+        assert_eq!(lap.lap_number, 1);
+        assert_eq!(lap.lap_time, LapTime::seconds(30));
+        assert!(lap.is_best);
+        assert_eq!(lap.total_time, LapTime::seconds(30));
+    }
+
+    #[test]
+    fn test_best_and_total_time() {
+        let mut engine = LapEngine::default();
+        engine.record(&passing("1", 0));
+        engine.record(&passing("1", 30_000));
+        let faster = engine.record(&passing("1", 58_000)).lap().unwrap();
+        let slower = engine.record(&passing("1", 90_000)).lap().unwrap();
+
+        assert!(faster.is_best);
+        assert_eq!(faster.best_lap, LapTime::seconds(28));
+        assert!(!slower.is_best);
+        assert_eq!(slower.best_lap, LapTime::seconds(28));
+        assert_eq!(slower.lap_number, 3);
+        assert_eq!(slower.total_time, LapTime::seconds(90));
+
+        let history = engine.history("1");
+        assert_eq!(history.len(), 3);
+        assert_eq!(history[1].lap_time, LapTime::seconds(28));
+        assert!(engine.history("2").is_empty());
+    }
+
+    #[test]
+    fn test_dead_time_keeps_first_read() {
+        let mut engine = LapEngine::default();
+        engine.record(&passing("1", 0));
+        engine.record(&passing("1", 30_000));
+        // Second read of the same crossing
+        assert_eq!(engine.record(&passing("1", 30_400)).reason(), Some(SuppressReason::DeadTime));
+
+        let lap = engine.record(&passing("1", 60_000)).lap().unwrap();
+        assert_eq!(lap.lap_time, LapTime::seconds(30));
+        assert_eq!(lap.lap_number, 2);
+
+        let summary = &engine.summaries()[0];
+        assert_eq!(summary.passings, 4);
+        assert_eq!(summary.suppressed, 1);
+    }
+
+    #[test]
+    fn test_out_of_order_read_is_suppressed() {
+        let mut engine = LapEngine::default();
+        engine.record(&passing("1", 10_000));
+        assert_eq!(engine.record(&passing("1", 5_000)).reason(), Some(SuppressReason::OutOfOrder));
+
+        let summary = &engine.summaries()[0];
+        assert_eq!(summary.laps, 0);
+        assert_eq!(summary.last_passing, passing("1", 10_000).timestamp);
+    }
+
+    #[test]
+    fn test_min_lap_time_and_dead_time_rules() {
+        let mut engine = LapEngine::default();
+        engine.set_rules(LapRules { min_lap_time: 20.0, dead_time: 3.0, ..LapRules::default() });
+        engine.record(&passing("1", 0));
+
+        // Slow exit over the loop, then a cut back across it
+        assert_eq!(engine.record(&passing("1", 2_500)).reason(), Some(SuppressReason::DeadTime));
+        assert_eq!(engine.record(&passing("1", 12_000)).reason(), Some(SuppressReason::MinLapTime));
+        // Suppressed reads don't move the lap start
+        assert_eq!(engine.record(&passing("1", 31_000)).lap().unwrap().lap_time, LapTime::seconds(31));
+    }
+
+    #[test]
+    fn test_signal_thresholds() {
+        let mut engine = LapEngine::default();
+        engine.set_rules(LapRules { min_hits: 3, min_strength: 50, ..LapRules::default() });
+
+        let weak = Passing { hits: 5, strength: 10, ..passing("1", 0) };
+        assert_eq!(engine.record(&weak).reason(), Some(SuppressReason::LowStrength));
+        let few = Passing { hits: 1, strength: 80, ..passing("1", 0) };
+        assert_eq!(engine.record(&few).reason(), Some(SuppressReason::LowHits));
+        // A suppressed first read doesn't open a lap
+        assert!(engine.summaries().is_empty());
+
+        let good = Passing { hits: 5, strength: 80, ..passing("1", 1_000) };
+        assert!(matches!(engine.record(&good), ReadOutcome::Started));
+    }
+
+    #[test]
+    fn test_impulse_marker_restarts_laps() {
+        let mut engine = LapEngine::default();
+        engine.record(&passing("1", 0));
+        engine.record(&passing("2", 1_000));
+        assert!(matches!(engine.record(&passing(IMPULSE_MARKER, 10_000)), ReadOutcome::Started));
+
+        assert_eq!(engine.record(&passing("1", 40_000)).lap().unwrap().lap_time, LapTime::seconds(30));
+        assert_eq!(engine.record(&passing("2", 41_000)).lap().unwrap().lap_time, LapTime::seconds(31));
+        // The marker itself is not a competitor
+        assert_eq!(engine.summaries().len(), 2);
+    }
+
+    #[test]
+    fn test_reset_forgets_transponders() {
+        let mut engine = LapEngine::default();
+        engine.record(&passing("1", 0));
+        engine.reset();
+        assert!(matches!(engine.record(&passing("1", 30_000)), ReadOutcome::Started));
+    }
+}
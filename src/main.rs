@@ -1,19 +1,42 @@
+use clap::Parser;
 use rust_embed::RustEmbed;
 use warp::Filter;
-use std::net::SocketAddr;
-use std::sync::{atomic::AtomicBool, Arc};
+use std::sync::Arc;
 use tokio::sync::broadcast;
 
 mod messages;
 mod json_server;
 mod config;
 mod ws_handler;
+mod status;
+mod state;
+mod commands;
+mod subscription;
+mod clients;
+mod events;
+mod sse;
+mod network;
+mod sources;
+mod laps;
+mod journal;
+mod recorder;
+mod db;
+mod history;
+mod session;
+mod standings;
+mod cli;
+mod replay;
+mod export;
+mod sheets;
+#[cfg(test)]
+mod testing;
 
 mod converter {
     pub mod decoder;
 }
 mod usb {
     pub mod decoder;
+    pub mod probe;
 }
 
 use messages::WsMessage;
@@ -22,15 +45,12 @@ use messages::WsMessage;
 #[folder = "web/"]
 struct Asset;
 
-use tokio::sync::mpsc;
-use std::path::{Path, PathBuf};
+use std::path::PathBuf;
 
-fn api_filters(config_path: PathBuf, mapping_path: PathBuf, shutdown_tx: mpsc::Sender<()>) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+fn api_filters(config_path: PathBuf, db: db::SharedDatabase, clients: clients::ClientRegistry, server_info: network::ServerInfo, sources: sources::SourceManager) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
     let config_path = Arc::new(config_path);
-    let mapping_path = Arc::new(mapping_path);
-    let p1 = config_path.clone();
-    let p2 = config_path.clone();
-    let m1 = mapping_path.clone();
+    let db1 = db.clone();
+    let db2 = db.clone();
     
     let api = warp::path("api");
 
@@ -40,70 +60,148 @@ fn api_filters(config_path: PathBuf, mapping_path: PathBuf, shutdown_tx: mpsc::S
         .and(warp::body::json())
         .map(move |mapping: std::collections::HashMap<String, String>| {
             println!("Received new mapping update");
-            match std::fs::File::create(m1.as_path()) {
-                Ok(file) => {
-                    match serde_json::to_writer_pretty(file, &mapping) {
-                        Ok(_) => warp::reply::json(&"Success"),
-                        Err(e) => {
-                            eprintln!("Failed to write JSON: {}", e);
-                            warp::reply::json(&"Error writing JSON")
-                        }
-                    }
-                },
+            match db1.save_mapping(&mapping) {
+                Ok(changes) => {
+                    println!("Saved {} mapping changes", changes);
+                    warp::reply::json(&"Success")
+                }
                 Err(e) => {
-                    eprintln!("Failed to create mapping.json: {}", e);
-                    warp::reply::json(&"Error creating file")
+                    eprintln!("Failed to save mapping: {}", e);
+                    warp::reply::json(&"Error saving mapping")
                 }
             }
         });
 
-    let shutdown_tx = warp::any().map(move || shutdown_tx.clone());
+    let sources = warp::any().map(move || sources.clone());
 
     let config_route = api
         .and(warp::path("config"))
         .and(
             warp::get().map(move || {
-                let config = config::load_config(p1.to_str().unwrap_or("config.json"));
-                warp::reply::json(&config)
+                match config::load_config(&db2, &config_path) {
+                    Ok(config) => Box::new(warp::reply::json(&config)) as Box<dyn warp::Reply>,
+                    // Send what was stored too, so saving the fix keeps the rest of it
+                    Err(e) => config_error_reply(&e, config::raw_config(&db2, &config_path)),
+                }
             })
             .or(
                 warp::post()
                 .and(warp::body::json())
-                .and(shutdown_tx)
-                .map(move |config: config::Config, tx: mpsc::Sender<()>| {
-                    config::save_config(p2.to_str().unwrap_or("config.json"), &config);
-                    // Spawn a task to send shutdown signal after a short delay
-                    // to allow the response to be sent
-                    tokio::spawn(async move {
-                        tokio::time::sleep(std::time::Duration::from_millis(500)).await; 
-                        let _ = tx.send(()).await;
-                    });
-                    warp::reply::json(&"Success")
+                .and(sources)
+                .then(move |value: serde_json::Value, sources: sources::SourceManager| {
+                    let db = db.clone();
+                    async move {
+                        let config = match config::parse_value(value) {
+                            Ok(config) => config,
+                            Err(e) => return config_error_reply(&e, None),
+                        };
+                        if let Err(e) = db.save_config(&config) {
+                            eprintln!("Failed to save config: {}", e);
+                            return config_error_reply(&config::ConfigError::Db(e), None);
+                        }
+                        // Swap the source in-process; the web server and open displays stay up
+                        let outcome = sources.apply(config).await;
+                        Box::new(warp::reply::json(&outcome))
+                    }
                 })
             )
         );
 
-    mapping_route.or(config_route)
+    let clients_route = api
+        .and(warp::path("clients"))
+        .and(warp::get())
+        .map(move || warp::reply::json(&clients.list()));
+
+    let server_route = api
+        .and(warp::path("server"))
+        .and(warp::get())
+        .map(move || warp::reply::json(&server_info));
+
+    mapping_route.or(config_route).or(clients_route).or(server_route)
+}
+
+/// Session state and controls: `GET /api/session`, `POST /api/session` with
+/// new settings, and `POST /api/session/{start,chequered,finish}`.
+fn session_filters(live_state: state::SharedState, tx: broadcast::Sender<WsMessage>) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+    let session = warp::path("api").and(warp::path("session"));
+    let s1 = live_state.clone();
+    let controls = warp::any().map(move || (live_state.clone(), tx.clone()));
+
+    let get_route = session
+        .and(warp::path::end())
+        .and(warp::get())
+        .map(move || warp::reply::json(s1.lock().unwrap().session()));
+
+    let new_route = session
+        .and(warp::path::end())
+        .and(warp::post())
+        .and(warp::body::json())
+        .and(controls.clone())
+        .map(|settings: session::SessionSettings, (live_state, tx)| {
+            if let Err(e) = settings.validate() {
+                let body = serde_json::json!({ "error": format!("{}: {}", e.field, e.message), "errors": [e] });
+                return warp::reply::with_status(warp::reply::json(&body), warp::http::StatusCode::UNPROCESSABLE_ENTITY);
+            }
+            println!("New {:?} session '{}'", settings.kind, settings.name);
+            session_reply(state::control_session(&live_state, &tx, state::SessionControl::New(settings)))
+        });
+
+    let control_route = session
+        .and(warp::path::param::<String>())
+        .and(warp::path::end())
+        .and(warp::post())
+        .and(controls)
+        .and_then(|action: String, (live_state, tx)| async move {
+            let control = match action.as_str() {
+                "start" => state::SessionControl::Start,
+                "chequered" => state::SessionControl::Chequered,
+                "finish" => state::SessionControl::Finish,
+                _ => return Err(warp::reject::not_found()),
+            };
+            Ok(session_reply(state::control_session(&live_state, &tx, control)))
+        });
+
+    get_route.or(new_route).or(control_route)
 }
 
-fn static_filters(mapping_path: PathBuf) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
-    let mapping_path = Arc::new(mapping_path);
+/// The session, or 409 with `{"error"}` when the control doesn't apply in its current phase.
+fn session_reply(result: Result<session::Session, String>) -> warp::reply::WithStatus<warp::reply::Json> {
+    match result {
+        Ok(session) => warp::reply::with_status(warp::reply::json(&session), warp::http::StatusCode::OK),
+        Err(e) => warp::reply::with_status(warp::reply::json(&serde_json::json!({ "error": e })), warp::http::StatusCode::CONFLICT),
+    }
+}
 
+/// `{"error": ..., "errors": [{"field", "message"}]}`, plus the offending `config` if given,
+/// 422 for a bad config and 500 if it could not be read or written.
+fn config_error_reply(e: &config::ConfigError, config: Option<serde_json::Value>) -> Box<dyn warp::Reply> {
+    let code = match e {
+        config::ConfigError::Io(_) | config::ConfigError::Db(_) => warp::http::StatusCode::INTERNAL_SERVER_ERROR,
+        _ => warp::http::StatusCode::UNPROCESSABLE_ENTITY,
+    };
+    let mut body = serde_json::json!({ "error": e.to_string(), "errors": e.field_errors() });
+    if let Some(config) = config {
+        body["config"] = config;
+    }
+    Box::new(warp::reply::with_status(warp::reply::json(&body), code))
+}
+
+fn static_filters(db: db::SharedDatabase) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
     warp::get().and(warp::path::tail()).map(move |tail: warp::path::Tail| {
         let path = tail.as_str();
-        let asset_path = if path == "" { "index.html" } else { path };
+        let asset_path = if path.is_empty() { "index.html" } else { path };
 
-        if asset_path == "mapping.json" && mapping_path.exists() {
-             match std::fs::read(mapping_path.as_path()) {
-                Ok(content) => {
-                     // println!("Serving external mapping.json");
-                     return warp::http::Response::builder()
+        // Names live in the database; the pages still load them as mapping.json
+        if asset_path == "mapping.json" {
+            match db.mapping() {
+                Ok(mapping) => {
+                    return warp::http::Response::builder()
                         .header("content-type", "application/json")
-                        .body(content)
+                        .body(serde_json::to_vec(&mapping).unwrap_or_default())
                         .unwrap_or_else(|_| warp::http::Response::new(vec![]));
-                },
-                Err(e) => eprintln!("Failed to read local mapping.json: {}", e),
-             }
+                }
+                Err(e) => eprintln!("Failed to read mapping from the database: {}", e),
+            }
         }
 
         match Asset::get(asset_path) {
@@ -124,85 +222,216 @@ fn static_filters(mapping_path: PathBuf) -> impl Filter<Extract = (impl warp::Re
     })
 }
 
-fn get_config_paths() -> (PathBuf, PathBuf) {
+/// `file_name` next to the executable, where data files live by default.
+fn data_path(file_name: &str) -> PathBuf {
     let mut exe_dir = std::env::current_exe().unwrap_or_else(|_| PathBuf::from("."));
     if exe_dir.file_name().is_some() {
         exe_dir.pop(); // Remove executable name
     }
-    
-    let config_path = exe_dir.join("config.json");
-    let mapping_path = exe_dir.join("mapping.json");
-    
-    (config_path, mapping_path)
+    exe_dir.join(file_name)
+}
+
+/// Opens the database, importing the names from `mapping_path` (or the
+/// built-in list) when it is new. Falls back to an in-memory database so
+/// timing still works if the file can't be opened.
+fn open_database(path: &std::path::Path, mapping_path: &std::path::Path) -> (db::SharedDatabase, Option<db::DbError>) {
+    let (db, error) = match db::Database::open(path) {
+        Ok(db) => (db, None),
+        Err(e) => {
+            eprintln!("Failed to open database {:?}, history will not be kept: {}", path, e);
+            match db::Database::in_memory() {
+                Ok(db) => (db, Some(e)),
+                Err(e) => panic!("Failed to create an in-memory database: {}", e),
+            }
+        }
+    };
+
+    if db.created() {
+        let content = std::fs::read(mapping_path)
+            .ok()
+            .or_else(|| Asset::get("mapping.json").map(|f| f.data.into_owned()));
+        let mapping: std::collections::HashMap<String, String> = content
+            .and_then(|content| serde_json::from_slice(&content).ok())
+            .unwrap_or_default();
+        match db.save_mapping(&mapping) {
+            Ok(n) => println!("Imported {} transponder names into the database", n),
+            Err(e) => eprintln!("Failed to import mapping: {}", e),
+        }
+    }
+    (Arc::new(db), error)
 }
 
 #[tokio::main]
 async fn main() {
-    let (config_path, mapping_path) = get_config_paths();
-    
-    println!("Using config file: {:?}", config_path);
-    println!("Using mapping file: {:?}", mapping_path);
+    let cli = cli::Cli::parse();
+    let config_path = cli.options.config.clone().unwrap_or_else(|| data_path("config.json"));
+    let mapping_path = cli.options.mapping.clone().unwrap_or_else(|| data_path("mapping.json"));
+    let journal_path = cli.options.journal.clone().unwrap_or_else(|| data_path("journal.jsonl"));
+    let database_path = cli.options.database.clone().unwrap_or_else(|| data_path("rrclivelaps.db"));
+
+    let result = match cli.command.unwrap_or(cli::Command::Serve) {
+        cli::Command::Serve => {
+            let (db, db_error) = open_database(&database_path, &mapping_path);
+            serve(&cli.options, config_path, db, db_error, Some(journal_path), None).await;
+            Ok(())
+        }
+        cli::Command::Replay { file, speed } => {
+            // A replay is not a real session, so it neither restores nor extends the journal or the history
+            let (db, db_error) = open_database(&database_path, &mapping_path);
+            serve(&cli.options, config_path, db, db_error, None, Some(replay::Replay::new(file, speed))).await;
+            Ok(())
+        }
+        cli::Command::Export { what: cli::ExportCommand::Mapping { output } } => {
+            let (db, _) = open_database(&database_path, &mapping_path);
+            db.mapping()
+                .map_err(|e| e.into())
+                .and_then(|mapping| export::to_output(output.as_deref(), |out| export::mapping_csv(&mapping, out)))
+        }
+        cli::Command::Export { what: cli::ExportCommand::Config { output } } => {
+            let (db, _) = open_database(&database_path, &mapping_path);
+            config::load_config(&db, &config_path)
+                .map_err(|e| e.into())
+                .and_then(|config| export::to_output(output.as_deref(), |out| {
+                    serde_json::to_writer_pretty(&mut *out, &config)?;
+                    Ok(out.write_all(b"\n")?)
+                }))
+        }
+        cli::Command::Import { what: cli::ImportCommand::Config { file } } => {
+            let (db, _) = open_database(&database_path, &mapping_path);
+            match config::read_config(&file) {
+                Ok(Some(config)) => db.save_config(&config).map_err(|e| e.into()).map(|()| {
+                    println!("Imported settings from {:?}; they apply the next time the server starts", file);
+                }),
+                Ok(None) => Err(format!("{:?} does not exist", file).into()),
+                Err(e) => Err(e.into()),
+            }
+        }
+        cli::Command::Export { what: cli::ExportCommand::Results { session, format, laps, output } } => {
+            let (db, _) = open_database(&database_path, &mapping_path);
+            export::SessionResults::load(&db, session)
+                .map_err(|e| e.into())
+                .and_then(|results| {
+                    export::to_output(output.as_deref(), |out| match (format, laps) {
+                        (cli::ResultsFormat::Csv, false) => export::classification_csv(&results, out),
+                        (cli::ResultsFormat::Csv, true) => export::laps_csv(&results, out),
+                        (cli::ResultsFormat::Xlsx, _) => export::results_xlsx(&results, out),
+                        (cli::ResultsFormat::Pdf, _) => sheets::pdf(&results, out),
+                        (cli::ResultsFormat::Html, _) => sheets::html(&results, out),
+                    })
+                })
+        }
+        cli::Command::ProbeSerial { port, seconds } => usb::probe::run(port, seconds).await,
+    };
 
+    if let Err(e) = result {
+        eprintln!("Error: {}", e);
+        std::process::exit(1);
+    }
+}
+
+/// Runs the web server and the configured timing source, or `replay` in its place.
+/// Settings come from `db`, imported from `config_path` the first time.
+/// With a journal, the live state is rebuilt from it before the source starts
+/// and history is recorded in `db`.
+async fn serve(options: &cli::Options, config_path: PathBuf, db: db::SharedDatabase, db_error: Option<db::DbError>, journal_path: Option<PathBuf>, replay: Option<replay::Replay>) {
     // Load Configuration
-    let config = config::load_config(config_path.to_str().unwrap_or("config.json"));
+    let config = match config::load_config(&db, &config_path) {
+        Ok(config) => config,
+        Err(e) => {
+            // Nothing is stored, so the file can still be fixed; the manage page shows the errors too
+            eprintln!("Could not load the settings, starting with defaults: {}", e);
+            config::Config::default()
+        }
+    };
     println!("Loaded config: {:?}", config);
 
     // Initialize Channels and State
-    let (tx, _rx) = broadcast::channel::<WsMessage>(100);
-    let is_connected = Arc::new(AtomicBool::new(false));
-    
-    // Spawn Decoder Task based on Mode
-    let tx_clone = tx.clone();
-    let is_connected_clone = is_connected.clone();
-    let config_clone = config.clone();
-
-    tokio::spawn(async move {
-        match config_clone.mode {
-            config::AppMode::Tcp { host, port } => {
-                println!("Starting in TCP Mode: {}:{}", host, port);
-                let ip = host.parse().expect("Invalid IP address");
-                let decoder = converter::decoder::Decoder::new(ip, port);
-                decoder.run(tx_clone, is_connected_clone).await;
-            },
-            config::AppMode::Usb { port_path } => {
-                println!("Starting in USB Mode: {}", port_path);
-                let usb_box = usb::decoder::UsbBox::new(port_path, 10);
-                usb_box.run(tx_clone, is_connected_clone).await;
-            },
-            config::AppMode::TcpServer { port } => {
-                println!("Starting in TCP Server Mode on port {}", port);
-                json_server::run_server(tx_clone, port, is_connected_clone, false).await;
+    let (tx, _rx) = broadcast::channel::<WsMessage>(config.broadcast_capacity.max(1));
+    let source_name = replay.as_ref().map_or_else(|| config.mode.describe(), |r| r.describe());
+    let status = status::StatusHandle::new(source_name, tx.clone());
+    let live_state = state::LiveState::shared();
+    if let Some(journal_path) = &journal_path {
+        let mut state = live_state.lock().unwrap();
+        // The journal brings the rules its passings were counted with; these take over after it
+        state.set_rules(config.rules.clone());
+        match state.open_journal(journal_path) {
+            Ok(0) => println!("Journaling passings to {:?}", journal_path),
+            Ok(n) => println!("Restored {} entries from {:?}", n, journal_path),
+            Err(e) => {
+                eprintln!("Failed to open journal {:?}, passings will not be saved: {}", journal_path, e);
+                state.raise_alert(format!("Passings are not being saved: {}", e));
             }
         }
-    });
-
-    // Shutdown channel
-    let (shutdown_tx, mut shutdown_rx) = mpsc::channel(1);
+        if let Err(e) = state.attach_database(db.clone()) {
+            eprintln!("Failed to record the session in the database: {}", e);
+        }
+    }
+    if let Some(e) = db_error {
+        live_state.lock().unwrap().raise_alert(format!("History and settings are not being kept: {}", e));
+    }
+    let passings = state::spawn(tx.clone(), live_state.clone());
+    // Replays don't store sessions, so there are no results to write
+    if let (Some(dir), None) = (&options.results_dir, &replay) {
+        println!("Writing results sheets to {:?}", dir);
+        sheets::spawn_writer(dir.clone(), db.clone(), live_state.clone(), &tx);
+    }
+    
+    // Spawn Decoder Task based on Mode
+    let sources = match replay {
+        Some(replay) => sources::SourceManager::replay(config.clone(), replay, passings, status.clone(), live_state.clone(), options.debug),
+        None => sources::SourceManager::start(config.clone(), passings, status.clone(), live_state.clone(), options.debug),
+    };
 
     // Setup Routes
-    let api = api_filters(config_path, mapping_path.clone(), shutdown_tx);
-    // WS route needs tx and is_connected
-    let ws = ws_handler::ws_routes(tx, is_connected);
-    let static_files = static_filters(mapping_path);
-
-    let routes = api.or(ws).or(static_files);
-
-    // Bind to port 0 to get a random free port
-    let addr: SocketAddr = ([127, 0, 0, 1], 8080).into();
+    let clients = clients::ClientRegistry::default();
+    // --bind only affects this run; the config file keeps its own address
+    let mut server_config = config.server.clone();
+    if let Some(bind) = options.bind {
+        bind.apply(&mut server_config);
+    }
+    let addr = server_config.addr();
+    let server_info = network::ServerInfo::new(addr);
+    let api = api_filters(config_path, db.clone(), clients.clone(), server_info.clone(), sources.clone());
+    let session_api = session_filters(live_state.clone(), tx.clone());
+    let history = history::history_routes(db.clone());
+    // WS and SSE routes need tx, the source status, the live state, the client registry, the groups and the event log
+    let feed = ws_handler::WsContext {
+        events: events::EventLog::spawn(&tx, config.broadcast_capacity),
+        tx,
+        status,
+        state: live_state,
+        clients,
+        groups: sources.groups(),
+    };
+    let ws = ws_handler::ws_routes(feed.clone());
+    let sse = sse::sse_routes(feed);
+    let static_files = static_filters(db);
+
+    let routes = api.or(session_api).or(history).or(ws).or(sse).or(static_files);
 
     println!("Starting server...");
-    let server = warp::serve(routes).bind_with_graceful_shutdown(addr, async move {
-        shutdown_rx.recv().await;
+    let server = warp::serve(routes).try_bind_with_graceful_shutdown(addr, async move {
+        let _ = tokio::signal::ctrl_c().await;
         println!("Shutting down server...");
     });
-    
-    let (addr, server) = server;
+
+    let (addr, server) = match server {
+        Ok(bound) => bound,
+        Err(e) => {
+            eprintln!("Failed to bind web server to {}: {}", addr, e);
+            return;
+        }
+    };
     println!("Listening on http://{}", addr);
+    for url in &server_info.lan_urls {
+        println!("Displays on the network can open {}", url);
+    }
 
     // Open browser
-    let url = format!("http://{}", addr);
-    if let Err(e) = webbrowser::open(&url) {
-        eprintln!("Failed to open browser: {}", e);
+    if !options.no_browser {
+        if let Err(e) = webbrowser::open(&server_info.local_url) {
+            eprintln!("Failed to open browser: {}", e);
+        }
     }
 
     server.await;
@@ -213,13 +442,40 @@ mod tests {
     use super::*;
     use std::collections::HashMap;
 
+    fn server_info() -> network::ServerInfo {
+        network::ServerInfo::new("127.0.0.1:8080".parse().unwrap())
+    }
+
+    fn ws_context(
+        tx: broadcast::Sender<WsMessage>,
+        status: status::StatusHandle,
+        state: state::SharedState,
+        clients: clients::ClientRegistry,
+    ) -> ws_handler::WsContext {
+        let groups = config::Groups::from([("junior".to_string(), vec!["0000002".to_string()])]);
+        let events = events::EventLog::spawn(&tx, 16);
+        ws_handler::WsContext { tx, status, state, clients, groups: Arc::new(std::sync::RwLock::new(groups)), events }
+    }
+
+    fn memory_db() -> db::SharedDatabase {
+        Arc::new(db::Database::in_memory().unwrap())
+    }
+
+    /// A manager running a TCP server on an ephemeral port, so nothing external is needed.
+    fn source_manager() -> sources::SourceManager {
+        let (tx, _rx) = broadcast::channel(16);
+        let status = status::StatusHandle::new("test", tx);
+        let (passings, _) = tokio::sync::mpsc::unbounded_channel();
+        let config = config::Config { mode: config::AppMode::TcpServer { port: 0 }, ..Default::default() };
+        sources::SourceManager::start(config, passings, status, state::LiveState::shared(), false).with_settle_timeout(std::time::Duration::from_millis(200))
+    }
+
     #[tokio::test]
     async fn test_save_mapping() {
-        let (tx, _) = mpsc::channel(1);
-        let mapping_path = PathBuf::from("test_mapping.json");
+        let db = memory_db();
         let config_path = PathBuf::from("test_config_dummy.json");
         
-        let filter = api_filters(config_path, mapping_path.clone(), tx);
+        let filter = api_filters(config_path, db.clone(), clients::ClientRegistry::default(), server_info(), source_manager());
 
         let mut map = HashMap::new();
         map.insert("001".to_string(), "Test Driver".to_string());
@@ -233,27 +489,26 @@ mod tests {
 
         assert_eq!(resp.status(), 200);
 
-        // Check if file exists and has content
-        let content = std::fs::read_to_string(&mapping_path).expect("Failed to read file");
-        assert!(content.contains("Test Driver"));
-        
-        // Clean up
-        let _ = std::fs::remove_file(mapping_path);
+        assert_eq!(db.mapping().unwrap().get("001").map(String::as_str), Some("Test Driver"));
+
+        // The pages load the names as mapping.json
+        let resp = warp::test::request().path("/mapping.json").reply(&static_filters(db)).await;
+        let served: HashMap<String, String> = serde_json::from_slice(resp.body()).unwrap();
+        assert_eq!(served, map);
     }
 
     #[tokio::test]
     async fn test_save_config() {
-        let test_file = PathBuf::from("test_config.json");
-        let mapping_dummy = PathBuf::from("test_mapping_dummy.json");
-        
-        let (tx, mut rx) = mpsc::channel(1);
-        let filter = api_filters(test_file.clone(), mapping_dummy, tx);
+        let db = memory_db();
+        let filter = api_filters(PathBuf::from("test_config_dummy.json"), db.clone(), clients::ClientRegistry::default(), server_info(), source_manager());
 
         let new_config = config::Config {
             mode: config::AppMode::Tcp {
                 host: "10.0.0.1".to_string(),
-                port: 1234
-            }
+                port: 1234,
+                fallback_hosts: Vec::new(),
+            },
+            ..Default::default()
         };
 
         let resp = warp::test::request()
@@ -265,15 +520,204 @@ mod tests {
 
         assert_eq!(resp.status(), 200);
 
-        // Verify file content
-        let content = std::fs::read_to_string(&test_file).expect("Failed to read config.json");
-        assert!(content.contains("10.0.0.1"));
-        
-        // Cleanup
-        let _ = std::fs::remove_file(test_file);
-        
-        // Verify shutdown signal was sent
-        let msg = rx.recv().await;
-        assert!(msg.is_some());
+        // Stored in the database, and read back from there
+        assert_eq!(db.config().unwrap().unwrap()["mode"]["host"], "10.0.0.1");
+        let resp_get = warp::test::request().path("/api/config").reply(&filter).await;
+        let stored: serde_json::Value = serde_json::from_slice(resp_get.body()).unwrap();
+        assert_eq!(stored["mode"]["port"], 1234);
+        assert!(!std::path::Path::new("test_config_dummy.json").exists());
+
+        // The new source was started in-process; nothing answers on 10.0.0.1 so it is not ok yet
+        let outcome: serde_json::Value = serde_json::from_slice(resp.body()).unwrap();
+        assert_eq!(outcome["status"]["source"], "tcp 10.0.0.1:1234");
+        assert_eq!(outcome["ok"], false);
+        assert_eq!(outcome["restart_required"], false);
+    }
+
+    #[tokio::test]
+    async fn test_save_invalid_config_reports_field_errors() {
+        let db = memory_db();
+        let filter = api_filters(PathBuf::from("test_config_dummy.json"), db.clone(), clients::ClientRegistry::default(), server_info(), source_manager());
+
+        let resp = warp::test::request()
+            .method("POST")
+            .path("/api/config")
+            .json(&serde_json::json!({ "mode": { "mode": "tcp_server", "port": 70000 } }))
+            .reply(&filter)
+            .await;
+
+        assert_eq!(resp.status(), 422);
+        let body: serde_json::Value = serde_json::from_slice(resp.body()).unwrap();
+        assert_eq!(body["errors"][0]["field"], "mode.port");
+        assert!(db.config().unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_load_invalid_config_sends_it_back() {
+        let db = memory_db();
+        let path = std::env::temp_dir().join(format!("rrclivelaps-invalid-{}.json", std::process::id()));
+        std::fs::write(&path, r#"{"version": 2, "mode": {"mode": "tcp_server", "port": 70000}, "server": {"bind": "0.0.0.0", "port": 8181}}"#).unwrap();
+        let filter = api_filters(path.clone(), db.clone(), clients::ClientRegistry::default(), server_info(), source_manager());
+
+        let resp = warp::test::request().path("/api/config").reply(&filter).await;
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(resp.status(), 422);
+        let body: serde_json::Value = serde_json::from_slice(resp.body()).unwrap();
+        assert_eq!(body["errors"][0]["field"], "mode.port");
+        // Saving a fix on top of it keeps the settings the page does not show
+        assert_eq!(body["config"]["server"]["port"], 8181);
+        assert!(db.config().unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_ws_sends_status_and_snapshot_on_connect() {
+        let (tx, _rx) = broadcast::channel(16);
+        let status = status::StatusHandle::new("test", tx.clone());
+        let live_state = state::LiveState::shared();
+        live_state.lock().unwrap().record_passing(&messages::Passing {
+            passing_number: 1,
+            transponder: "0000001".to_string(),
+            timestamp: messages::now(),
+            strength: 0,
+            tran_code: String::new(),
+            noise: 0,
+            hits: 0,
+            timing_point: None,
+        });
+
+        let filter = ws_handler::ws_routes(ws_context(tx, status, live_state, clients::ClientRegistry::default()));
+        let mut client = warp::test::ws().path("/ws").handshake(filter).await.expect("handshake");
+
+        let first: serde_json::Value = serde_json::from_str(client.recv().await.unwrap().to_str().unwrap()).unwrap();
+        assert_eq!(first["type"], "status");
+
+        let second: serde_json::Value = serde_json::from_str(client.recv().await.unwrap().to_str().unwrap()).unwrap();
+        assert_eq!(second["type"], "snapshot");
+        assert_eq!(second["data"]["transponders"][0]["transponder"], "0000001");
+    }
+
+    #[tokio::test]
+    async fn test_ws_resyncs_lagged_client() {
+        let (tx, _rx) = broadcast::channel(2);
+        let status = status::StatusHandle::new("test", tx.clone());
+        let filter = ws_handler::ws_routes(ws_context(tx.clone(), status.clone(), state::LiveState::shared(), clients::ClientRegistry::default()));
+        let mut client = warp::test::ws().path("/ws").handshake(filter).await.expect("handshake");
+
+        // Initial status and snapshot
+        client.recv().await.unwrap();
+        client.recv().await.unwrap();
+
+        // Overflow the channel before the handler gets to run
+        for state in [status::SourceState::Handshaking, status::SourceState::Connected, status::SourceState::Connecting] {
+            status.set_state(state);
+        }
+
+        let lagged: serde_json::Value = serde_json::from_str(client.recv().await.unwrap().to_str().unwrap()).unwrap();
+        assert_eq!(lagged["type"], "lagged");
+        assert_eq!(lagged["data"]["skipped"], 1);
+
+        let snapshot: serde_json::Value = serde_json::from_str(client.recv().await.unwrap().to_str().unwrap()).unwrap();
+        assert_eq!(snapshot["type"], "snapshot");
+    }
+
+    #[tokio::test]
+    async fn test_clients_endpoint_lists_live_ws_clients() {
+        let (tx, _rx) = broadcast::channel(16);
+        let registry = clients::ClientRegistry::default();
+        let status = status::StatusHandle::new("test", tx.clone());
+        let ws = ws_handler::ws_routes(ws_context(tx, status, state::LiveState::shared(), registry.clone()));
+        let api = api_filters(PathBuf::from("test_config_clients.json"), memory_db(), registry, server_info(), source_manager());
+
+        let client = warp::test::ws()
+            .path("/ws?format=legacy")
+            .header("user-agent", "pit-wall")
+            .handshake(ws)
+            .await
+            .expect("handshake");
+
+        let resp = warp::test::request().path("/api/clients").reply(&api).await;
+        let clients: serde_json::Value = serde_json::from_slice(resp.body()).unwrap();
+        assert_eq!(clients.as_array().unwrap().len(), 1);
+        assert_eq!(clients[0]["user_agent"], "pit-wall");
+        assert_eq!(clients[0]["format"], "legacy");
+
+        // Once the connection is gone, so is the client
+        drop(client);
+        let mut clients = serde_json::Value::Null;
+        for _ in 0..50 {
+            let resp = warp::test::request().path("/api/clients").reply(&api).await;
+            clients = serde_json::from_slice(resp.body()).unwrap();
+            if clients.as_array().unwrap().is_empty() {
+                break;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+        }
+        assert_eq!(clients, serde_json::json!([]));
+    }
+
+    #[tokio::test]
+    async fn test_session_api_controls_and_broadcasts() {
+        let (tx, mut rx) = broadcast::channel(16);
+        let filter = session_filters(state::LiveState::shared(), tx);
+
+        let resp = warp::test::request()
+            .method("POST")
+            .path("/api/session")
+            .json(&serde_json::json!({ "name": "Final", "kind": "race", "start": "api", "limit": { "laps": 10 } }))
+            .reply(&filter)
+            .await;
+        assert_eq!(resp.status(), 200);
+        assert!(matches!(rx.recv().await.unwrap(), WsMessage::SessionReset { .. }));
+        assert!(matches!(rx.recv().await.unwrap(), WsMessage::Session(s) if s.phase == session::SessionPhase::Waiting));
+
+        let resp = warp::test::request().method("POST").path("/api/session/start").reply(&filter).await;
+        let body: serde_json::Value = serde_json::from_slice(resp.body()).unwrap();
+        assert_eq!(body["phase"], "running");
+        assert_eq!(body["limit"], serde_json::json!({ "laps": 10 }));
+
+        let resp = warp::test::request().method("POST").path("/api/session/start").reply(&filter).await;
+        assert_eq!(resp.status(), 409);
+
+        let resp = warp::test::request().method("POST").path("/api/session").json(&serde_json::json!({ "limit": { "laps": 0 } })).reply(&filter).await;
+        assert_eq!(resp.status(), 422);
+
+        let resp = warp::test::request().path("/api/session").reply(&filter).await;
+        let body: serde_json::Value = serde_json::from_slice(resp.body()).unwrap();
+        assert_eq!(body["name"], "Final");
+    }
+
+    #[tokio::test]
+    async fn test_ws_query_filters_passings() {
+        let (tx, _rx) = broadcast::channel(16);
+        let status = status::StatusHandle::new("test", tx.clone());
+        let filter = ws_handler::ws_routes(ws_context(tx.clone(), status, state::LiveState::shared(), clients::ClientRegistry::default()));
+
+        let rejected = warp::test::ws().path("/ws?groups=senior").handshake(filter.clone()).await;
+        assert!(rejected.is_err());
+
+        let mut client = warp::test::ws()
+            .path("/ws?groups=junior&types=passing")
+            .handshake(filter)
+            .await
+            .expect("handshake");
+
+        for transponder in ["0000001", "0000002"] {
+            let _ = tx.send(WsMessage::Passing(messages::Passing {
+                passing_number: 1,
+                transponder: transponder.to_string(),
+                timestamp: messages::now(),
+                strength: 0,
+                tran_code: String::new(),
+                noise: 0,
+                hits: 0,
+                timing_point: None,
+            }));
+        }
+
+        // Status and snapshot are filtered out by type, the first passing by group
+        let msg: serde_json::Value = serde_json::from_str(client.recv().await.unwrap().to_str().unwrap()).unwrap();
+        assert_eq!(msg["type"], "passing");
+        assert_eq!(msg["data"]["transponder"], "0000002");
     }
 }
@@ -1,21 +1,347 @@
+use chrono::{DateTime, FixedOffset, Local, NaiveDateTime, TimeDelta, TimeZone};
 use serde::{Deserialize, Serialize};
 
-#[derive(Clone, Debug, Serialize, Deserialize)]
-#[serde(untagged)]
+use crate::commands::CommandReply;
+use crate::laps::{Lap, SuppressedRead};
+use crate::session::Session;
+use crate::standings::Standings;
+use crate::state::{Alert, Snapshot};
+use crate::status::SourceStatus;
+
+/// Passing time as reported by a source, normalised to the local offset.
+pub type Timestamp = DateTime<FixedOffset>;
+
+/// Lap and gap times.
+pub type LapTime = TimeDelta;
+
+/// Version of the `{"type", "v", "data"}` envelope sent to WebSocket clients.
+pub const PROTOCOL_VERSION: u32 = 1;
+
+#[derive(Clone, Debug, Serialize)]
+#[serde(tag = "type", content = "data", rename_all = "snake_case")]
 pub enum WsMessage {
     Passing(Passing),
-    Status { event: String },
+    /// Completed lap, computed from the passings by the lap engine.
+    Lap(Lap),
+    /// A passing the lap rules kept from counting, with the reason.
+    Suppressed(SuppressedRead),
+    /// Leaderboard after a counted passing.
+    Standings(Standings),
+    Status(SourceStatus),
+    Alert(Alert),
+    Snapshot(Snapshot),
+    /// Session settings and phase, sent whenever either changes.
+    Session(Session),
+    SessionReset {
+        #[serde(with = "timestamp")]
+        started: Timestamp,
+    },
+    /// Answer to a client command; only sent to the client that asked.
+    Reply(CommandReply),
+    /// The client fell behind and missed messages; a fresh snapshot follows.
+    Lagged { skipped: u64 },
+}
+
+/// Value of the envelope `type` field, used to pick which messages a client gets.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MessageKind {
+    Passing,
+    Lap,
+    Suppressed,
+    Standings,
+    Status,
+    Alert,
+    Snapshot,
+    Session,
+    SessionReset,
+    Reply,
+    Lagged,
+}
+
+/// How messages are framed on the wire for a given client.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum WireFormat {
+    /// `{"type": "passing", "v": 1, "data": {...}}`
+    #[default]
+    Envelope,
+    /// The original untagged format, kept for existing displays.
+    Legacy,
+}
+
+#[derive(Serialize)]
+struct Envelope<'a> {
+    #[serde(flatten)]
+    message: &'a WsMessage,
+    v: u32,
+}
+
+#[derive(Serialize)]
+#[serde(untagged)]
+enum LegacyMessage<'a> {
+    Passing(LegacyPassing<'a>),
+    Status { event: &'a str },
+}
+
+/// A passing in the original layout, with the time split into strings in
+/// the decoder's local time: `date` and `rtc_time` as
+/// `2024-01-12T09:06:35.944`, `time` as `09:06:35.944`.
+#[derive(Serialize)]
+struct LegacyPassing<'a> {
+    passing_number: u32,
+    transponder: &'a str,
+    rtc_time: String,
+    strength: u32,
+    tran_code: &'a str,
+    noise: u32,
+    hits: u32,
+    date: String,
+    time: String,
+}
+
+impl<'a> From<&'a Passing> for LegacyPassing<'a> {
+    fn from(p: &'a Passing) -> Self {
+        let date = p.timestamp.format("%Y-%m-%dT%H:%M:%S%.3f").to_string();
+        LegacyPassing {
+            passing_number: p.passing_number,
+            transponder: &p.transponder,
+            rtc_time: date.clone(),
+            strength: p.strength,
+            tran_code: &p.tran_code,
+            noise: p.noise,
+            hits: p.hits,
+            time: p.timestamp.format("%H:%M:%S%.3f").to_string(),
+            date,
+        }
+    }
+}
+
+impl WsMessage {
+    pub fn kind(&self) -> MessageKind {
+        match self {
+            WsMessage::Passing(_) => MessageKind::Passing,
+            WsMessage::Lap(_) => MessageKind::Lap,
+            WsMessage::Suppressed(_) => MessageKind::Suppressed,
+            WsMessage::Standings(_) => MessageKind::Standings,
+            WsMessage::Status(_) => MessageKind::Status,
+            WsMessage::Alert(_) => MessageKind::Alert,
+            WsMessage::Snapshot(_) => MessageKind::Snapshot,
+            WsMessage::Session(_) => MessageKind::Session,
+            WsMessage::SessionReset { .. } => MessageKind::SessionReset,
+            WsMessage::Reply(_) => MessageKind::Reply,
+            WsMessage::Lagged { .. } => MessageKind::Lagged,
+        }
+    }
+
+    /// Serialises the message for the given wire format. Returns `None` when
+    /// the message has no representation in that format.
+    pub fn encode(&self, format: WireFormat) -> Option<String> {
+        let result = match format {
+            WireFormat::Envelope => serde_json::to_string(&Envelope {
+                message: self,
+                v: PROTOCOL_VERSION,
+            }),
+            WireFormat::Legacy => {
+                let legacy = match self {
+                    WsMessage::Passing(p) => LegacyMessage::Passing(p.into()),
+                    WsMessage::Status(status) => LegacyMessage::Status {
+                        event: if status.is_connected() { "connected" } else { "disconnected" },
+                    },
+                    _ => return None,
+                };
+                serde_json::to_string(&legacy)
+            }
+        };
+
+        match result {
+            Ok(json) => Some(json),
+            Err(e) => {
+                eprintln!("Failed to serialise message: {}", e);
+                None
+            }
+        }
+    }
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct Passing {
     pub passing_number: u32,
     pub transponder: String,
-    pub rtc_time: String,
+    #[serde(with = "timestamp")]
+    pub timestamp: Timestamp,
     pub strength: u32,
     pub tran_code: String,
     pub noise: u32,
     pub hits: u32,
-    pub date: String,
-    pub time: String,
+    /// Loop / timing point that saw the transponder, when the source reports it.
+    pub timing_point: Option<String>,
+}
+
+/// Current wall-clock time in the local offset.
+pub fn now() -> Timestamp {
+    Local::now().fixed_offset()
+}
+
+/// Interprets a decoder wall-clock reading (no offset) as local time.
+pub fn local_timestamp(naive: NaiveDateTime) -> Option<Timestamp> {
+    Local
+        .from_local_datetime(&naive)
+        .earliest()
+        .map(|dt| dt.fixed_offset())
+}
+
+/// Converts any timestamp to the local offset so all sources agree.
+pub fn to_local(dt: DateTime<impl TimeZone>) -> Timestamp {
+    dt.with_timezone(&Local).fixed_offset()
+}
+
+/// RFC 3339 with microsecond precision and an explicit offset, e.g.
+/// `2024-01-12T09:06:35.944000+01:00`.
+pub mod timestamp {
+    use super::Timestamp;
+    use chrono::{DateTime, SecondsFormat};
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn format(ts: &Timestamp) -> String {
+        ts.to_rfc3339_opts(SecondsFormat::Micros, false)
+    }
+
+    pub fn serialize<S: Serializer>(ts: &Timestamp, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&format(ts))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Timestamp, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        DateTime::parse_from_rfc3339(&s).map_err(serde::de::Error::custom)
+    }
+
+    /// Same format for optional fields, `null` when unset.
+    pub mod option {
+        use super::Timestamp;
+        use serde::{Deserialize, Deserializer, Serializer};
+
+        pub fn serialize<S: Serializer>(ts: &Option<Timestamp>, serializer: S) -> Result<S::Ok, S::Error> {
+            match ts {
+                Some(ts) => super::serialize(ts, serializer),
+                None => serializer.serialize_none(),
+            }
+        }
+
+        pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<Timestamp>, D::Error> {
+            match Option::<String>::deserialize(deserializer)? {
+                Some(s) => chrono::DateTime::parse_from_rfc3339(&s)
+                    .map(Some)
+                    .map_err(serde::de::Error::custom),
+                None => Ok(None),
+            }
+        }
+    }
+}
+
+/// Durations as fractional seconds with microsecond precision, e.g. `23.456789`.
+pub mod seconds {
+    use super::LapTime;
+    use serde::Serializer;
+
+    pub fn to_secs(d: &LapTime) -> f64 {
+        d.num_microseconds().unwrap_or(i64::MAX) as f64 / 1_000_000.0
+    }
+
+    /// Inverse of [`to_secs`], rounded to the microsecond.
+    pub fn from_secs(secs: f64) -> LapTime {
+        LapTime::microseconds((secs * 1_000_000.0).round() as i64)
+    }
+
+    pub fn serialize<S: Serializer>(d: &LapTime, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_f64(to_secs(d))
+    }
+
+    /// Same format for optional fields, `null` when unset.
+    pub mod option {
+        use super::LapTime;
+        use serde::{Deserialize, Deserializer, Serializer};
+
+        pub fn serialize<S: Serializer>(d: &Option<LapTime>, serializer: S) -> Result<S::Ok, S::Error> {
+            match d {
+                Some(d) => super::serialize(d, serializer),
+                None => serializer.serialize_none(),
+            }
+        }
+
+        pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<LapTime>, D::Error> {
+            Ok(Option::<f64>::deserialize(deserializer)?.map(super::from_secs))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_timestamp_round_trip() {
+        let ts = DateTime::parse_from_rfc3339("2024-01-12T09:06:35.944123+01:00").unwrap();
+        let passing = Passing {
+            passing_number: 1,
+            transponder: "0000001".to_string(),
+            timestamp: ts,
+            strength: 0,
+            tran_code: String::new(),
+            noise: 0,
+            hits: 0,
+            timing_point: None,
+        };
+
+        let json = serde_json::to_value(&passing).unwrap();
+        assert_eq!(json["timestamp"], "2024-01-12T09:06:35.944123+01:00");
+
+        let back: Passing = serde_json::from_value(json).unwrap();
+        assert_eq!(back.timestamp, ts);
+    }
+
+    #[test]
+    fn test_envelope_and_legacy_encoding() {
+        let msg = WsMessage::Status(SourceStatus {
+            source: "tcp 127.0.0.1:3601".to_string(),
+            state: crate::status::SourceState::Connected,
+            last_error: None,
+            since: now(),
+            last_passing: None,
+        });
+
+        let envelope: serde_json::Value = serde_json::from_str(&msg.encode(WireFormat::Envelope).unwrap()).unwrap();
+        assert_eq!(envelope["type"], "status");
+        assert_eq!(envelope["v"], PROTOCOL_VERSION);
+        assert_eq!(envelope["data"]["state"], "connected");
+        assert_eq!(envelope["data"]["last_passing"], serde_json::Value::Null);
+
+        let legacy: serde_json::Value = serde_json::from_str(&msg.encode(WireFormat::Legacy).unwrap()).unwrap();
+        assert_eq!(legacy, serde_json::json!({ "event": "connected" }));
+    }
+
+    #[test]
+    fn test_legacy_passing_layout() {
+        let msg = WsMessage::Passing(Passing {
+            passing_number: 7,
+            transponder: "0000001".to_string(),
+            timestamp: DateTime::parse_from_rfc3339("2024-01-12T09:06:35.944123+01:00").unwrap(),
+            strength: 120,
+            tran_code: "ZZ".to_string(),
+            noise: 3,
+            hits: 40,
+            timing_point: Some("1".to_string()),
+        });
+
+        assert_eq!(
+            msg.encode(WireFormat::Legacy).unwrap(),
+            r#"{"passing_number":7,"transponder":"0000001","rtc_time":"2024-01-12T09:06:35.944","strength":120,"tran_code":"ZZ","noise":3,"hits":40,"date":"2024-01-12T09:06:35.944","time":"09:06:35.944"}"#
+        );
+    }
+
+    #[test]
+    fn test_to_local_keeps_instant() {
+        let utc = DateTime::parse_from_rfc3339("2024-01-12T09:06:35.944Z").unwrap();
+        assert_eq!(to_local(utc), utc);
+    }
 }
@@ -0,0 +1,79 @@
+use serde::Serialize;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+
+/// Where the HTTP server is reachable, for `/api/server` and the startup log.
+#[derive(Clone, Debug, Serialize)]
+pub struct ServerInfo {
+    pub bind: SocketAddr,
+    /// Addresses other devices on the network can open.
+    pub lan_urls: Vec<String>,
+    pub ws_urls: Vec<String>,
+    /// Address for the browser on this machine.
+    pub local_url: String,
+}
+
+impl ServerInfo {
+    pub fn new(bind: SocketAddr) -> Self {
+        let interfaces = match local_ip_address::list_afinet_netifas() {
+            Ok(list) => list.into_iter().map(|(_, ip)| ip).collect(),
+            Err(e) => {
+                eprintln!("Failed to list network interfaces: {}", e);
+                Vec::new()
+            }
+        };
+        Self::from_interfaces(bind, &interfaces)
+    }
+
+    fn from_interfaces(bind: SocketAddr, interfaces: &[IpAddr]) -> Self {
+        let port = bind.port();
+        let lan_ips: Vec<IpAddr> = if bind.ip().is_unspecified() {
+            interfaces
+                .iter()
+                .copied()
+                .filter(|ip| !ip.is_loopback() && ip.is_ipv4() == bind.is_ipv4())
+                .collect()
+        } else if bind.ip().is_loopback() {
+            Vec::new()
+        } else {
+            vec![bind.ip()]
+        };
+
+        let local_ip = if bind.ip().is_unspecified() {
+            IpAddr::V4(Ipv4Addr::LOCALHOST)
+        } else {
+            bind.ip()
+        };
+
+        Self {
+            bind,
+            lan_urls: lan_ips.iter().map(|ip| url("http", *ip, port)).collect(),
+            ws_urls: lan_ips.iter().map(|ip| format!("{}/ws", url("ws", *ip, port))).collect(),
+            local_url: url("http", local_ip, port),
+        }
+    }
+}
+
+fn url(scheme: &str, ip: IpAddr, port: u16) -> String {
+    format!("{}://{}", scheme, SocketAddr::new(ip, port))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_lan_urls_for_wildcard_bind() {
+        let interfaces: Vec<IpAddr> = vec!["127.0.0.1".parse().unwrap(), "192.168.1.20".parse().unwrap(), "fe80::1".parse().unwrap()];
+        let info = ServerInfo::from_interfaces("0.0.0.0:8080".parse().unwrap(), &interfaces);
+
+        assert_eq!(info.lan_urls, vec!["http://192.168.1.20:8080"]);
+        assert_eq!(info.ws_urls, vec!["ws://192.168.1.20:8080/ws"]);
+        assert_eq!(info.local_url, "http://127.0.0.1:8080");
+    }
+
+    #[test]
+    fn test_loopback_bind_has_no_lan_urls() {
+        let info = ServerInfo::from_interfaces("127.0.0.1:8080".parse().unwrap(), &["192.168.1.20".parse().unwrap()]);
+        assert!(info.lan_urls.is_empty());
+    }
+}
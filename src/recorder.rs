@@ -0,0 +1,203 @@
+use std::sync::mpsc;
+use std::thread::{self, JoinHandle};
+
+use crate::db::{Database, SharedDatabase};
+use crate::journal::{Journal, JournalEntry};
+use crate::messages::Timestamp;
+use crate::session::Session;
+
+/// A database write, given the database and the current session's row.
+pub type Store = Box<dyn FnOnce(&Database, Option<i64>) -> rusqlite::Result<()> + Send>;
+
+/// Called with a message the first time a journal write fails.
+pub type ErrorHandler = Box<dyn Fn(String) + Send>;
+
+enum Job {
+    AttachJournal(Journal),
+    AttachDatabase(SharedDatabase, i64),
+    OnJournalError(ErrorHandler),
+    Journal(JournalEntry),
+    NewSession(Session, Timestamp),
+    Store(Store),
+    Written(mpsc::Sender<()>),
+}
+
+/// Durable writes of the live state, the journal and the history database,
+/// made in order on a thread of their own so the state lock is never held
+/// across an fsync or a query. Dropping it waits for the queued writes.
+pub struct Recorder {
+    jobs: Option<mpsc::Sender<Job>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Recorder {
+    pub fn spawn() -> Recorder {
+        let (jobs, rx) = mpsc::channel();
+        let thread = thread::Builder::new()
+            .name("recorder".to_string())
+            .spawn(move || Writer::default().run(rx))
+            .expect("failed to start the recorder thread");
+        Recorder { jobs: Some(jobs), thread: Some(thread) }
+    }
+
+    fn send(&self, job: Job) {
+        if let Some(jobs) = &self.jobs {
+            let _ = jobs.send(job);
+        }
+    }
+
+    pub fn attach_journal(&self, journal: Journal) {
+        self.send(Job::AttachJournal(journal));
+    }
+
+    /// Starts storing into `db`, with `session_id` as the current session.
+    pub fn attach_database(&self, db: SharedDatabase, session_id: i64) {
+        self.send(Job::AttachDatabase(db, session_id));
+    }
+
+    pub fn on_journal_error(&self, handler: ErrorHandler) {
+        self.send(Job::OnJournalError(handler));
+    }
+
+    pub fn journal(&self, entry: JournalEntry) {
+        self.send(Job::Journal(entry));
+    }
+
+    /// Adds the session to the database and stores against it from now on.
+    pub fn new_session(&self, session: Session, created_at: Timestamp) {
+        self.send(Job::NewSession(session, created_at));
+    }
+
+    /// Runs a database write, if there is a database. History is best effort;
+    /// the journal is what recovery relies on, so failures are only logged.
+    pub fn store(&self, write: Store) {
+        self.send(Job::Store(write));
+    }
+
+    /// Hears back once everything queued so far has been written, or at once
+    /// if the recorder has stopped.
+    pub fn written(&self) -> mpsc::Receiver<()> {
+        let (tx, rx) = mpsc::channel();
+        self.send(Job::Written(tx));
+        rx
+    }
+}
+
+impl Drop for Recorder {
+    fn drop(&mut self) {
+        self.jobs = None;
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+#[derive(Default)]
+struct Writer {
+    journal: Option<Journal>,
+    /// Set after a failed write so the alert is raised once, not per passing.
+    journal_failed: bool,
+    on_journal_error: Option<ErrorHandler>,
+    db: Option<SharedDatabase>,
+    /// Database row of the current session.
+    session_id: Option<i64>,
+}
+
+impl Writer {
+    fn run(mut self, jobs: mpsc::Receiver<Job>) {
+        for job in jobs {
+            match job {
+                Job::AttachJournal(journal) => self.journal = Some(journal),
+                Job::AttachDatabase(db, id) => {
+                    self.db = Some(db);
+                    self.session_id = Some(id);
+                }
+                Job::OnJournalError(handler) => self.on_journal_error = Some(handler),
+                Job::Journal(entry) => self.write_journal(&entry),
+                Job::NewSession(session, created_at) => {
+                    if let Some(db) = &self.db {
+                        match db.session_id(&session, created_at) {
+                            Ok(id) => self.session_id = Some(id),
+                            Err(e) => eprintln!("Failed to add the session to the database: {}", e),
+                        }
+                    }
+                }
+                Job::Store(write) => {
+                    if let Some(db) = &self.db {
+                        if let Err(e) = write(db, self.session_id) {
+                            eprintln!("Failed to write to the database: {}", e);
+                        }
+                    }
+                }
+                Job::Written(done) => {
+                    let _ = done.send(());
+                }
+            }
+        }
+    }
+
+    fn write_journal(&mut self, entry: &JournalEntry) {
+        let Some(journal) = self.journal.as_mut() else { return };
+        match journal.append(entry) {
+            Ok(()) => self.journal_failed = false,
+            Err(e) => {
+                eprintln!("Failed to write journal {}: {}", journal.path().display(), e);
+                let message = format!("Passings are not being saved to {}: {}", journal.path().display(), e);
+                if !std::mem::replace(&mut self.journal_failed, true) {
+                    if let Some(handler) = &self.on_journal_error {
+                        handler(message);
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::laps::Lap;
+    use crate::messages::LapTime;
+    use crate::session::SessionSettings;
+    use std::sync::Arc;
+
+    fn lap(transponder: &str, timestamp: Timestamp) -> Lap {
+        Lap {
+            transponder: transponder.to_string(),
+            lap_number: 1,
+            lap_time: LapTime::seconds(30),
+            best_lap: LapTime::seconds(30),
+            is_best: true,
+            total_time: LapTime::seconds(30),
+            timestamp,
+            timing_point: None,
+        }
+    }
+
+    #[test]
+    fn test_writes_in_order_against_the_current_session() {
+        let db = Arc::new(Database::in_memory().unwrap());
+        let first = crate::messages::now();
+        let first_id = db.session_id(&Session::new(SessionSettings::default()), first).unwrap();
+
+        let recorder = Recorder::spawn();
+        recorder.attach_database(db.clone(), first_id);
+        let stored = lap("1", first);
+        recorder.store(Box::new(move |db, id| db.insert_lap(id.unwrap(), &stored)));
+        let second = first + LapTime::seconds(60);
+        recorder.new_session(Session::new(SessionSettings::default()), second);
+        let stored = lap("2", second);
+        recorder.store(Box::new(move |db, id| db.insert_lap(id.unwrap(), &stored)));
+        recorder.written().recv().unwrap();
+
+        let second_id = db.session_id(&Session::new(SessionSettings::default()), second).unwrap();
+        assert_ne!(second_id, first_id);
+        assert_eq!(db.all_laps(first_id).unwrap()[0].transponder, "1");
+        assert_eq!(db.all_laps(second_id).unwrap()[0].transponder, "2");
+
+        // Stopped recorders answer at once
+        drop(recorder);
+        let recorder = Recorder { jobs: None, thread: None };
+        assert!(recorder.written().recv().is_err());
+    }
+}
@@ -0,0 +1,127 @@
+use serde_json::Value;
+use std::path::PathBuf;
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, BufReader};
+
+use crate::messages::{Passing, Timestamp};
+use crate::state::PassingSender;
+use crate::status::{SourceState, StatusHandle};
+
+/// Plays a recording back as if it came from a decoder, keeping the gaps
+/// between passings (scaled by `speed`).
+pub struct Replay {
+    path: PathBuf,
+    speed: f64,
+}
+
+impl Replay {
+    pub fn new(path: PathBuf, speed: f64) -> Self {
+        Self { path, speed }
+    }
+
+    /// Source name used in status reports.
+    pub fn describe(&self) -> String {
+        format!("replay {}", self.path.display())
+    }
+
+    pub async fn run(self, passings: PassingSender, status: StatusHandle) {
+        let file = match tokio::fs::File::open(&self.path).await {
+            Ok(file) => file,
+            Err(e) => {
+                eprintln!("Failed to open replay file {}: {}", self.path.display(), e);
+                status.set_error(SourceState::Failed, format!("Failed to open {}: {}", self.path.display(), e));
+                return;
+            }
+        };
+        status.set_state(SourceState::Connected);
+
+        let mut lines = BufReader::new(file).lines();
+        let mut line_number = 0;
+        let mut count = 0;
+        let mut previous: Option<Timestamp> = None;
+        loop {
+            let line = match lines.next_line().await {
+                Ok(Some(line)) => line,
+                Ok(None) => break,
+                Err(e) => {
+                    eprintln!("Failed to read replay file: {}", e);
+                    status.set_error(SourceState::Failed, e);
+                    return;
+                }
+            };
+            line_number += 1;
+            if line.trim().is_empty() {
+                continue;
+            }
+
+            let passing = match parse_line(&line) {
+                Ok(Some(passing)) => passing,
+                Ok(None) => continue,
+                Err(e) => {
+                    eprintln!("Skipping line {} of {}: {}", line_number, self.path.display(), e);
+                    continue;
+                }
+            };
+
+            if let Some(previous) = previous {
+                if let Some(gap) = self.gap(previous, passing.timestamp) {
+                    tokio::time::sleep(gap).await;
+                }
+            }
+            previous = Some(passing.timestamp);
+
+            status.record_passing(&passing);
+            let _ = passings.send(passing);
+            count += 1;
+        }
+        println!("Replay of {} finished after {} passings", self.path.display(), count);
+    }
+
+    fn gap(&self, previous: Timestamp, next: Timestamp) -> Option<Duration> {
+        if self.speed == 0.0 {
+            return None;
+        }
+        let gap = (next - previous).to_std().ok()?;
+        // Never panics, even for a speed too slow to wait out
+        Some(Duration::try_from_secs_f64(gap.as_secs_f64() / self.speed).unwrap_or(Duration::MAX))
+    }
+}
+
+/// Accepts a bare `Passing` (as in the legacy feed) or a feed envelope;
+/// envelopes that aren't passings are skipped.
+fn parse_line(line: &str) -> Result<Option<Passing>, serde_json::Error> {
+    let mut value: Value = serde_json::from_str(line)?;
+    match value.get("type").and_then(Value::as_str) {
+        Some("passing") => serde_json::from_value(value["data"].take()).map(Some),
+        Some(_) => Ok(None),
+        None => serde_json::from_value(value).map(Some),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_line_formats() {
+        let passing = r#"{"passing_number":1,"transponder":"0000001","timestamp":"2024-01-12T09:06:35.944000+01:00","strength":0,"tran_code":"","noise":0,"hits":0,"timing_point":null}"#;
+        assert_eq!(parse_line(passing).unwrap().unwrap().transponder, "0000001");
+
+        let envelope = format!(r#"{{"type":"passing","v":1,"data":{}}}"#, passing);
+        assert_eq!(parse_line(&envelope).unwrap().unwrap().passing_number, 1);
+
+        assert!(parse_line(r#"{"type":"lagged","v":1,"data":{"skipped":3}}"#).unwrap().is_none());
+        assert!(parse_line("not json").is_err());
+    }
+
+    #[test]
+    fn test_gap_scales_with_speed() {
+        let start = chrono::DateTime::parse_from_rfc3339("2024-01-12T09:00:00+01:00").unwrap();
+        let next = start + chrono::TimeDelta::seconds(30);
+        let gap = |speed| Replay::new(PathBuf::from("race.jsonl"), speed).gap(start, next);
+        assert_eq!(gap(2.0), Some(Duration::from_secs(15)));
+        assert_eq!(gap(0.0), None);
+        assert_eq!(gap(1e-300), Some(Duration::MAX));
+        assert_eq!(Replay::new(PathBuf::from("race.jsonl"), 1.0).gap(next, start), None);
+    }
+}
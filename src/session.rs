@@ -0,0 +1,376 @@
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeSet;
+
+use crate::config::{FieldError, LapRules};
+use crate::laps::{Lap, IMPULSE_MARKER};
+use crate::messages::{timestamp, Passing, Timestamp};
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SessionKind {
+    #[default]
+    Practice,
+    Qualifying,
+    Race,
+}
+
+/// What moves a session from waiting to running.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum StartTrigger {
+    /// The impulse marker transponder crossing the line.
+    Marker,
+    /// Only a `start_session` command or `POST /api/session/start`.
+    Api,
+    /// The first competitor crossing the line.
+    #[default]
+    FirstCrossing,
+}
+
+/// When the chequered flag comes out: `{"time": 600}` (seconds after the
+/// start) or `{"laps": 20}` (when the leader completes them).
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Limit {
+    Time(f64),
+    Laps(u32),
+}
+
+/// How the standings are ordered: `"laps"`, `"best_lap"` or
+/// `{"best_consecutive": 3}`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Ranking {
+    /// Most laps, then lowest cumulative time.
+    #[default]
+    Laps,
+    /// Fastest single valid lap.
+    BestLap,
+    /// Fastest run of this many laps in a row.
+    BestConsecutive(u32),
+}
+
+/// How a session is run; sent with `new_session` and `POST /api/session`.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SessionSettings {
+    pub name: String,
+    pub kind: SessionKind,
+    pub start: StartTrigger,
+    /// No limit means the session runs until it is flagged or finished by hand.
+    pub limit: Option<Limit>,
+    /// Defaults to `laps` for races and `best_lap` otherwise.
+    pub ranking: Option<Ranking>,
+    /// Replaces the configured lap rules for this session only.
+    pub rules: Option<LapRules>,
+}
+
+impl SessionSettings {
+    pub fn ranking(&self) -> Ranking {
+        self.ranking.unwrap_or(match self.kind {
+            SessionKind::Race => Ranking::Laps,
+            SessionKind::Practice | SessionKind::Qualifying => Ranking::BestLap,
+        })
+    }
+
+    pub fn validate(&self) -> Result<(), FieldError> {
+        match self.limit {
+            Some(Limit::Time(secs)) if !secs.is_finite() || secs <= 0.0 => {
+                return Err(FieldError::new("limit", "time limit must be more than 0 seconds"))
+            }
+            Some(Limit::Laps(0)) => return Err(FieldError::new("limit", "lap limit must be at least 1")),
+            _ => {}
+        }
+        if self.ranking == Some(Ranking::BestConsecutive(0)) {
+            return Err(FieldError::new("ranking", "must count at least 1 lap"));
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SessionPhase {
+    /// Waiting for the start trigger; crossings don't count yet.
+    Waiting,
+    Running,
+    /// Chequered flag is out; each competitor finishes on their next crossing.
+    Chequered,
+    Finished,
+}
+
+impl SessionPhase {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            SessionPhase::Waiting => "waiting",
+            SessionPhase::Running => "running",
+            SessionPhase::Chequered => "chequered",
+            SessionPhase::Finished => "finished",
+        }
+    }
+}
+
+/// A session and where it stands, broadcast as `WsMessage::Session` whenever
+/// it changes.
+#[derive(Clone, Debug, Serialize)]
+pub struct Session {
+    #[serde(flatten)]
+    pub settings: SessionSettings,
+    pub phase: SessionPhase,
+    #[serde(with = "timestamp::option")]
+    pub started_at: Option<Timestamp>,
+    #[serde(with = "timestamp::option")]
+    pub chequered_at: Option<Timestamp>,
+    #[serde(with = "timestamp::option")]
+    pub finished_at: Option<Timestamp>,
+    /// Competitors that have taken the flag, in finishing order.
+    pub finished: Vec<String>,
+    /// Everyone who crossed the line while the session was running.
+    #[serde(skip)]
+    competitors: BTreeSet<String>,
+}
+
+impl Session {
+    pub fn new(settings: SessionSettings) -> Self {
+        Self {
+            settings,
+            phase: SessionPhase::Waiting,
+            started_at: None,
+            chequered_at: None,
+            finished_at: None,
+            finished: Vec::new(),
+            competitors: BTreeSet::new(),
+        }
+    }
+
+    /// Whether a crossing by `transponder` should go to the lap engine. After
+    /// the flag only those already racing count, until they finish; someone
+    /// first seen then would otherwise keep the session from finishing.
+    pub fn counts(&self, transponder: &str) -> bool {
+        match self.phase {
+            SessionPhase::Running => true,
+            SessionPhase::Chequered => {
+                self.competitors.contains(transponder) && !self.finished.iter().any(|t| t == transponder)
+            }
+            SessionPhase::Waiting | SessionPhase::Finished => false,
+        }
+    }
+
+    /// Looks at every passing before it is counted; starts the session when
+    /// it is the configured trigger. Returns whether the session changed.
+    pub fn observe(&mut self, passing: &Passing) -> bool {
+        if self.phase != SessionPhase::Waiting {
+            return false;
+        }
+        let is_marker = passing.transponder == IMPULSE_MARKER;
+        match self.settings.start {
+            StartTrigger::Marker if is_marker => self.start(passing.timestamp),
+            StartTrigger::FirstCrossing if !is_marker => self.start(passing.timestamp),
+            _ => false,
+        }
+    }
+
+    /// Notes a counted crossing that didn't complete a lap.
+    pub fn record_crossing(&mut self, transponder: &str) {
+        if transponder != IMPULSE_MARKER {
+            self.competitors.insert(transponder.to_string());
+        }
+    }
+
+    /// Updates the session for a completed lap: the leader reaching the lap
+    /// limit brings out the flag, and once it is out the lap finishes the
+    /// competitor. Returns whether the session changed.
+    pub fn record_lap(&mut self, lap: &Lap) -> bool {
+        self.record_crossing(&lap.transponder);
+        match self.phase {
+            SessionPhase::Running => match self.settings.limit {
+                Some(Limit::Laps(laps)) if lap.lap_number >= laps => {
+                    self.chequered(lap.timestamp);
+                    self.finish_competitor(&lap.transponder, lap.timestamp);
+                    true
+                }
+                _ => false,
+            },
+            SessionPhase::Chequered => {
+                self.finish_competitor(&lap.transponder, lap.timestamp);
+                true
+            }
+            SessionPhase::Waiting | SessionPhase::Finished => false,
+        }
+    }
+
+    /// Brings out the flag once the time limit has run out at `now`.
+    /// Returns whether the session changed.
+    pub fn tick(&mut self, now: Timestamp) -> bool {
+        match (self.phase, self.settings.limit, self.started_at) {
+            (SessionPhase::Running, Some(Limit::Time(secs)), Some(started)) => {
+                let end = started + crate::laps::duration(secs);
+                now >= end && self.chequered(end)
+            }
+            _ => false,
+        }
+    }
+
+    pub fn start(&mut self, at: Timestamp) -> bool {
+        if self.phase != SessionPhase::Waiting {
+            return false;
+        }
+        self.phase = SessionPhase::Running;
+        self.started_at = Some(at);
+        true
+    }
+
+    /// Shows the chequered flag. A session nobody has crossed in yet is
+    /// finished straight away.
+    pub fn chequered(&mut self, at: Timestamp) -> bool {
+        if self.phase != SessionPhase::Running {
+            return false;
+        }
+        self.phase = SessionPhase::Chequered;
+        self.chequered_at = Some(at);
+        if self.competitors.is_empty() {
+            self.finish(at);
+        }
+        true
+    }
+
+    /// Ends the session without waiting for the remaining competitors.
+    pub fn finish(&mut self, at: Timestamp) -> bool {
+        if self.phase == SessionPhase::Finished {
+            return false;
+        }
+        self.phase = SessionPhase::Finished;
+        self.finished_at = Some(at);
+        true
+    }
+
+    fn finish_competitor(&mut self, transponder: &str, at: Timestamp) {
+        if !self.finished.iter().any(|t| t == transponder) {
+            self.finished.push(transponder.to_string());
+        }
+        if self.competitors.iter().all(|t| self.finished.contains(t)) {
+            self.finish(at);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::messages::LapTime;
+    use crate::testing::passing;
+
+    fn at(secs: i64) -> Timestamp {
+        chrono::DateTime::parse_from_rfc3339("2024-01-12T09:00:00+01:00").unwrap() + LapTime::seconds(secs)
+    }
+
+    fn lap(transponder: &str, lap_number: u32, secs: i64) -> Lap {
+        Lap {
+            transponder: transponder.to_string(),
+            lap_number,
+            lap_time: LapTime::seconds(30),
+            best_lap: LapTime::seconds(30),
+            is_best: false,
+            total_time: LapTime::seconds(30 * lap_number as i64),
+            timestamp: at(secs),
+            timing_point: None,
+        }
+    }
+
+    #[test]
+    fn test_start_triggers() {
+        let mut session = Session::new(SessionSettings { start: StartTrigger::Marker, ..Default::default() });
+        assert!(!session.observe(&passing("0000001", 0)));
+        assert!(!session.counts("0000001"));
+        assert!(session.observe(&passing(IMPULSE_MARKER, 5_000)));
+        assert_eq!(session.started_at, Some(at(5)));
+        assert!(session.counts("0000001"));
+
+        let mut session = Session::new(SessionSettings::default());
+        assert!(!session.observe(&passing(IMPULSE_MARKER, 0)));
+        assert!(session.observe(&passing("0000001", 3_000)));
+        assert_eq!(session.phase, SessionPhase::Running);
+
+        let mut session = Session::new(SessionSettings { start: StartTrigger::Api, ..Default::default() });
+        assert!(!session.observe(&passing("0000001", 0)));
+        assert!(session.start(at(1)));
+        assert!(!session.start(at(2)));
+    }
+
+    #[test]
+    fn test_lap_limit_finishes_on_next_crossing() {
+        let settings = SessionSettings { kind: SessionKind::Race, limit: Some(Limit::Laps(2)), ..Default::default() };
+        let mut session = Session::new(settings);
+        session.observe(&passing("0000001", 0));
+        session.record_crossing("0000001");
+        session.record_crossing("0000002");
+
+        assert!(!session.record_lap(&lap("0000001", 1, 30)));
+        assert!(!session.record_lap(&lap("0000002", 1, 31)));
+        assert!(session.record_lap(&lap("0000001", 2, 60)));
+        assert_eq!(session.phase, SessionPhase::Chequered);
+        assert_eq!(session.chequered_at, Some(at(60)));
+        assert!(!session.counts("0000001"));
+        assert!(session.counts("0000002"));
+
+        assert!(session.record_lap(&lap("0000002", 2, 62)));
+        assert_eq!(session.phase, SessionPhase::Finished);
+        assert_eq!(session.finished, ["0000001", "0000002"]);
+        assert_eq!(session.finished_at, Some(at(62)));
+    }
+
+    #[test]
+    fn test_newcomer_after_the_flag_is_ignored() {
+        let settings = SessionSettings { kind: SessionKind::Race, limit: Some(Limit::Laps(1)), ..Default::default() };
+        let mut session = Session::new(settings);
+        session.observe(&passing("0000001", 0));
+        session.record_crossing("0000001");
+        session.record_crossing("0000002");
+        assert!(session.record_lap(&lap("0000001", 1, 30)));
+        assert_eq!(session.phase, SessionPhase::Chequered);
+
+        // Turns up after the flag, e.g. a marshal's car or a late entry
+        assert!(!session.counts("0000003"));
+        assert!(session.record_lap(&lap("0000002", 1, 32)));
+        assert_eq!(session.phase, SessionPhase::Finished);
+        assert_eq!(session.finished, ["0000001", "0000002"]);
+    }
+
+    #[test]
+    fn test_time_limit_and_manual_flag() {
+        let mut session = Session::new(SessionSettings { limit: Some(Limit::Time(600.0)), ..Default::default() });
+        session.observe(&passing("0000001", 0));
+        session.record_crossing("0000001");
+        assert!(!session.tick(at(599)));
+        assert!(session.tick(at(603)));
+        assert_eq!(session.chequered_at, Some(at(600)));
+        assert!(!session.tick(at(604)));
+
+        let mut session = Session::new(SessionSettings::default());
+        assert!(!session.chequered(at(0)));
+        session.observe(&passing("0000001", 0));
+        session.record_crossing("0000001");
+        assert!(!session.tick(at(10_000)));
+        assert!(session.chequered(at(100)));
+        assert!(session.finish(at(110)));
+        assert!(!session.counts("0000002"));
+    }
+
+    #[test]
+    fn test_settings_json() {
+        let settings: SessionSettings = serde_json::from_str(r#"{"kind": "race", "start": "marker", "limit": {"laps": 10}}"#).unwrap();
+        assert_eq!(settings.limit, Some(Limit::Laps(10)));
+        assert_eq!(settings.start, StartTrigger::Marker);
+        assert!(SessionSettings { limit: Some(Limit::Time(0.0)), ..Default::default() }.validate().is_err());
+
+        let qualifying: SessionSettings = serde_json::from_str(r#"{"kind": "qualifying", "ranking": {"best_consecutive": 3}}"#).unwrap();
+        assert_eq!(qualifying.ranking(), Ranking::BestConsecutive(3));
+        let error = SessionSettings { ranking: Some(Ranking::BestConsecutive(0)), ..Default::default() }.validate().unwrap_err();
+        assert_eq!(error.field, "ranking");
+
+        let json = serde_json::to_value(Session::new(settings)).unwrap();
+        assert_eq!(json["kind"], "race");
+        assert_eq!(json["phase"], "waiting");
+        assert_eq!(json["started_at"], serde_json::Value::Null);
+    }
+}
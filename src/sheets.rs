@@ -0,0 +1,680 @@
+use pdf_writer::{Content, Finish, Name, Pdf, Rect, Ref, Str, TextStr};
+use std::error::Error;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use tokio::sync::broadcast;
+
+use crate::db::SharedDatabase;
+use crate::export::{self, lap_range, SessionResults};
+use crate::messages::{seconds, LapTime, Timestamp, WsMessage};
+use crate::session::{Limit, Ranking, SessionKind, SessionPhase};
+use crate::standings::Standing;
+use crate::state::SharedState;
+
+/// Lines in the lap chart, by finishing position.
+const PALETTE: [(u8, u8, u8); 10] = [
+    (31, 119, 180),
+    (255, 127, 14),
+    (44, 160, 44),
+    (214, 39, 40),
+    (148, 103, 189),
+    (140, 86, 75),
+    (227, 119, 194),
+    (127, 127, 127),
+    (188, 189, 34),
+    (23, 190, 207),
+];
+
+/// A results sheet laid out once, then rendered as HTML or PDF.
+struct Sheet {
+    title: String,
+    details: Vec<(&'static str, String)>,
+    headers: [String; 7],
+    rows: Vec<[String; 7]>,
+    chart: Option<Chart>,
+}
+
+impl Sheet {
+    fn new(results: &SessionResults) -> Self {
+        let session = &results.record.session;
+        let settings = &session.settings;
+        let title = match settings.name.trim() {
+            "" => format!("Session {}", results.record.id),
+            name => name.to_string(),
+        };
+
+        let ranking = settings.ranking();
+        let mut details = vec![
+            ("Session", kind_name(settings.kind).to_string()),
+            ("Ranking", ranking_name(ranking)),
+        ];
+        match settings.limit {
+            Some(Limit::Laps(laps)) => details.push(("Limit", format!("{} laps", laps))),
+            Some(Limit::Time(secs)) => details.push(("Limit", format_time(&seconds::from_secs(secs)))),
+            None => {}
+        }
+        if let Some(started) = &session.started_at {
+            details.push(("Started", format_clock(started)));
+        }
+        if let Some(finished) = &session.finished_at {
+            details.push(("Finished", format_clock(finished)));
+        }
+        details.push(("Competitors", results.standings.entries.len().to_string()));
+        details.push(("Laps", results.laps.len().to_string()));
+
+        // The ranked time and what it was made of, followed by the gap it opened
+        let (ranked, counted) = match ranking {
+            Ranking::Laps => ("Time".to_string(), "Best lap"),
+            Ranking::BestLap => ("Best lap".to_string(), "Lap"),
+            Ranking::BestConsecutive(n) => (format!("Best {} laps", n), "Laps"),
+        };
+        let headers = ["Pos", "No.", "Name", "Laps", &ranked, counted, "Gap"].map(str::to_string);
+        let rows = results
+            .standings
+            .entries
+            .iter()
+            .map(|s| {
+                let (time, detail) = match ranking {
+                    Ranking::Laps => (s.race_time, s.best_lap.as_ref().map(format_time).unwrap_or_default()),
+                    _ => (s.ranked_time, lap_range(&s.counted_laps)),
+                };
+                [
+                    s.position.to_string(),
+                    s.transponder.clone(),
+                    results.name(&s.transponder).to_string(),
+                    s.laps.to_string(),
+                    time.as_ref().map(format_time).unwrap_or_default(),
+                    detail,
+                    gap(s),
+                ]
+            })
+            .collect();
+
+        Sheet { title, details, headers, rows, chart: Chart::new(results) }
+    }
+}
+
+/// Lap times per competitor against lap number.
+struct Chart {
+    laps: u32,
+    /// Time axis range in seconds; slower laps are drawn at the top.
+    min: f64,
+    max: f64,
+    step: f64,
+    series: Vec<Series>,
+}
+
+struct Series {
+    label: String,
+    color: (u8, u8, u8),
+    /// Lap number and lap time in seconds.
+    points: Vec<(u32, f64)>,
+}
+
+impl Chart {
+    fn new(results: &SessionResults) -> Option<Chart> {
+        let series: Vec<Series> = results
+            .laps_by_competitor()
+            .into_iter()
+            .enumerate()
+            .filter(|(_, (_, laps))| !laps.is_empty())
+            .map(|(i, (standing, laps))| Series {
+                label: match results.name(&standing.transponder) {
+                    "" => format!("P{} {}", standing.position, standing.transponder),
+                    name => format!("P{} {}", standing.position, name),
+                },
+                color: PALETTE[i % PALETTE.len()],
+                points: laps.iter().map(|lap| (lap.lap_number, seconds::to_secs(&lap.lap_time))).collect(),
+            })
+            .collect();
+
+        let times = || series.iter().flat_map(|s| s.points.iter().map(|p| p.1));
+        let best = times().fold(f64::INFINITY, f64::min);
+        if !best.is_finite() {
+            return None;
+        }
+        // Keep a crash or a pit stop from squashing every other lap
+        let slowest = times().fold(best, f64::max).min(best * 2.0);
+        let step = [0.1, 0.2, 0.5, 1.0, 2.0, 5.0, 10.0, 15.0, 30.0, 60.0, 120.0, 300.0]
+            .into_iter()
+            .find(|step| (slowest - best) / step <= 6.0)
+            .unwrap_or(600.0);
+        let min = (best / step).floor() * step;
+        let max = ((slowest / step).ceil() * step).max(min + step);
+        let laps = series.iter().flat_map(|s| s.points.iter().map(|p| p.0)).max().unwrap_or(1);
+        Some(Chart { laps, min, max, step, series })
+    }
+
+    /// Horizontal position of a lap, from 0 to 1.
+    fn x(&self, lap: u32) -> f64 {
+        if self.laps <= 1 {
+            0.5
+        } else {
+            (lap - 1) as f64 / (self.laps - 1) as f64
+        }
+    }
+
+    /// Height of a lap time, from 0 at the bottom to 1 at the top.
+    fn y(&self, secs: f64) -> f64 {
+        ((secs - self.min) / (self.max - self.min)).clamp(0.0, 1.0)
+    }
+
+    fn time_ticks(&self) -> Vec<f64> {
+        let count = ((self.max - self.min) / self.step).round() as u32;
+        (0..=count).map(|i| self.min + i as f64 * self.step).collect()
+    }
+
+    /// `25`, `1:05` or `25.5`, as precise as the step needs.
+    fn tick_label(&self, secs: f64) -> String {
+        let decimals = if self.step < 1.0 { 1 } else { 0 };
+        if secs >= 60.0 {
+            let width = if decimals > 0 { 4 } else { 2 };
+            format!("{}:{:0width$.decimals$}", (secs / 60.0).floor(), secs % 60.0)
+        } else {
+            format!("{:.decimals$}", secs)
+        }
+    }
+
+    fn lap_ticks(&self) -> Vec<u32> {
+        let every = [1, 2, 5, 10, 20, 50, 100].into_iter().find(|n| self.laps / n <= 20).unwrap_or(200);
+        (1..=self.laps).filter(|lap| *lap == 1 || lap % every == 0).collect()
+    }
+}
+
+fn kind_name(kind: SessionKind) -> &'static str {
+    match kind {
+        SessionKind::Practice => "Practice",
+        SessionKind::Qualifying => "Qualifying",
+        SessionKind::Race => "Race",
+    }
+}
+
+fn ranking_name(ranking: Ranking) -> String {
+    match ranking {
+        Ranking::Laps => "Most laps".to_string(),
+        Ranking::BestLap => "Best lap".to_string(),
+        Ranking::BestConsecutive(n) => format!("Best {} consecutive laps", n),
+    }
+}
+
+/// `23.456`, or `1:23.456` from a minute up.
+fn format_time(time: &LapTime) -> String {
+    let millis = (seconds::to_secs(time) * 1000.0).round() as i64;
+    let (minutes, millis) = (millis / 60_000, millis % 60_000);
+    if minutes > 0 {
+        format!("{}:{:02}.{:03}", minutes, millis / 1000, millis % 1000)
+    } else {
+        format!("{}.{:03}", millis / 1000, millis % 1000)
+    }
+}
+
+fn format_clock(ts: &Timestamp) -> String {
+    ts.format("%Y-%m-%d %H:%M:%S").to_string()
+}
+
+/// `+1.234` behind, or `+2 laps` when lapped.
+fn gap(standing: &Standing) -> String {
+    match (standing.laps_down, &standing.gap) {
+        (0, Some(gap)) => format!("+{}", format_time(gap)),
+        (0, None) => String::new(),
+        (1, _) => "+1 lap".to_string(),
+        (n, _) => format!("+{} laps", n),
+    }
+}
+
+/// A single HTML page with everything inline, to print or post anywhere.
+pub fn html(results: &SessionResults, out: &mut dyn Write) -> Result<(), Box<dyn Error>> {
+    let sheet = Sheet::new(results);
+    let mut page = String::new();
+    page.push_str("<!DOCTYPE html>\n<html lang=\"en\">\n<head>\n<meta charset=\"utf-8\">\n");
+    page.push_str(&format!("<title>{}</title>\n", escape(&sheet.title)));
+    page.push_str(
+        "<style>\n\
+         body { font-family: Helvetica, Arial, sans-serif; margin: 2em auto; max-width: 60em; color: #222; }\n\
+         dl { display: grid; grid-template-columns: max-content auto; gap: 0.2em 1em; }\n\
+         dt { font-weight: bold; }\n\
+         dd { margin: 0; }\n\
+         table { border-collapse: collapse; width: 100%; margin: 1.5em 0; }\n\
+         th, td { padding: 0.3em 0.6em; border-bottom: 1px solid #ccc; text-align: left; }\n\
+         th.num, td.num { text-align: right; font-variant-numeric: tabular-nums; }\n\
+         svg { width: 100%; height: auto; }\n\
+         svg text { font-size: 12px; fill: #444; }\n\
+         .legend { display: flex; flex-wrap: wrap; gap: 0.3em 1.2em; padding: 0; list-style: none; }\n\
+         .legend span { display: inline-block; width: 1.5em; height: 0.3em; margin-right: 0.4em; vertical-align: middle; }\n\
+         </style>\n</head>\n<body>\n",
+    );
+    page.push_str(&format!("<h1>{}</h1>\n<dl>\n", escape(&sheet.title)));
+    for (label, value) in &sheet.details {
+        page.push_str(&format!("<dt>{}</dt><dd>{}</dd>\n", label, escape(value)));
+    }
+    page.push_str("</dl>\n<table>\n<tr>");
+    for (i, header) in sheet.headers.iter().enumerate() {
+        page.push_str(&format!("<th{}>{}</th>", num_class(i), escape(header)));
+    }
+    page.push_str("</tr>\n");
+    for row in &sheet.rows {
+        page.push_str("<tr>");
+        for (i, cell) in row.iter().enumerate() {
+            page.push_str(&format!("<td{}>{}</td>", num_class(i), escape(cell)));
+        }
+        page.push_str("</tr>\n");
+    }
+    page.push_str("</table>\n");
+    if let Some(chart) = &sheet.chart {
+        page.push_str("<h2>Lap times</h2>\n");
+        page.push_str(&svg(chart));
+    }
+    page.push_str("</body>\n</html>\n");
+    out.write_all(page.as_bytes())?;
+    Ok(())
+}
+
+/// Laps, times, details and gap line up on the right.
+fn num_class(column: usize) -> &'static str {
+    if column >= 3 {
+        " class=\"num\""
+    } else {
+        ""
+    }
+}
+
+fn svg(chart: &Chart) -> String {
+    let (width, height) = (800.0, 360.0);
+    let (left, right, top, bottom) = (60.0, 790.0, 10.0, 330.0);
+    let x = |lap| left + chart.x(lap) * (right - left);
+    let y = |secs| bottom - chart.y(secs) * (bottom - top);
+
+    let mut svg = format!("<svg viewBox=\"0 0 {} {}\" xmlns=\"http://www.w3.org/2000/svg\">\n", width, height);
+    for tick in chart.time_ticks() {
+        svg.push_str(&format!(
+            "<line x1=\"{left}\" y1=\"{y:.1}\" x2=\"{right}\" y2=\"{y:.1}\" stroke=\"#ddd\"/>\
+             <text x=\"{}\" y=\"{:.1}\" text-anchor=\"end\">{}</text>\n",
+            left - 6.0,
+            y(tick) + 4.0,
+            chart.tick_label(tick),
+            y = y(tick)
+        ));
+    }
+    for lap in chart.lap_ticks() {
+        svg.push_str(&format!("<text x=\"{:.1}\" y=\"{}\" text-anchor=\"middle\">{}</text>\n", x(lap), bottom + 18.0, lap));
+    }
+    svg.push_str(&format!("<line x1=\"{left}\" y1=\"{bottom}\" x2=\"{right}\" y2=\"{bottom}\" stroke=\"#888\"/>\n"));
+    for series in &chart.series {
+        let points: Vec<String> = series.points.iter().map(|(lap, secs)| format!("{:.1},{:.1}", x(*lap), y(*secs))).collect();
+        let (r, g, b) = series.color;
+        svg.push_str(&format!(
+            "<polyline points=\"{}\" fill=\"none\" stroke=\"rgb({},{},{})\" stroke-width=\"2\"><title>{}</title></polyline>\n",
+            points.join(" "),
+            r,
+            g,
+            b,
+            escape(&series.label)
+        ));
+    }
+    svg.push_str("</svg>\n<ul class=\"legend\">\n");
+    for series in &chart.series {
+        let (r, g, b) = series.color;
+        svg.push_str(&format!("<li><span style=\"background: rgb({},{},{})\"></span>{}</li>\n", r, g, b, escape(&series.label)));
+    }
+    svg.push_str("</ul>\n");
+    svg
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;")
+}
+
+// A4 in points
+const PAGE_WIDTH: f32 = 595.0;
+const PAGE_HEIGHT: f32 = 842.0;
+const MARGIN: f32 = 40.0;
+const ROW_HEIGHT: f32 = 16.0;
+const CHART_HEIGHT: f32 = 260.0;
+/// Left edge of the text columns, right edge of the numeric ones.
+const COLUMN_X: [f32; 7] = [MARGIN, 72.0, 150.0, 345.0, 420.0, 490.0, PAGE_WIDTH - MARGIN];
+const REGULAR: Name = Name(b"F1");
+const BOLD: Name = Name(b"F2");
+
+/// Pages being filled top to bottom.
+struct Pages {
+    pages: Vec<Content>,
+    y: f32,
+}
+
+impl Pages {
+    fn new() -> Self {
+        Pages { pages: vec![Content::new()], y: PAGE_HEIGHT - MARGIN }
+    }
+
+    fn content(&mut self) -> &mut Content {
+        self.pages.last_mut().unwrap()
+    }
+
+    fn new_page(&mut self) {
+        self.pages.push(Content::new());
+        self.y = PAGE_HEIGHT - MARGIN;
+    }
+
+    /// Moves down by `height`, starting a new page if it doesn't fit.
+    fn advance(&mut self, height: f32) -> f32 {
+        if self.y - height < MARGIN {
+            self.new_page();
+        }
+        self.y -= height;
+        self.y
+    }
+
+    fn text(&mut self, font: Name, size: f32, x: f32, y: f32, text: &str) {
+        if text.is_empty() {
+            return;
+        }
+        self.content().begin_text().set_font(font, size).next_line(x, y).show(Str(&win_ansi(text))).end_text();
+    }
+
+    fn text_right(&mut self, font: Name, size: f32, right: f32, y: f32, text: &str) {
+        self.text(font, size, right - text_width(text, size), y, text);
+    }
+
+    fn line(&mut self, from: (f32, f32), to: (f32, f32), gray: f32) {
+        self.content().set_stroke_gray(gray).move_to(from.0, from.1).line_to(to.0, to.1).stroke();
+    }
+}
+
+/// A printable results sheet using the PDF base fonts, so nothing is embedded.
+pub fn pdf(results: &SessionResults, out: &mut dyn Write) -> Result<(), Box<dyn Error>> {
+    let sheet = Sheet::new(results);
+    let mut pages = Pages::new();
+
+    let y = pages.advance(20.0);
+    pages.text(BOLD, 18.0, MARGIN, y, &sheet.title);
+    pages.advance(8.0);
+    for pair in sheet.details.chunks(2) {
+        let y = pages.advance(14.0);
+        for (i, (label, value)) in pair.iter().enumerate() {
+            let x = MARGIN + i as f32 * 260.0;
+            pages.text(BOLD, 10.0, x, y, label);
+            pages.text(REGULAR, 10.0, x + 75.0, y, value);
+        }
+    }
+    pages.advance(12.0);
+
+    let row = |pages: &mut Pages, font: Name, cells: &[String; 7]| {
+        let y = pages.advance(ROW_HEIGHT);
+        for (i, cell) in cells.iter().enumerate() {
+            if i >= 3 {
+                pages.text_right(font, 10.0, COLUMN_X[i], y, cell);
+            } else {
+                let width = if i == 2 { 34 } else { 14 };
+                pages.text(font, 10.0, COLUMN_X[i], y, &truncate(cell, width));
+            }
+        }
+        pages.line((MARGIN, y - 5.0), (PAGE_WIDTH - MARGIN, y - 5.0), 0.8);
+    };
+    row(&mut pages, BOLD, &sheet.headers);
+    for cells in &sheet.rows {
+        if pages.y - ROW_HEIGHT < MARGIN {
+            // Repeat the headers on the next page
+            pages.new_page();
+            row(&mut pages, BOLD, &sheet.headers);
+        }
+        row(&mut pages, REGULAR, cells);
+    }
+
+    if let Some(chart) = &sheet.chart {
+        let legend_rows = chart.series.len().div_ceil(3) as f32;
+        pages.advance(24.0);
+        if pages.y - CHART_HEIGHT - 60.0 - legend_rows * 14.0 < MARGIN {
+            pages.new_page();
+        }
+        let y = pages.advance(16.0);
+        pages.text(BOLD, 12.0, MARGIN, y, "Lap times");
+        let top = pages.advance(10.0);
+        let bottom = pages.advance(CHART_HEIGHT);
+        pdf_chart(&mut pages, chart, top, bottom);
+        pages.advance(20.0);
+        for line in chart.series.chunks(3) {
+            let y = pages.advance(14.0);
+            for (i, series) in line.iter().enumerate() {
+                let x = MARGIN + i as f32 * 175.0;
+                let (r, g, b) = rgb(series.color);
+                pages.content().set_stroke_rgb(r, g, b).set_line_width(2.0).move_to(x, y + 3.0).line_to(x + 18.0, y + 3.0).stroke();
+                pages.content().set_line_width(1.0);
+                pages.text(REGULAR, 9.0, x + 24.0, y, &truncate(&series.label, 30));
+            }
+        }
+    }
+
+    out.write_all(&finish_pdf(&sheet.title, pages.pages))?;
+    Ok(())
+}
+
+fn pdf_chart(pages: &mut Pages, chart: &Chart, top: f32, bottom: f32) {
+    let (left, right) = (MARGIN + 40.0, PAGE_WIDTH - MARGIN);
+    let x = |lap| left + chart.x(lap) as f32 * (right - left);
+    let y = |secs| bottom + chart.y(secs) as f32 * (top - bottom);
+
+    for tick in chart.time_ticks() {
+        pages.line((left, y(tick)), (right, y(tick)), 0.85);
+        pages.text_right(REGULAR, 8.0, left - 5.0, y(tick) - 3.0, &chart.tick_label(tick));
+    }
+    for lap in chart.lap_ticks() {
+        let label = lap.to_string();
+        pages.text(REGULAR, 8.0, x(lap) - text_width(&label, 8.0) / 2.0, bottom - 12.0, &label);
+    }
+    pages.line((left, bottom), (right, bottom), 0.5);
+
+    for series in &chart.series {
+        let (r, g, b) = rgb(series.color);
+        let content = pages.content();
+        content.set_stroke_rgb(r, g, b).set_line_width(1.5);
+        for (i, (lap, secs)) in series.points.iter().enumerate() {
+            if i == 0 {
+                content.move_to(x(*lap), y(*secs));
+            } else {
+                content.line_to(x(*lap), y(*secs));
+            }
+        }
+        // A single lap still shows as a dot
+        if series.points.len() == 1 {
+            let (lap, secs) = series.points[0];
+            content.line_to(x(lap) + 0.5, y(secs));
+        }
+        content.stroke().set_line_width(1.0);
+    }
+}
+
+fn rgb((r, g, b): (u8, u8, u8)) -> (f32, f32, f32) {
+    (r as f32 / 255.0, g as f32 / 255.0, b as f32 / 255.0)
+}
+
+/// Writes the page contents out as a document.
+fn finish_pdf(title: &str, contents: Vec<Content>) -> Vec<u8> {
+    let catalog_id = Ref::new(1);
+    let page_tree_id = Ref::new(2);
+    let regular_id = Ref::new(3);
+    let bold_id = Ref::new(4);
+    let info_id = Ref::new(5);
+    let page_ids: Vec<Ref> = (0..contents.len() as i32).map(|i| Ref::new(6 + 2 * i)).collect();
+
+    let mut pdf = Pdf::new();
+    pdf.catalog(catalog_id).pages(page_tree_id);
+    pdf.pages(page_tree_id).kids(page_ids.iter().copied()).count(contents.len() as i32);
+    pdf.document_info(info_id).title(TextStr(title)).creator(TextStr("rrclivelaps"));
+    pdf.type1_font(regular_id).base_font(Name(b"Helvetica")).encoding_predefined(Name(b"WinAnsiEncoding"));
+    pdf.type1_font(bold_id).base_font(Name(b"Helvetica-Bold")).encoding_predefined(Name(b"WinAnsiEncoding"));
+
+    for (page_id, content) in page_ids.into_iter().zip(contents) {
+        let content_id = Ref::new(page_id.get() + 1);
+        let mut page = pdf.page(page_id);
+        page.media_box(Rect::new(0.0, 0.0, PAGE_WIDTH, PAGE_HEIGHT));
+        page.parent(page_tree_id);
+        page.contents(content_id);
+        page.resources().fonts().pair(REGULAR, regular_id).pair(BOLD, bold_id);
+        page.finish();
+        pdf.stream(content_id, &content.finish());
+    }
+    pdf.finish()
+}
+
+/// Latin-1 covers the printable part of WinAnsi; anything else becomes `?`.
+fn win_ansi(text: &str) -> Vec<u8> {
+    text.chars()
+        .map(|c| match c as u32 {
+            0x20..=0x7e | 0xa0..=0xff => c as u8,
+            _ => b'?',
+        })
+        .collect()
+}
+
+/// Width of `text` in Helvetica, close enough to right-align numbers.
+fn text_width(text: &str, size: f32) -> f32 {
+    let units: u32 = text
+        .chars()
+        .map(|c| match c {
+            '0'..='9' => 556,
+            '.' | ',' | ':' | ' ' => 278,
+            '+' => 584,
+            '-' | '(' | ')' => 333,
+            'i' | 'l' => 222,
+            'a'..='z' => 500,
+            _ => 667,
+        })
+        .sum();
+    units as f32 * size / 1000.0
+}
+
+fn truncate(text: &str, chars: usize) -> String {
+    if text.chars().count() <= chars {
+        text.to_string()
+    } else {
+        let mut short: String = text.chars().take(chars.saturating_sub(3)).collect();
+        short.push_str("...");
+        short
+    }
+}
+
+/// Writes the HTML and PDF sheets of each session into `dir` as it
+/// finishes, once `state` has stored it. Subscribes to `tx` before returning
+/// so no finish is missed; if the writer falls behind and a finish may have
+/// been among what it skipped, it writes the last finished session's sheets
+/// unless they are there already.
+pub fn spawn_writer(dir: PathBuf, db: SharedDatabase, state: SharedState, tx: &broadcast::Sender<WsMessage>) {
+    let mut rx = tx.subscribe();
+    tokio::spawn(async move {
+        let write = |if_missing| {
+            let (dir, db) = (dir.clone(), db.clone());
+            let written = state.lock().unwrap().written();
+            tokio::task::spawn_blocking(move || {
+                let _ = written.recv();
+                if let Err(e) = write_sheets(&dir, &db, if_missing) {
+                    eprintln!("Failed to write results sheets: {}", e);
+                }
+            })
+        };
+        loop {
+            match rx.recv().await {
+                Ok(WsMessage::Session(session)) if session.phase == SessionPhase::Finished => {
+                    let _ = write(false).await;
+                }
+                Ok(_) => {}
+                Err(broadcast::error::RecvError::Lagged(n)) => {
+                    eprintln!("Results writer lagged behind by {} messages, checking the last results are written", n);
+                    let _ = write(true).await;
+                }
+                Err(broadcast::error::RecvError::Closed) => break,
+            }
+        }
+    });
+}
+
+/// The session that just finished is the most recent finished one. With
+/// `if_missing`, nothing is written if there is none or its sheets exist.
+fn write_sheets(dir: &Path, db: &SharedDatabase, if_missing: bool) -> Result<(), Box<dyn Error>> {
+    if if_missing && db.last_finished_session()?.is_none() {
+        return Ok(());
+    }
+    let results = SessionResults::load(db, None)?;
+    let pdf_path = dir.join(results.file_name("results.pdf"));
+    // The PDF is written last
+    if if_missing && pdf_path.exists() {
+        return Ok(());
+    }
+    std::fs::create_dir_all(dir)?;
+    export::to_output(Some(&dir.join(results.file_name("results.html"))), |out| html(&results, out))?;
+    export::to_output(Some(&pdf_path), |out| pdf(&results, out))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::LiveState;
+    use crate::testing::finished_race;
+
+    #[test]
+    fn test_formatting() {
+        assert_eq!(format_time(&seconds::from_secs(23.4564)), "23.456");
+        assert_eq!(format_time(&seconds::from_secs(83.0)), "1:23.000");
+        assert_eq!(win_ansi("Müller 日本"), b"M\xfcller ??".to_vec());
+        assert_eq!(truncate("Max Verstappen", 6), "Max...");
+    }
+
+    /// Waits for the PDF, which is written last, to be complete.
+    async fn wait_for_pdf(path: &Path) {
+        for _ in 0..50 {
+            if std::fs::read(path).is_ok_and(|pdf| pdf.trim_ascii_end().ends_with(b"%%EOF")) {
+                return;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+        }
+    }
+
+    #[tokio::test]
+    async fn test_sheets_written_when_session_finishes() {
+        let db = finished_race();
+        let dir = PathBuf::from("test_results_sheets");
+        let _ = std::fs::remove_dir_all(&dir);
+        let (tx, _rx) = broadcast::channel(16);
+        spawn_writer(dir.clone(), db.clone(), LiveState::shared(), &tx);
+
+        let results = SessionResults::load(&db, None).unwrap();
+        tx.send(WsMessage::Session(results.record.session.clone())).unwrap();
+        let pdf_path = dir.join(results.file_name("results.pdf"));
+        wait_for_pdf(&pdf_path).await;
+
+        let html = std::fs::read_to_string(dir.join(results.file_name("results.html"))).unwrap();
+        assert!(html.contains("<h1>Heat 1 / Final</h1>"));
+        assert!(html.contains("<td>Max &amp; Co</td>"));
+        assert!(html.contains("<td class=\"num\">58.000</td><td class=\"num\">28.000</td>"));
+        assert!(html.contains("<td class=\"num\">+1.000</td>"));
+        assert_eq!(html.matches("<polyline").count(), 2);
+
+        let pdf = std::fs::read(&pdf_path).unwrap();
+        assert!(pdf.starts_with(b"%PDF"));
+        let pdf = String::from_utf8_lossy(&pdf);
+        assert!(pdf.contains("(Max & Co) Tj"));
+        assert!(pdf.contains("/Count 1"));
+
+        let _ = std::fs::remove_dir_all(dir);
+    }
+
+    #[tokio::test]
+    async fn test_sheets_written_when_the_finish_was_skipped() {
+        let db = finished_race();
+        let dir = PathBuf::from("test_results_sheets_lagged");
+        let _ = std::fs::remove_dir_all(&dir);
+        let (tx, _rx) = broadcast::channel(1);
+        spawn_writer(dir.clone(), db.clone(), LiveState::shared(), &tx);
+
+        // The writer hasn't run yet, so the finish is pushed out of the channel
+        let results = SessionResults::load(&db, None).unwrap();
+        tx.send(WsMessage::Session(results.record.session.clone())).unwrap();
+        tx.send(WsMessage::SessionReset { started: crate::messages::now() }).unwrap();
+        let pdf_path = dir.join(results.file_name("results.pdf"));
+        wait_for_pdf(&pdf_path).await;
+
+        assert!(std::fs::read(&pdf_path).unwrap().starts_with(b"%PDF"));
+        assert!(dir.join(results.file_name("results.html")).exists());
+
+        let _ = std::fs::remove_dir_all(dir);
+    }
+}
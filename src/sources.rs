@@ -0,0 +1,246 @@
+use serde::Serialize;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+use tokio::sync::Mutex;
+use tokio::task::JoinHandle;
+
+use crate::config::{self, AppMode, Config, Groups};
+use crate::status::{SourceState, SourceStatus, StatusHandle};
+use crate::replay::Replay;
+use crate::state::{PassingSender, SharedState};
+use crate::{converter, json_server, usb};
+
+/// How much longer than a connect attempt `apply` waits for the new source
+/// to connect or fail, for the handshake and a slow host lookup.
+const SETTLE_MARGIN: Duration = Duration::from_secs(3);
+
+pub type SharedGroups = Arc<RwLock<Groups>>;
+
+/// Result of applying a new configuration, returned by `POST /api/config`.
+#[derive(Debug, Serialize)]
+pub struct ApplyOutcome {
+    /// The new source connected (or, in TCP server mode, is listening).
+    pub ok: bool,
+    pub status: SourceStatus,
+    /// Web server settings only take effect after a restart.
+    pub restart_required: bool,
+}
+
+struct Running {
+    config: Config,
+    task: JoinHandle<()>,
+}
+
+/// Owns the timing source task so it can be swapped without touching the
+/// web server or connected displays.
+#[derive(Clone)]
+pub struct SourceManager {
+    running: Arc<Mutex<Running>>,
+    passings: PassingSender,
+    status: StatusHandle,
+    groups: SharedGroups,
+    /// Receives the lap rules whenever a config is applied.
+    state: SharedState,
+    /// Replaces the per-mode wait in `apply`, for tests.
+    settle_timeout: Option<Duration>,
+    /// Log raw source data (`--debug`).
+    debug: bool,
+}
+
+impl SourceManager {
+    pub fn start(config: Config, passings: PassingSender, status: StatusHandle, state: SharedState, debug: bool) -> Self {
+        let task = spawn_source(config.mode.clone(), passings.clone(), status.clone(), debug);
+        Self::new(config, task, passings, status, state, debug)
+    }
+
+    /// Feeds a recording instead of the configured source, until a new
+    /// configuration is applied.
+    pub fn replay(
+        config: Config,
+        replay: Replay,
+        passings: PassingSender,
+        status: StatusHandle,
+        state: SharedState,
+        debug: bool,
+    ) -> Self {
+        let task = tokio::spawn(replay.run(passings.clone(), status.clone()));
+        Self::new(config, task, passings, status, state, debug)
+    }
+
+    fn new(
+        config: Config,
+        task: JoinHandle<()>,
+        passings: PassingSender,
+        status: StatusHandle,
+        state: SharedState,
+        debug: bool,
+    ) -> Self {
+        state.lock().unwrap().set_rules(config.rules.clone());
+        Self {
+            groups: Arc::new(RwLock::new(config.groups.clone())),
+            state,
+            running: Arc::new(Mutex::new(Running { config, task })),
+            passings,
+            status,
+            settle_timeout: None,
+            debug,
+        }
+    }
+
+    #[cfg(test)]
+    pub fn with_settle_timeout(mut self, timeout: Duration) -> Self {
+        self.settle_timeout = Some(timeout);
+        self
+    }
+
+    pub fn groups(&self) -> SharedGroups {
+        self.groups.clone()
+    }
+
+    /// Tears down the current source, starts the one from `config` and waits
+    /// briefly to see whether it comes up.
+    pub async fn apply(&self, config: Config) -> ApplyOutcome {
+        let mut running = self.running.lock().await;
+
+        let restart_required = running.config.server.addr() != config.server.addr()
+            || running.config.broadcast_capacity != config.broadcast_capacity;
+        *self.groups.write().unwrap() = config.groups.clone();
+        self.state.lock().unwrap().set_rules(config.rules.clone());
+
+        running.task.abort();
+        // Make sure the old port/socket is released before the new source opens it
+        let _ = (&mut running.task).await;
+
+        println!("Applying new source: {}", config.mode.describe());
+        self.status.restart(config.mode.describe());
+        running.task = spawn_source(config.mode.clone(), self.passings.clone(), self.status.clone(), self.debug);
+        running.config = config;
+
+        let status = self.settle(&running.config.mode).await;
+        let ok = status.is_connected()
+            || (matches!(running.config.mode, AppMode::TcpServer { .. })
+                && status.state == SourceState::Connecting
+                && status.last_error.is_none());
+
+        ApplyOutcome { ok, status, restart_required }
+    }
+
+    /// Waits until the source connects or reports a problem, or its first
+    /// connect attempt should have done either.
+    async fn settle(&self, mode: &AppMode) -> SourceStatus {
+        let timeout = self.settle_timeout.unwrap_or_else(|| settle_timeout(mode));
+        let deadline = tokio::time::Instant::now() + timeout;
+        loop {
+            let status = self.status.snapshot();
+            let settled = match status.state {
+                SourceState::Connected | SourceState::Retrying | SourceState::Failed => true,
+                SourceState::Connecting | SourceState::Handshaking => status.last_error.is_some(),
+            };
+            if settled || tokio::time::Instant::now() >= deadline {
+                return status;
+            }
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+    }
+}
+
+/// How long `apply` waits for `mode`: a bit longer than its first connect
+/// attempt can take before the source reports that it is connected or why not.
+fn settle_timeout(mode: &AppMode) -> Duration {
+    match mode {
+        // Every target is tried in turn; names with several addresses can take longer still
+        AppMode::Tcp { fallback_hosts, .. } => converter::decoder::CONNECT_TIMEOUT * (1 + fallback_hosts.len() as u32) + SETTLE_MARGIN,
+        AppMode::Usb { .. } => usb::decoder::HANDSHAKE_TIME + SETTLE_MARGIN,
+        // A TCP server only fails on bind; after that it just waits for a client
+        AppMode::TcpServer { .. } => Duration::from_secs(1),
+    }
+}
+
+fn spawn_source(mode: AppMode, passings: PassingSender, status: StatusHandle, debug: bool) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        match mode {
+            config::AppMode::Tcp { host, port, fallback_hosts } => {
+                println!("Starting in TCP Mode: {}:{}", host, port);
+                match config::tcp_targets(&host, port, &fallback_hosts) {
+                    Ok(targets) => converter::decoder::Decoder::new(targets).run(passings, status).await,
+                    Err(e) => {
+                        eprintln!("Invalid decoder address: {}", e);
+                        status.set_error(SourceState::Failed, format!("Invalid decoder address: {}", e));
+                    }
+                }
+            },
+            config::AppMode::Usb { port_path } => {
+                println!("Starting in USB Mode: {}", port_path);
+                let usb_box = usb::decoder::UsbBox::new(port_path, 10);
+                usb_box.run(passings, status).await;
+            },
+            config::AppMode::TcpServer { port } => {
+                println!("Starting in TCP Server Mode on port {}", port);
+                json_server::run_server(passings, port, status, debug).await;
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::LiveState;
+    use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+    use tokio::net::TcpListener;
+
+    fn manager() -> SourceManager {
+        let (tx, _rx) = tokio::sync::broadcast::channel(16);
+        let status = StatusHandle::new("test", tx);
+        let (passings, _) = tokio::sync::mpsc::unbounded_channel();
+        let config = Config { mode: AppMode::TcpServer { port: 0 }, ..Default::default() };
+        SourceManager::start(config, passings, status, LiveState::shared(), false).with_settle_timeout(Duration::from_secs(5))
+    }
+
+    fn tcp(port: u16) -> Config {
+        Config { mode: AppMode::Tcp { host: "127.0.0.1".to_string(), port, fallback_hosts: Vec::new() }, ..Default::default() }
+    }
+
+    #[test]
+    fn test_settle_timeout_outlasts_a_connect_attempt() {
+        let fallbacks = AppMode::Tcp { host: "converter.local".to_string(), port: 3601, fallback_hosts: vec!["10.0.0.2".to_string(), "10.0.0.3".to_string()] };
+        assert!(settle_timeout(&fallbacks) > converter::decoder::CONNECT_TIMEOUT * 3);
+        assert!(settle_timeout(&AppMode::Usb { port_path: "COM3".to_string() }) > usb::decoder::HANDSHAKE_TIME);
+    }
+
+    #[tokio::test]
+    async fn test_apply_switches_to_a_working_source() {
+        // A decoder that acknowledges the handshake
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            let (socket, _) = listener.accept().await.unwrap();
+            let (reader, mut writer) = socket.into_split();
+            let mut lines = BufReader::new(reader).lines();
+            while let Ok(Some(line)) = lines.next_line().await {
+                let reply = match line.as_str() {
+                    "SETPROTOCOL;2.0" => "SETPROTOCOL;2.0\n",
+                    "SETPUSHPASSINGS;1;1" => "SETPUSHPASSINGS;1\n",
+                    _ => continue,
+                };
+                writer.write_all(reply.as_bytes()).await.unwrap();
+            }
+        });
+
+        let outcome = manager().apply(tcp(port)).await;
+        assert!(outcome.ok, "{:?}", outcome);
+        assert_eq!(outcome.status.state, SourceState::Connected);
+        assert_eq!(outcome.status.source, format!("tcp 127.0.0.1:{}", port));
+    }
+
+    #[tokio::test]
+    async fn test_apply_reports_a_failing_source() {
+        // Nothing listens on a port that was just released
+        let port = TcpListener::bind("127.0.0.1:0").await.unwrap().local_addr().unwrap().port();
+
+        let outcome = manager().apply(tcp(port)).await;
+        assert!(!outcome.ok);
+        assert_eq!(outcome.status.state, SourceState::Retrying);
+        assert!(outcome.status.last_error.is_some());
+    }
+}
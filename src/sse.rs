@@ -0,0 +1,201 @@
+use futures::stream::{self, Stream, StreamExt};
+use std::convert::Infallible;
+use tokio::sync::broadcast;
+use warp::sse::Event;
+use warp::Filter;
+
+use crate::events::{Resume, Sequenced};
+use crate::messages::{WireFormat, WsMessage};
+use crate::subscription::{FeedParams, Subscription};
+use crate::ws_handler::WsContext;
+
+/// `/events`: the same feed as `/ws` as Server-Sent Events. Every event id is
+/// the message sequence number, so a reconnecting `EventSource` resumes from
+/// `Last-Event-ID` without gaps.
+pub fn sse_routes(ctx: WsContext) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+    let ctx = warp::any().map(move || ctx.clone());
+
+    warp::path("events")
+        .and(warp::get())
+        .and(warp::query::<FeedParams>())
+        .and(warp::header::optional::<u64>("last-event-id"))
+        .and(ctx)
+        .map(|params: FeedParams, last_event_id: Option<u64>, ctx: WsContext| {
+            let subscription = match params.subscription(&ctx.groups.read().unwrap()) {
+                Ok(subscription) => subscription,
+                Err(e) => {
+                    return Box::new(warp::reply::with_status(e, warp::http::StatusCode::BAD_REQUEST))
+                        as Box<dyn warp::Reply>;
+                }
+            };
+            let stream = event_stream(ctx, params.format, subscription, last_event_id);
+            Box::new(warp::sse::reply(warp::sse::keep_alive().stream(stream)))
+        })
+}
+
+fn event_stream(
+    ctx: WsContext,
+    format: WireFormat,
+    subscription: Subscription,
+    last_event_id: Option<u64>,
+) -> impl Stream<Item = Result<Event, Infallible>> + Send + 'static {
+    let mut resume = ctx.events.resume(last_event_id, &ctx.state);
+
+    // Either replay what the client missed, or start it off like a new /ws client;
+    // one whose messages are no longer buffered is told it lagged first
+    let first: Vec<Sequenced> = match resume.backlog.take() {
+        Some(backlog) => {
+            println!("SSE client resumed after event {}", last_event_id.unwrap_or_default());
+            backlog
+        }
+        None => {
+            let skipped = last_event_id.filter(|id| *id < resume.last_seq).map(|id| resume.last_seq - id);
+            if let Some(skipped) = skipped {
+                println!("SSE client missed {} events that are no longer buffered, resyncing", skipped);
+            }
+            resync(&ctx, &mut resume, skipped)
+        }
+    };
+
+    // `seen` is the newest sequence number the client already has (directly or via a snapshot)
+    let live = stream::unfold((resume.rx, ctx, resume.last_seq), |(mut rx, ctx, seen)| async move {
+        let (batch, seen) = match rx.recv().await {
+            Ok(event) if event.seq <= seen => (Vec::new(), seen),
+            Ok(event) if event.seq == seen + 1 => {
+                let seq = event.seq;
+                (vec![event], seq)
+            }
+            // Numbers the event log skipped were never sent to anyone
+            Ok(event) => {
+                println!("SSE client missed {} events the server dropped, resyncing", event.seq - seen - 1);
+                resync_live(&ctx, &mut rx, event.seq - seen - 1)
+            }
+            Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                println!("SSE client lagged behind by {} messages, resyncing", skipped);
+                resync_live(&ctx, &mut rx, skipped)
+            }
+            Err(broadcast::error::RecvError::Closed) => return None,
+        };
+        Some((stream::iter(batch), (rx, ctx, seen)))
+    })
+    .flatten();
+
+    stream::iter(first)
+        .chain(live)
+        .filter_map(move |event| {
+            let event = subscription
+                .apply(&event.msg)
+                .and_then(|msg| msg.encode(format))
+                .map(|json| Ok(Event::default().id(event.seq.to_string()).data(json)));
+            futures::future::ready(event)
+        })
+}
+
+/// Starts a client that missed `skipped` events over from a fresh snapshot,
+/// on a new receiver; returns what to send and the newest sequence number in it.
+fn resync_live(ctx: &WsContext, rx: &mut broadcast::Receiver<Sequenced>, skipped: u64) -> (Vec<Sequenced>, u64) {
+    let mut resume = ctx.events.resume(None, &ctx.state);
+    let batch = resync(ctx, &mut resume, Some(skipped));
+    *rx = resume.rx;
+    (batch, resume.last_seq)
+}
+
+/// Status and the resume's snapshot, tagged with its newest sequence number
+/// so a later `Last-Event-ID` resumes right after them.
+fn resync(ctx: &WsContext, resume: &mut Resume, skipped: Option<u64>) -> Vec<Sequenced> {
+    let mut messages = Vec::new();
+    if let Some(skipped) = skipped {
+        messages.push(WsMessage::Lagged { skipped });
+    }
+    messages.push(WsMessage::Status(ctx.status.snapshot()));
+    messages.extend(resume.snapshot.take().map(WsMessage::Snapshot));
+
+    messages
+        .into_iter()
+        .map(|msg| Sequenced { seq: resume.last_seq, msg })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clients::ClientRegistry;
+    use crate::events::EventLog;
+    use crate::state::LiveState;
+    use crate::status::StatusHandle;
+    use std::net::SocketAddr;
+    use std::sync::{Arc, RwLock};
+    use std::time::Duration;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    /// Serves `/events` on an ephemeral port, after `sent` status messages.
+    async fn serve(sent: u64) -> SocketAddr {
+        let (tx, _rx) = broadcast::channel(2048);
+        let status = StatusHandle::new("test", tx.clone());
+        let state = LiveState::shared();
+        let events = EventLog::spawn(&tx, 2048);
+        for _ in 0..sent {
+            tx.send(WsMessage::Status(status.snapshot())).unwrap();
+        }
+
+        let ctx = WsContext {
+            tx,
+            status,
+            state,
+            clients: ClientRegistry::default(),
+            groups: Arc::new(RwLock::new(Default::default())),
+            events,
+        };
+        let (addr, server) = warp::serve(sse_routes(ctx)).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+        addr
+    }
+
+    /// Reads the stream until `until` shows up in it.
+    async fn read_events(addr: SocketAddr, last_event_id: Option<u64>, until: &str) -> String {
+        let mut stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+        let header = last_event_id.map(|id| format!("Last-Event-ID: {}\r\n", id)).unwrap_or_default();
+        let request = format!("GET /events HTTP/1.1\r\nHost: localhost\r\n{}\r\n", header);
+        stream.write_all(request.as_bytes()).await.unwrap();
+
+        let mut received = String::new();
+        let mut buf = [0u8; 4096];
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while !received.contains(until) {
+                let n = stream.read(&mut buf).await.unwrap();
+                assert!(n > 0, "stream ended without {:?}:\n{}", until, received);
+                received.push_str(&String::from_utf8_lossy(&buf[..n]));
+            }
+        })
+        .await
+        .unwrap_or_else(|_| panic!("no {:?} in:\n{}", until, received));
+        received
+    }
+
+    #[tokio::test]
+    async fn test_new_client_gets_status_and_snapshot() {
+        let addr = serve(2).await;
+        let received = read_events(addr, None, r#""type":"snapshot""#).await;
+        assert!(received.contains("text/event-stream"));
+        assert!(received.contains(r#""type":"status""#));
+        assert!(!received.contains(r#""type":"lagged""#));
+    }
+
+    #[tokio::test]
+    async fn test_resumes_after_last_event_id() {
+        let addr = serve(3).await;
+        let received = read_events(addr, Some(1), "id:3").await;
+        assert!(received.contains("id:2"));
+        assert!(!received.contains("id:1\n"));
+        assert!(!received.contains(r#""type":"snapshot""#));
+    }
+
+    #[tokio::test]
+    async fn test_evicted_last_event_id_is_told_to_resync() {
+        // More than the log keeps, so event 1 is gone
+        let addr = serve(1005).await;
+        let received = read_events(addr, Some(1), r#""type":"snapshot""#).await;
+        assert!(received.contains(r#"{"type":"lagged","data":{"skipped":1004},"v":1}"#));
+        assert!(received.find(r#""type":"lagged""#) < received.find(r#""type":"snapshot""#));
+    }
+}
@@ -0,0 +1,438 @@
+use serde::{Deserialize, Serialize};
+use std::cmp::Ordering;
+use std::collections::BTreeMap;
+
+use crate::laps::{Lap, LapEngine};
+use crate::messages::{seconds, LapTime, Timestamp};
+use crate::session::{Ranking, Session};
+
+/// The leaderboard, broadcast as `WsMessage::Standings` after every counted
+/// passing.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct Standings {
+    pub ranking: Ranking,
+    /// Leader first.
+    pub entries: Vec<Standing>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Standing {
+    /// 1 for the leader.
+    pub position: u32,
+    pub transponder: String,
+    pub laps: u32,
+    /// From the session start to the crossing that completed the last lap.
+    #[serde(with = "seconds::option")]
+    pub race_time: Option<LapTime>,
+    #[serde(with = "seconds::option")]
+    pub last_lap: Option<LapTime>,
+    #[serde(with = "seconds::option")]
+    pub best_lap: Option<LapTime>,
+    /// Behind the leader: at the line in a race, or the delta to pole when
+    /// ranked on lap times. `null` for the leader and anyone without a lap.
+    #[serde(with = "seconds::option")]
+    pub gap: Option<LapTime>,
+    /// Behind the competitor one place ahead, measured the same way.
+    #[serde(with = "seconds::option")]
+    pub interval: Option<LapTime>,
+    /// Laps behind the leader in a race.
+    pub laps_down: u32,
+    /// Time the ranking compared when ranked on lap times, e.g. the best lap.
+    #[serde(with = "seconds::option")]
+    pub ranked_time: Option<LapTime>,
+    /// Lap numbers that made up `ranked_time`.
+    pub counted_laps: Vec<u32>,
+    /// Has taken the chequered flag.
+    pub finished: bool,
+}
+
+/// Ranks the session the way its settings ask for. Competitors without a
+/// lap yet come last, in the order they first crossed the line.
+pub fn compute(laps: &LapEngine, session: &Session, consecutive: &ConsecutiveLaps) -> Standings {
+    let rows: Vec<Row> = laps
+        .transponders()
+        .map(|t| Row {
+            transponder: &t.transponder,
+            history: laps.history(&t.transponder),
+            opened: t.lap_started,
+        })
+        .collect();
+
+    let ranking = session.settings.ranking();
+    let entries = match ranking {
+        Ranking::Laps => by_laps(rows, session),
+        Ranking::BestLap => by_best_lap(rows, session),
+        Ranking::BestConsecutive(_) => by_best_consecutive(rows, session, consecutive),
+    };
+    Standings { ranking, entries }
+}
+
+struct Row<'a> {
+    transponder: &'a str,
+    history: &'a [Lap],
+    /// Start of the lap in progress; orders competitors without a lap.
+    opened: Timestamp,
+}
+
+impl Row<'_> {
+    fn without_laps_order(&self, other: &Row) -> Ordering {
+        self.opened.cmp(&other.opened).then_with(|| self.transponder.cmp(other.transponder))
+    }
+
+    /// Entry with the fields every ranking shares.
+    fn standing(&self, position: usize, session: &Session) -> Standing {
+        let last = self.history.last();
+        Standing {
+            position: position as u32 + 1,
+            transponder: self.transponder.to_string(),
+            laps: self.history.len() as u32,
+            race_time: last.map(|lap| match session.started_at {
+                Some(started) => lap.timestamp - started,
+                None => lap.total_time,
+            }),
+            last_lap: last.map(|lap| lap.lap_time),
+            best_lap: last.map(|lap| lap.best_lap),
+            gap: None,
+            interval: None,
+            laps_down: 0,
+            ranked_time: None,
+            counted_laps: Vec::new(),
+            finished: session.finished.iter().any(|t| t == self.transponder),
+        }
+    }
+}
+
+/// Race order: most laps first, then whoever completed them first (the
+/// lowest cumulative time from the session start).
+fn by_laps(mut rows: Vec<Row>, session: &Session) -> Vec<Standing> {
+    rows.sort_by(|a, b| {
+        b.history.len().cmp(&a.history.len()).then_with(|| match (a.history.last(), b.history.last()) {
+            (Some(a), Some(b)) => a.timestamp.cmp(&b.timestamp),
+            _ => a.without_laps_order(b),
+        })
+    });
+
+    let crossing = |history: &[Lap], lap_number: usize| history.get(lap_number - 1).map(|lap| lap.timestamp);
+    let leader = rows.first().map(|row| row.history).unwrap_or_default();
+
+    rows.iter()
+        .enumerate()
+        .map(|(i, row)| {
+            let last = row.history.last();
+            let behind = |other: &[Lap]| match last {
+                Some(last) if i > 0 => crossing(other, row.history.len()).map(|ts| last.timestamp - ts),
+                _ => None,
+            };
+            Standing {
+                gap: behind(leader),
+                interval: if i > 0 { behind(rows[i - 1].history) } else { None },
+                laps_down: (leader.len() - row.history.len()) as u32,
+                ..row.standing(i, session)
+            }
+        })
+        .collect()
+}
+
+/// Qualifying order: fastest valid lap first. Equal laps go to whoever set
+/// theirs first, then to the better second-best lap.
+fn by_best_lap(rows: Vec<Row>, session: &Session) -> Vec<Standing> {
+    let mut rows: Vec<(Row, Option<BestLaps>)> = rows
+        .into_iter()
+        .map(|row| {
+            let best = BestLaps::of(row.history);
+            (row, best)
+        })
+        .collect();
+    rows.sort_by(|(a, a_best), (b, b_best)| match (a_best, b_best) {
+        (Some(a), Some(b)) => a
+            .lap
+            .lap_time
+            .cmp(&b.lap.lap_time)
+            .then_with(|| a.lap.timestamp.cmp(&b.lap.timestamp))
+            .then_with(|| cmp_missing_last(&a.second, &b.second)),
+        (Some(_), None) => Ordering::Less,
+        (None, Some(_)) => Ordering::Greater,
+        (None, None) => a.without_laps_order(b),
+    });
+
+    let pole = rows.first().and_then(|(_, best)| best.as_ref().map(|best| best.lap.lap_time));
+    rows.iter()
+        .enumerate()
+        .map(|(i, (row, best))| {
+            let ranked = best.as_ref().map(|best| best.lap.lap_time);
+            let ahead = if i > 0 { rows[i - 1].1.as_ref().map(|best| best.lap.lap_time) } else { None };
+            Standing {
+                gap: if i > 0 { ranked.zip(pole).map(|(t, pole)| t - pole) } else { None },
+                interval: ranked.zip(ahead).map(|(t, ahead)| t - ahead),
+                ranked_time: ranked,
+                counted_laps: best.iter().map(|best| best.lap.lap_number).collect(),
+                ..row.standing(i, session)
+            }
+        })
+        .collect()
+}
+
+/// A competitor's best lap (the first one, if equalled later) and the next
+/// best time.
+struct BestLaps<'a> {
+    lap: &'a Lap,
+    second: Option<LapTime>,
+}
+
+impl<'a> BestLaps<'a> {
+    fn of(history: &'a [Lap]) -> Option<Self> {
+        let lap = history.iter().min_by_key(|lap| lap.lap_time)?;
+        let second = history
+            .iter()
+            .filter(|other| other.lap_number != lap.lap_number)
+            .map(|other| other.lap_time)
+            .min();
+        Some(Self { lap, second })
+    }
+}
+
+/// Fastest run of N laps in a row first; equal runs go to whoever completed
+/// theirs first. Competitors without N laps yet follow, most laps first.
+fn by_best_consecutive(mut rows: Vec<Row>, session: &Session, consecutive: &ConsecutiveLaps) -> Vec<Standing> {
+    rows.sort_by(|a, b| match (consecutive.best(a.transponder), consecutive.best(b.transponder)) {
+        (Some(a), Some(b)) => a.time.cmp(&b.time).then_with(|| a.completed.cmp(&b.completed)),
+        (Some(_), None) => Ordering::Less,
+        (None, Some(_)) => Ordering::Greater,
+        (None, None) => b.history.len().cmp(&a.history.len()).then_with(|| a.without_laps_order(b)),
+    });
+
+    let ranked = |row: &Row| consecutive.best(row.transponder).map(|window| window.time);
+    let pole = rows.first().and_then(ranked);
+    rows.iter()
+        .enumerate()
+        .map(|(i, row)| {
+            let window = consecutive.best(row.transponder);
+            let time = ranked(row);
+            let ahead = if i > 0 { ranked(&rows[i - 1]) } else { None };
+            Standing {
+                gap: if i > 0 { time.zip(pole).map(|(t, pole)| t - pole) } else { None },
+                interval: time.zip(ahead).map(|(t, ahead)| t - ahead),
+                ranked_time: time,
+                counted_laps: window.map(|w| (w.first_lap..w.first_lap + consecutive.laps).collect()).unwrap_or_default(),
+                ..row.standing(i, session)
+            }
+        })
+        .collect()
+}
+
+/// Best run of N consecutive laps per transponder for the `best_consecutive`
+/// ranking, kept up to date lap by lap so ranking never rescans the history.
+#[derive(Default)]
+pub struct ConsecutiveLaps {
+    /// Window length; 0 when the session isn't ranked this way.
+    laps: u32,
+    transponders: BTreeMap<String, Windows>,
+}
+
+#[derive(Default)]
+struct Windows {
+    /// Sum of the last `laps` laps.
+    running: LapTime,
+    best: Option<Window>,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Window {
+    pub first_lap: u32,
+    pub time: LapTime,
+    /// When the window's last lap was completed.
+    pub completed: Timestamp,
+}
+
+impl ConsecutiveLaps {
+    pub fn new(ranking: Ranking) -> Self {
+        let laps = match ranking {
+            Ranking::BestConsecutive(laps) => laps,
+            Ranking::Laps | Ranking::BestLap => 0,
+        };
+        Self { laps, transponders: BTreeMap::new() }
+    }
+
+    /// Slides the transponder's window on by `lap`, which must be the last
+    /// entry of `history`.
+    pub fn record(&mut self, lap: &Lap, history: &[Lap]) {
+        let n = self.laps as usize;
+        if n == 0 {
+            return;
+        }
+        let windows = self.transponders.entry(lap.transponder.clone()).or_default();
+        windows.running += lap.lap_time;
+        if history.len() > n {
+            windows.running -= history[history.len() - 1 - n].lap_time;
+        }
+        if history.len() >= n && windows.best.is_none_or(|best| windows.running < best.time) {
+            windows.best = Some(Window {
+                first_lap: lap.lap_number + 1 - self.laps,
+                time: windows.running,
+                completed: lap.timestamp,
+            });
+        }
+    }
+
+    pub fn best(&self, transponder: &str) -> Option<&Window> {
+        self.transponders.get(transponder)?.best.as_ref()
+    }
+}
+
+fn cmp_missing_last(a: &Option<LapTime>, b: &Option<LapTime>) -> Ordering {
+    match (a, b) {
+        (Some(a), Some(b)) => a.cmp(b),
+        (Some(_), None) => Ordering::Less,
+        (None, Some(_)) => Ordering::Greater,
+        (None, None) => Ordering::Equal,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::laps::ReadOutcome;
+    use crate::session::{SessionKind, SessionSettings};
+    use crate::testing::passing;
+
+    fn race(crossings: &[(&str, i64)]) -> Standings {
+        run(SessionKind::Race, crossings)
+    }
+
+    fn run(kind: SessionKind, crossings: &[(&str, i64)]) -> Standings {
+        run_settings(SessionSettings { kind, ..Default::default() }, crossings)
+    }
+
+    fn run_settings(settings: SessionSettings, crossings: &[(&str, i64)]) -> Standings {
+        let mut laps = LapEngine::default();
+        let mut consecutive = ConsecutiveLaps::new(settings.ranking());
+        let mut session = Session::new(settings);
+        for (transponder, millis) in crossings {
+            let passing = passing(transponder, *millis);
+            session.observe(&passing);
+            if let ReadOutcome::Lap(lap) = laps.record(&passing) {
+                consecutive.record(&lap, laps.history(&lap.transponder));
+            }
+        }
+        compute(&laps, &session, &consecutive)
+    }
+
+    #[test]
+    fn test_order_gap_and_interval() {
+        let standings = race(&[
+            ("1", 0),
+            ("2", 500),
+            ("3", 1_000),
+            ("1", 30_000),
+            ("2", 31_000),
+            ("3", 33_000),
+            ("1", 60_000),
+            ("2", 62_500),
+        ]);
+        let order: Vec<_> = standings.entries.iter().map(|s| s.transponder.as_str()).collect();
+        assert_eq!(order, ["1", "2", "3"]);
+
+        let [leader, second, third] = &standings.entries[..] else { panic!() };
+        assert_eq!(leader.position, 1);
+        assert_eq!(leader.gap, None);
+        assert_eq!(leader.race_time, Some(LapTime::seconds(60)));
+        assert_eq!(second.gap, Some(LapTime::milliseconds(2_500)));
+        assert_eq!(second.interval, Some(LapTime::milliseconds(2_500)));
+        // A lap down: measured against where the others were on lap 1
+        assert_eq!(third.laps_down, 1);
+        assert_eq!(third.gap, Some(LapTime::seconds(3)));
+        assert_eq!(third.interval, Some(LapTime::seconds(2)));
+    }
+
+    #[test]
+    fn test_competitors_without_laps_come_last() {
+        let standings = race(&[("2", 0), ("1", 1_000), ("2", 30_000)]);
+        assert_eq!(standings.entries[0].transponder, "2");
+        assert_eq!(standings.entries[1].transponder, "1");
+        assert_eq!(standings.entries[1].laps, 0);
+        assert_eq!(standings.entries[1].gap, None);
+        assert_eq!(standings.entries[1].race_time, None);
+    }
+
+    #[test]
+    fn test_qualifying_by_best_lap() {
+        let standings = run(
+            SessionKind::Qualifying,
+            &[
+                ("1", 0),
+                ("2", 0),
+                ("3", 0),
+                ("1", 31_000),
+                ("2", 30_000),
+                ("3", 35_000),
+                ("1", 62_000),
+                ("2", 62_000),
+                ("3", 65_000),
+                ("1", 92_000),
+            ],
+        );
+        assert_eq!(standings.ranking, Ranking::BestLap);
+        let order: Vec<_> = standings.entries.iter().map(|s| s.transponder.as_str()).collect();
+        assert_eq!(order, ["2", "3", "1"]);
+
+        let [pole, second, third] = &standings.entries[..] else { panic!() };
+        assert_eq!(pole.ranked_time, Some(LapTime::seconds(30)));
+        assert_eq!(pole.counted_laps, [1]);
+        assert_eq!(pole.gap, None);
+        assert_eq!(second.counted_laps, [2]);
+        assert_eq!(second.gap, Some(LapTime::zero()));
+        // All three did a 30s lap; whoever set it first ranks higher
+        assert_eq!(third.counted_laps, [3]);
+        assert_eq!(third.ranked_time, Some(LapTime::seconds(30)));
+        assert_eq!(third.best_lap, Some(LapTime::seconds(30)));
+    }
+
+    #[test]
+    fn test_equal_best_laps_fall_back_to_second_best() {
+        let standings = run(
+            SessionKind::Qualifying,
+            &[("1", 0), ("2", 0), ("1", 30_000), ("2", 30_000), ("1", 65_000), ("2", 62_000)],
+        );
+        // Same best lap at the same moment; 2's second lap was quicker
+        assert_eq!(standings.entries[0].transponder, "2");
+        assert_eq!(standings.entries[1].interval, Some(LapTime::zero()));
+    }
+
+    #[test]
+    fn test_best_consecutive_window() {
+        let settings = SessionSettings { ranking: Some(Ranking::BestConsecutive(3)), ..Default::default() };
+        // 1: 30, 35, 29, 29, 29 -> best run is laps 3-5 (87s)
+        // 2: 28, 29, 31, 40 -> best run is laps 1-3 (88s), despite the faster single lap
+        // 3: only two laps
+        let standings = run_settings(
+            settings,
+            &[
+                ("1", 0),
+                ("2", 0),
+                ("3", 0),
+                ("2", 28_000),
+                ("3", 29_000),
+                ("1", 30_000),
+                ("2", 57_000),
+                ("3", 58_000),
+                ("1", 65_000),
+                ("2", 88_000),
+                ("1", 94_000),
+                ("1", 123_000),
+                ("2", 128_000),
+                ("1", 152_000),
+            ],
+        );
+        let order: Vec<_> = standings.entries.iter().map(|s| s.transponder.as_str()).collect();
+        assert_eq!(order, ["1", "2", "3"]);
+
+        let [first, second, third] = &standings.entries[..] else { panic!() };
+        assert_eq!(first.ranked_time, Some(LapTime::seconds(87)));
+        assert_eq!(first.counted_laps, [3, 4, 5]);
+        assert_eq!(second.ranked_time, Some(LapTime::seconds(88)));
+        assert_eq!(second.counted_laps, [1, 2, 3]);
+        assert_eq!(second.gap, Some(LapTime::seconds(1)));
+        assert_eq!(third.ranked_time, None);
+        assert!(third.counted_laps.is_empty());
+    }
+}
@@ -0,0 +1,659 @@
+use chrono::SubsecRound;
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::io;
+use std::path::Path;
+use std::sync::{mpsc, Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::broadcast;
+
+use crate::config::LapRules;
+use crate::db::{Database, SharedDatabase};
+use crate::journal::{Journal, JournalEntry};
+use crate::recorder::Recorder;
+use crate::laps::{LapEngine, ReadOutcome, SuppressedRead, TransponderSummary};
+use crate::messages::{self, timestamp, LapTime, Passing, Timestamp, WsMessage};
+use crate::session::{Session, SessionSettings};
+use crate::standings::{self, ConsecutiveLaps, Standings};
+use crate::status::{SourceState, SourceStatus};
+
+/// Number of raw passings (and of suppressed reads) kept for late joiners.
+const RECENT_PASSINGS: usize = 50;
+
+/// How often a running session checks its time limit.
+const TICK_INTERVAL: Duration = Duration::from_secs(1);
+
+pub type SharedState = Arc<Mutex<LiveState>>;
+
+/// Where timing sources hand their passings to the live state, which counts
+/// them and then publishes them. Unbounded, unlike the broadcast channel, so
+/// no passing is dropped however far behind the live state falls.
+pub type PassingSender = tokio::sync::mpsc::UnboundedSender<Passing>;
+
+#[derive(Clone, Debug, Serialize)]
+pub struct Alert {
+    pub id: u64,
+    pub message: String,
+    #[serde(with = "timestamp")]
+    pub raised_at: Timestamp,
+    pub acknowledged: bool,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct Snapshot {
+    #[serde(with = "timestamp")]
+    pub session_started: Timestamp,
+    pub session: Session,
+    pub standings: Standings,
+    pub transponders: Vec<TransponderSummary>,
+    pub recent_passings: Vec<Passing>,
+    /// Most recent reads the lap rules kept from counting.
+    pub suppressed: Vec<SuppressedRead>,
+    pub alerts: Vec<Alert>,
+}
+
+/// Server-side view of the running session, fed by the timing source.
+pub struct LiveState {
+    session_started: Timestamp,
+    session: Session,
+    /// Configured rules, used when the session doesn't bring its own.
+    rules: LapRules,
+    laps: LapEngine,
+    consecutive: ConsecutiveLaps,
+    recent: VecDeque<Passing>,
+    suppressed: VecDeque<SuppressedRead>,
+    alerts: Vec<Alert>,
+    next_alert_id: u64,
+    source_state: Option<SourceState>,
+    /// Latest passing time and when it arrived, so session time follows the
+    /// source clock (and replays) rather than the machine's.
+    clock: Option<(Timestamp, Instant)>,
+    /// Writes the journal and the database; only started once there is one.
+    recorder: Option<Recorder>,
+    has_journal: bool,
+    has_db: bool,
+}
+
+impl Default for LiveState {
+    fn default() -> Self {
+        Self {
+            session_started: messages::now(),
+            session: Session::new(SessionSettings::default()),
+            rules: LapRules::default(),
+            laps: LapEngine::default(),
+            consecutive: ConsecutiveLaps::default(),
+            recent: VecDeque::with_capacity(RECENT_PASSINGS),
+            suppressed: VecDeque::with_capacity(RECENT_PASSINGS),
+            alerts: Vec::new(),
+            next_alert_id: 1,
+            source_state: None,
+            clock: None,
+            recorder: None,
+            has_journal: false,
+            has_db: false,
+        }
+    }
+}
+
+impl LiveState {
+    pub fn shared() -> SharedState {
+        Arc::new(Mutex::new(Self::default()))
+    }
+
+    /// Sets the configured lap rules; a session with its own rules keeps them.
+    /// A change is journaled, so a replay counts laps the way they were counted.
+    pub fn set_rules(&mut self, rules: LapRules) {
+        if rules != self.rules {
+            self.write_journal(JournalEntry::Rules(rules.clone()));
+        }
+        self.rules = rules;
+        self.laps.set_rules(self.session.settings.rules.clone().unwrap_or_else(|| self.rules.clone()));
+    }
+
+    /// Rebuilds the current session from the journal at `path`, replaying
+    /// from its last new session on under the lap rules journaled with it,
+    /// then keeps writing every passing, session control and rule change to
+    /// it. The configured rules apply from then on. Returns how many entries
+    /// were replayed.
+    pub fn open_journal(&mut self, path: &Path) -> io::Result<usize> {
+        let (journal, entries) = Journal::open(path)?;
+        self.has_journal = false;
+        let configured = self.rules.clone();
+        let start = entries
+            .iter()
+            .rposition(|entry| matches!(entry, JournalEntry::Session { control: SessionControl::New(_), .. }))
+            .unwrap_or(0);
+        let mut journaled_rules = None;
+        for (i, entry) in entries.iter().enumerate() {
+            match entry {
+                JournalEntry::Rules(rules) => {
+                    self.set_rules(rules.clone());
+                    journaled_rules = Some(rules);
+                }
+                JournalEntry::Passing(passing) if i >= start => {
+                    self.record_passing(passing);
+                }
+                JournalEntry::Session { at, control } if i >= start => {
+                    let _ = self.apply_control(control.clone(), *at);
+                }
+                _ => {}
+            }
+        }
+        // Time has passed since the last entry; go by the wall clock until the next passing
+        self.clock = None;
+        self.set_rules(configured.clone());
+        self.recorder().attach_journal(journal);
+        self.has_journal = true;
+        if journaled_rules != Some(&configured) {
+            self.write_journal(JournalEntry::Rules(configured));
+        }
+        if entries.is_empty() {
+            // Record when the session that runs until the first new one began,
+            // so a restart carries on with it rather than starting another
+            self.session_started = self.session_started.trunc_subsecs(6);
+            let control = SessionControl::New(self.session.settings.clone());
+            self.write_journal(JournalEntry::Session { at: self.session_started, control });
+        }
+        Ok(entries.len() - start)
+    }
+
+    /// Starts recording into `db`, beginning with the current session, which
+    /// is found again if it was already stored before a restart.
+    pub fn attach_database(&mut self, db: SharedDatabase) -> rusqlite::Result<()> {
+        let id = db.session_id(&self.session, self.session_started)?;
+        db.update_session(id, &self.session, &self.standings())?;
+        self.recorder().attach_database(db, id);
+        self.has_db = true;
+        Ok(())
+    }
+
+    /// Raises an alert through `handler` the first time the journal can't be
+    /// written. Only has an effect once a journal or database is attached.
+    pub fn on_journal_error(&self, handler: impl Fn(String) + Send + 'static) {
+        if let Some(recorder) = &self.recorder {
+            recorder.on_journal_error(Box::new(handler));
+        }
+    }
+
+    /// Hears back once every write made so far is on disk, or at once if
+    /// nothing is recorded. Wait on it without holding the state lock.
+    pub fn written(&self) -> mpsc::Receiver<()> {
+        match &self.recorder {
+            Some(recorder) => recorder.written(),
+            None => mpsc::channel().1,
+        }
+    }
+
+    fn recorder(&mut self) -> &Recorder {
+        self.recorder.get_or_insert_with(Recorder::spawn)
+    }
+
+    /// Queues a database write, if there is a database.
+    fn store(&self, write: impl FnOnce(&Database, Option<i64>) -> rusqlite::Result<()> + Send + 'static) {
+        if let (true, Some(recorder)) = (self.has_db, &self.recorder) {
+            recorder.store(Box::new(write));
+        }
+    }
+
+    /// Stores the session's phase and standings.
+    fn store_session(&self) {
+        if !self.has_db {
+            return;
+        }
+        let (session, standings) = (self.session.clone(), self.standings());
+        self.store(move |db, id| match id {
+            Some(id) => db.update_session(id, &session, &standings),
+            None => Ok(()),
+        });
+    }
+
+    /// Queues a journal entry, if there is a journal. It is not on disk yet
+    /// when this returns; see [`Journal`].
+    fn write_journal(&self, entry: JournalEntry) {
+        if let (true, Some(recorder)) = (self.has_journal, &self.recorder) {
+            recorder.journal(entry);
+        }
+    }
+
+    /// Records a passing and returns the messages it leads to: a lap or a
+    /// suppressed read, the standings when the passing counted, and the
+    /// session when it changed.
+    pub fn record_passing(&mut self, passing: &Passing) -> Vec<WsMessage> {
+        let mut messages = Vec::new();
+        self.write_journal(JournalEntry::Passing(passing.clone()));
+        push_bounded(&mut self.recent, passing.clone());
+        let stored = passing.clone();
+        self.store(move |db, id| db.insert_passing(id, &stored));
+        self.clock = Some((passing.timestamp, Instant::now()));
+
+        let mut session_changed = self.session.tick(passing.timestamp);
+        session_changed |= self.session.observe(passing);
+        let mut standings_changed = false;
+        if self.session.counts(&passing.transponder) {
+            match self.laps.record(passing) {
+                ReadOutcome::Started => {
+                    self.session.record_crossing(&passing.transponder);
+                    standings_changed = true;
+                    messages.push(WsMessage::Standings(self.standings()));
+                }
+                ReadOutcome::Lap(lap) => {
+                    session_changed |= self.session.record_lap(&lap);
+                    self.consecutive.record(&lap, self.laps.history(&lap.transponder));
+                    let stored = lap.clone();
+                    self.store(move |db, id| id.map_or(Ok(()), |id| db.insert_lap(id, &stored)));
+                    standings_changed = true;
+                    messages.push(WsMessage::Lap(lap));
+                    messages.push(WsMessage::Standings(self.standings()));
+                }
+                ReadOutcome::Suppressed(read) => {
+                    push_bounded(&mut self.suppressed, read.clone());
+                    messages.push(WsMessage::Suppressed(read));
+                }
+            }
+        }
+        if session_changed || standings_changed {
+            self.store_session();
+        }
+        if session_changed {
+            messages.push(WsMessage::Session(self.session.clone()));
+        }
+        messages
+    }
+
+    /// Session time: the last passing's timestamp plus the time since it
+    /// arrived, or the wall clock before any passing.
+    pub fn clock(&self) -> Timestamp {
+        match self.clock {
+            Some((ts, received)) => ts + LapTime::from_std(received.elapsed()).unwrap_or_default(),
+            None => messages::now(),
+        }
+    }
+
+    /// Checks the time limit; returns the session when the flag came out.
+    pub fn tick(&mut self) -> Option<Session> {
+        let now = self.clock();
+        if !self.session.tick(now) {
+            return None;
+        }
+        self.store_session();
+        Some(self.session.clone())
+    }
+
+    pub fn session(&self) -> &Session {
+        &self.session
+    }
+
+    pub fn standings(&self) -> Standings {
+        standings::compute(&self.laps, &self.session, &self.consecutive)
+    }
+
+    /// Applies a session control now and journals it; see [`control_session`].
+    pub fn control_session(&mut self, control: SessionControl) -> Result<Session, String> {
+        // Journal timestamps keep microseconds; restore to exactly the same state
+        let at = self.clock().trunc_subsecs(6);
+        let session = self.apply_control(control.clone(), at)?;
+        self.write_journal(JournalEntry::Session { at, control: control.clone() });
+        self.store_session();
+        let detail = serde_json::to_value(&control).unwrap_or_default();
+        self.store(move |db, id| db.log_event(id, "session", &detail));
+        Ok(session)
+    }
+
+    fn apply_control(&mut self, control: SessionControl, at: Timestamp) -> Result<Session, String> {
+        let changed = match control {
+            SessionControl::New(settings) => {
+                settings.validate().map_err(|e| format!("{}: {}", e.field, e.message))?;
+                self.reset_to(settings, at);
+                true
+            }
+            SessionControl::Start => self.session.start(at),
+            SessionControl::Chequered => self.session.chequered(at),
+            SessionControl::Finish => self.session.finish(at),
+        };
+        if !changed {
+            return Err(format!("Session is {}", self.session.phase.as_str()));
+        }
+        Ok(self.session.clone())
+    }
+
+    /// Raises an alert when the source drops out of a working state.
+    pub fn record_status(&mut self, status: &SourceStatus) -> Option<Alert> {
+        let previous = self.source_state.replace(status.state);
+        let failing = matches!(status.state, SourceState::Retrying | SourceState::Failed);
+        let was_failing = matches!(previous, Some(SourceState::Retrying | SourceState::Failed));
+        if !failing || was_failing {
+            return None;
+        }
+
+        let message = match &status.last_error {
+            Some(e) => format!("{} is {:?}: {}", status.source, status.state, e),
+            None => format!("{} is {:?}", status.source, status.state),
+        };
+        Some(self.raise_alert(message))
+    }
+
+    pub fn raise_alert(&mut self, message: String) -> Alert {
+        let alert = Alert {
+            id: self.next_alert_id,
+            message,
+            raised_at: messages::now(),
+            acknowledged: false,
+        };
+        self.next_alert_id += 1;
+        self.alerts.push(alert.clone());
+        alert
+    }
+
+    pub fn acknowledge_alert(&mut self, id: u64) -> Option<Alert> {
+        let alert = self.alerts.iter_mut().find(|a| a.id == id)?;
+        alert.acknowledged = true;
+        Some(alert.clone())
+    }
+
+    /// Starts a fresh session, forgetting all passings. Alerts are kept.
+    fn reset_to(&mut self, settings: SessionSettings, at: Timestamp) -> Timestamp {
+        self.session_started = at;
+        self.consecutive = ConsecutiveLaps::new(settings.ranking());
+        self.session = Session::new(settings);
+        if let (true, Some(recorder)) = (self.has_db, &self.recorder) {
+            recorder.new_session(self.session.clone(), at);
+        }
+        self.laps.reset();
+        self.set_rules(self.rules.clone());
+        self.recent.clear();
+        self.suppressed.clear();
+        self.session_started
+    }
+
+    pub fn snapshot(&self) -> Snapshot {
+        Snapshot {
+            session_started: self.session_started,
+            session: self.session.clone(),
+            standings: self.standings(),
+            transponders: self.laps.summaries(),
+            recent_passings: self.recent.iter().cloned().collect(),
+            suppressed: self.suppressed.iter().cloned().collect(),
+            alerts: self.alerts.clone(),
+        }
+    }
+}
+
+/// Ways to drive the session, shared by the WebSocket commands and the REST API.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(tag = "action", content = "settings", rename_all = "snake_case")]
+pub enum SessionControl {
+    /// Replaces the session with a new one, forgetting all passings.
+    New(SessionSettings),
+    Start,
+    Chequered,
+    Finish,
+}
+
+/// Applies `control` and tells every client about the new session state.
+pub fn control_session(state: &SharedState, tx: &broadcast::Sender<WsMessage>, control: SessionControl) -> Result<Session, String> {
+    let is_new = matches!(control, SessionControl::New(_));
+    // Sent under the lock; see [`subscribe`]
+    let mut state = state.lock().unwrap();
+    let session = state.control_session(control)?;
+    if is_new {
+        let _ = tx.send(WsMessage::SessionReset { started: state.session_started });
+    }
+    let _ = tx.send(WsMessage::Session(session.clone()));
+    Ok(session)
+}
+
+/// Subscribes to `tx` and takes a snapshot of `state` at the same moment.
+/// Whatever the live state sends about a change goes out before it lets go
+/// of the lock, so each change is either in the snapshot or still to come on
+/// the receiver, never both (a lap is not counted twice) and never neither.
+pub fn subscribe(tx: &broadcast::Sender<WsMessage>, state: &SharedState) -> (broadcast::Receiver<WsMessage>, Snapshot) {
+    let state = state.lock().unwrap();
+    (tx.subscribe(), state.snapshot())
+}
+
+fn push_bounded<T>(list: &mut VecDeque<T>, item: T) {
+    if list.len() == RECENT_PASSINGS {
+        list.pop_front();
+    }
+    list.push_back(item);
+}
+
+/// Counts the passings sent to the returned sender into `state` and publishes
+/// them on `tx` with what they lead to, follows the source status, and raises
+/// an alert if the journal can't be written. Subscribes before returning so
+/// no status sent afterwards is missed.
+pub fn spawn(tx: broadcast::Sender<WsMessage>, state: SharedState) -> PassingSender {
+    let (weak, alerts) = (Arc::downgrade(&state), tx.clone());
+    state.lock().unwrap().on_journal_error(move |message| {
+        if let Some(state) = weak.upgrade() {
+            let mut state = state.lock().unwrap();
+            let _ = alerts.send(WsMessage::Alert(state.raise_alert(message)));
+        }
+    });
+    let rx = tx.subscribe();
+    let (passings, passings_rx) = tokio::sync::mpsc::unbounded_channel();
+    tokio::spawn(run(tx, rx, passings_rx, state));
+    passings
+}
+
+async fn run(
+    tx: broadcast::Sender<WsMessage>,
+    mut rx: broadcast::Receiver<WsMessage>,
+    mut passings: tokio::sync::mpsc::UnboundedReceiver<Passing>,
+    state: SharedState,
+) {
+    let mut ticks = tokio::time::interval(TICK_INTERVAL);
+    loop {
+        let msg = tokio::select! {
+            Some(passing) = passings.recv() => WsMessage::Passing(passing),
+            msg = rx.recv() => match msg {
+                // Passings come in on their own channel
+                Ok(WsMessage::Passing(_)) => continue,
+                Ok(msg) => msg,
+                Err(broadcast::error::RecvError::Lagged(n)) => {
+                    // Only status updates can be among them; the next one catches up
+                    eprintln!("Live state missed {} broadcast messages", n);
+                    continue;
+                }
+                Err(broadcast::error::RecvError::Closed) => break,
+            },
+            _ = ticks.tick() => {
+                let mut state = state.lock().unwrap();
+                if let Some(session) = state.tick() {
+                    println!("Chequered flag: time limit reached");
+                    let _ = tx.send(WsMessage::Session(session));
+                }
+                continue;
+            }
+        };
+
+        // What a change leads to is sent before the lock is released; see [`subscribe`]
+        let mut state = state.lock().unwrap();
+        match msg {
+            WsMessage::Passing(passing) => {
+                let _ = tx.send(WsMessage::Passing(passing.clone()));
+                for msg in state.record_passing(&passing) {
+                    match &msg {
+                        WsMessage::Suppressed(read) => {
+                            println!("Suppressed read of {} ({:?}): {}", read.transponder, read.reason, read.detail)
+                        }
+                        WsMessage::Session(session) => println!("Session is {:?}", session.phase),
+                        _ => {}
+                    }
+                    let _ = tx.send(msg);
+                }
+            }
+            WsMessage::Status(status) => {
+                if let Some(alert) = state.record_status(&status) {
+                    let _ = tx.send(WsMessage::Alert(alert));
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::session::{Limit, SessionKind, SessionPhase};
+    use crate::testing::passing;
+
+    #[test]
+    fn test_journal_restores_session_after_restart() {
+        let path = std::path::PathBuf::from("test_state_journal.jsonl");
+        let _ = std::fs::remove_file(&path);
+
+        // The read at 10s is too quick for these rules but would be a lap by default
+        let rules = LapRules { min_lap_time: 20.0, ..LapRules::default() };
+        let mut state = LiveState::default();
+        state.set_rules(rules.clone());
+        state.open_journal(&path).unwrap();
+        state.record_passing(&passing("9", 0));
+        let settings = SessionSettings { kind: SessionKind::Race, limit: Some(Limit::Laps(2)), ..Default::default() };
+        state.control_session(SessionControl::New(settings)).unwrap();
+        for (transponder, secs) in [("1", 0), ("2", 1), ("1", 10), ("1", 30), ("2", 32), ("1", 60)] {
+            state.record_passing(&passing(transponder, secs * 1000));
+        }
+        let before = state.snapshot();
+        drop(state);
+
+        // The passing before the new session is kept but not replayed
+        let mut restored = LiveState::default();
+        restored.set_rules(rules);
+        assert_eq!(restored.open_journal(&path).unwrap(), 7);
+        let after = restored.snapshot();
+        assert_eq!(after.suppressed.len(), 1);
+        assert_eq!(after.session.phase, SessionPhase::Chequered);
+        assert_eq!(after.session.finished, before.session.finished);
+        assert_eq!(after.session_started, before.session_started);
+        assert_eq!(after.standings.entries[0].transponder, "1");
+        assert_eq!(after.standings.entries[1].laps, 1);
+
+        // Carries on where it left off
+        restored.record_passing(&passing("2", 62_000));
+        assert_eq!(restored.session().phase, SessionPhase::Finished);
+        drop(restored);
+        assert_eq!(crate::journal::Journal::open(&path).unwrap().1.len(), 11);
+
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[test]
+    fn test_journal_replays_rule_changes() {
+        let path = std::path::PathBuf::from("test_state_journal_rules.jsonl");
+        let _ = std::fs::remove_file(&path);
+        let strict = LapRules { min_lap_time: 20.0, ..LapRules::default() };
+
+        let mut state = LiveState::default();
+        state.set_rules(strict.clone());
+        state.open_journal(&path).unwrap();
+        for secs in [0, 10, 30] {
+            state.record_passing(&passing("1", secs * 1000));
+        }
+        // Relaxed mid-session, e.g. from the manage page
+        state.set_rules(LapRules::default());
+        state.record_passing(&passing("1", 35_000));
+        let before = state.snapshot();
+        drop(state);
+
+        // Started again with the strict rules configured
+        let mut restored = LiveState::default();
+        restored.set_rules(strict);
+        restored.open_journal(&path).unwrap();
+        let after = restored.snapshot();
+        assert_eq!(after.transponders[0].laps, 2);
+        assert_eq!(after.transponders[0].laps, before.transponders[0].laps);
+        assert_eq!(after.suppressed.len(), 1);
+
+        // The configured rules apply from here on
+        restored.record_passing(&passing("1", 40_000));
+        assert_eq!(restored.snapshot().suppressed.len(), 2);
+        drop(restored);
+        let entries = crate::journal::Journal::open(&path).unwrap().1;
+        assert!(matches!(&entries[entries.len() - 2], JournalEntry::Rules(rules) if rules.min_lap_time == 20.0));
+
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[test]
+    fn test_restart_keeps_the_session_that_was_never_replaced() {
+        let path = std::path::PathBuf::from("test_state_journal_implicit.jsonl");
+        let _ = std::fs::remove_file(&path);
+        let db = Arc::new(Database::in_memory().unwrap());
+
+        let mut state = LiveState::default();
+        state.open_journal(&path).unwrap();
+        state.attach_database(db.clone()).unwrap();
+        state.record_passing(&passing("1", 0));
+        state.record_passing(&passing("1", 30_000));
+        let before = state.snapshot();
+        drop(state);
+
+        let mut restored = LiveState::default();
+        assert_eq!(restored.open_journal(&path).unwrap(), 3);
+        restored.attach_database(db.clone()).unwrap();
+        assert_eq!(restored.snapshot().session_started, before.session_started);
+        assert_eq!(restored.snapshot().transponders[0].laps, 1);
+        drop(restored);
+
+        let filter = crate::db::HistoryFilter { limit: 10, ..Default::default() };
+        assert_eq!(db.sessions(&filter).unwrap().total, 1);
+
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_subscribe_sees_each_lap_once() {
+        let (tx, _rx) = broadcast::channel(1024);
+        let state = LiveState::shared();
+        let passings = spawn(tx.clone(), state.clone());
+
+        let feeder = tokio::spawn(async move {
+            for secs in 0..200 {
+                let _ = passings.send(passing("1", secs * 10_000));
+                tokio::task::yield_now().await;
+            }
+        });
+        // Join mid-feed, as a display opening during a race would
+        tokio::time::sleep(Duration::from_millis(2)).await;
+        let (mut rx, snapshot) = subscribe(&tx, &state);
+        feeder.await.unwrap();
+
+        let laps_in_snapshot = snapshot.transponders.iter().map(|t| t.laps).sum::<u32>();
+        let mut laps_received = 0;
+        let expected = loop {
+            let laps = state.lock().unwrap().snapshot().transponders.iter().map(|t| t.laps).sum::<u32>();
+            while let Ok(msg) = rx.try_recv() {
+                laps_received += matches!(msg, WsMessage::Lap(_)) as u32;
+            }
+            if laps == 199 {
+                break laps;
+            }
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        };
+        while let Ok(msg) = rx.try_recv() {
+            laps_received += matches!(msg, WsMessage::Lap(_)) as u32;
+        }
+        assert_eq!(laps_in_snapshot + laps_received, expected);
+    }
+
+    #[tokio::test]
+    async fn test_counts_every_passing_when_the_broadcast_overflows() {
+        // Far more passings than the broadcast channel holds, sent before the live state runs
+        let (tx, _rx) = broadcast::channel(4);
+        let state = LiveState::shared();
+        let passings = spawn(tx.clone(), state.clone());
+        for secs in 0..100 {
+            passings.send(passing("1", secs * 30_000)).unwrap();
+        }
+
+        for _ in 0..100 {
+            if state.lock().unwrap().snapshot().transponders.first().is_some_and(|t| t.laps == 99) {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        panic!("passings were lost: {:?}", state.lock().unwrap().snapshot().transponders);
+    }
+}
@@ -0,0 +1,126 @@
+use serde::{Deserialize, Serialize};
+use std::sync::{Arc, Mutex};
+use tokio::sync::broadcast;
+
+use crate::messages::{self, timestamp, Passing, Timestamp, WsMessage};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SourceState {
+    /// Opening the port / socket, or waiting for a client to connect.
+    Connecting,
+    /// Link is up, protocol setup (time sync, push passings) in progress.
+    Handshaking,
+    Connected,
+    /// Link lost or could not be opened, will try again shortly.
+    Retrying,
+    /// Gave up; needs a configuration change.
+    Failed,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct SourceStatus {
+    pub source: String,
+    pub state: SourceState,
+    pub last_error: Option<String>,
+    /// When `state` last changed.
+    #[serde(with = "timestamp")]
+    pub since: Timestamp,
+    #[serde(with = "timestamp::option")]
+    pub last_passing: Option<Timestamp>,
+}
+
+impl SourceStatus {
+    pub fn is_connected(&self) -> bool {
+        self.state == SourceState::Connected
+    }
+}
+
+/// Shared, broadcasting view of a timing source's connection state.
+#[derive(Clone)]
+pub struct StatusHandle {
+    inner: Arc<Mutex<SourceStatus>>,
+    tx: broadcast::Sender<WsMessage>,
+}
+
+impl StatusHandle {
+    pub fn new(source: impl Into<String>, tx: broadcast::Sender<WsMessage>) -> Self {
+        Self {
+            inner: Arc::new(Mutex::new(SourceStatus {
+                source: source.into(),
+                state: SourceState::Connecting,
+                last_error: None,
+                since: messages::now(),
+                last_passing: None,
+            })),
+            tx,
+        }
+    }
+
+    pub fn snapshot(&self) -> SourceStatus {
+        self.inner.lock().unwrap().clone()
+    }
+
+    pub fn set_state(&self, state: SourceState) {
+        self.update(|status| {
+            if status.state == state {
+                return false;
+            }
+            status.state = state;
+            status.since = messages::now();
+            true
+        });
+    }
+
+    /// Moves to `state` and records why.
+    pub fn set_error(&self, state: SourceState, error: impl ToString) {
+        let error = error.to_string();
+        self.update(|status| {
+            if status.state != state {
+                status.since = messages::now();
+            }
+            status.state = state;
+            status.last_error = Some(error);
+            true
+        });
+    }
+
+    /// Starts over for a newly configured source.
+    pub fn restart(&self, source: impl Into<String>) {
+        let source = source.into();
+        self.update(|status| {
+            *status = SourceStatus {
+                source,
+                state: SourceState::Connecting,
+                last_error: None,
+                since: messages::now(),
+                last_passing: None,
+            };
+            true
+        });
+    }
+
+    /// Remembers the passing time; not broadcast, the passing itself is.
+    pub fn record_passing(&self, passing: &Passing) {
+        self.inner.lock().unwrap().last_passing = Some(passing.timestamp);
+    }
+
+    fn update(&self, f: impl FnOnce(&mut SourceStatus) -> bool) {
+        let snapshot = {
+            let mut status = self.inner.lock().unwrap();
+            if !f(&mut status) {
+                return;
+            }
+            status.clone()
+        };
+
+        match &snapshot.last_error {
+            Some(e) if !snapshot.is_connected() => {
+                println!("Source {} is {:?}: {}", snapshot.source, snapshot.state, e)
+            }
+            _ => println!("Source {} is {:?}", snapshot.source, snapshot.state),
+        }
+        // No receivers just means no display is open yet
+        let _ = self.tx.send(WsMessage::Status(snapshot));
+    }
+}
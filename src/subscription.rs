@@ -0,0 +1,159 @@
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+use std::collections::BTreeSet;
+
+use crate::config::Groups;
+use crate::messages::{MessageKind, Passing, WireFormat, WsMessage};
+use crate::state::Snapshot;
+
+/// Filters as sent by a client, either in the feed query string or in a
+/// `subscribe` command. Omitted fields mean "everything".
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct SubscriptionRequest {
+    pub types: Option<Vec<MessageKind>>,
+    pub transponders: Option<Vec<String>>,
+    pub groups: Option<Vec<String>>,
+    pub timing_points: Option<Vec<String>>,
+}
+
+impl SubscriptionRequest {
+    /// Checks the request and expands groups into their transponders.
+    pub fn resolve(self, groups: &Groups) -> Result<Subscription, String> {
+        if matches!(&self.types, Some(types) if types.is_empty()) {
+            return Err("types needs at least one message type".to_string());
+        }
+
+        let mut transponders: Option<BTreeSet<String>> = self.transponders.map(|t| t.into_iter().collect());
+        if let Some(names) = &self.groups {
+            let selected = transponders.get_or_insert_with(BTreeSet::new);
+            for name in names {
+                let members = groups.get(name).ok_or_else(|| format!("Unknown group '{}'", name))?;
+                selected.extend(members.iter().cloned());
+            }
+        }
+
+        Ok(Subscription {
+            types: self.types.map(|t| t.into_iter().collect()),
+            transponders,
+            groups: self.groups.unwrap_or_default(),
+            timing_points: self.timing_points.map(|t| t.into_iter().collect()),
+        })
+    }
+}
+
+/// What a single WebSocket client wants to receive.
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct Subscription {
+    /// `None` means every message type.
+    pub types: Option<BTreeSet<MessageKind>>,
+    /// Explicit transponders plus all members of `groups`; `None` means all.
+    pub transponders: Option<BTreeSet<String>>,
+    pub groups: Vec<String>,
+    pub timing_points: Option<BTreeSet<String>>,
+}
+
+impl Subscription {
+    /// Returns the message as this client should see it, or `None` to skip it.
+    /// Snapshots and standings are trimmed down to the subscribed transponders;
+    /// standings keep the overall positions.
+    pub fn apply<'a>(&self, msg: &'a WsMessage) -> Option<Cow<'a, WsMessage>> {
+        if let Some(types) = &self.types {
+            if !types.contains(&msg.kind()) {
+                return None;
+            }
+        }
+
+        match msg {
+            WsMessage::Passing(p) if !self.wants_passing(p) => None,
+            WsMessage::Lap(lap) if !self.wants_read(&lap.transponder, &lap.timing_point) => None,
+            WsMessage::Suppressed(read) if !self.wants_read(&read.transponder, &read.timing_point) => None,
+            WsMessage::Standings(standings) if self.transponders.is_some() => {
+                let mut standings = standings.clone();
+                standings.entries.retain(|s| self.wants_transponder(&s.transponder));
+                Some(Cow::Owned(WsMessage::Standings(standings)))
+            }
+            WsMessage::Snapshot(snapshot) if self.is_filtered() => {
+                Some(Cow::Owned(WsMessage::Snapshot(self.filter_snapshot(snapshot))))
+            }
+            _ => Some(Cow::Borrowed(msg)),
+        }
+    }
+
+    fn is_filtered(&self) -> bool {
+        self.transponders.is_some() || self.timing_points.is_some()
+    }
+
+    fn wants_transponder(&self, transponder: &str) -> bool {
+        self.transponders.as_ref().is_none_or(|t| t.contains(transponder))
+    }
+
+    fn wants_passing(&self, passing: &Passing) -> bool {
+        self.wants_read(&passing.transponder, &passing.timing_point)
+    }
+
+    fn wants_read(&self, transponder: &str, timing_point: &Option<String>) -> bool {
+        let at_timing_point = match (&self.timing_points, timing_point) {
+            (Some(points), Some(point)) => points.contains(point),
+            (Some(_), None) => false,
+            (None, _) => true,
+        };
+        at_timing_point && self.wants_transponder(transponder)
+    }
+
+    fn filter_snapshot(&self, snapshot: &Snapshot) -> Snapshot {
+        let mut snapshot = snapshot.clone();
+        snapshot.transponders.retain(|t| self.wants_transponder(&t.transponder));
+        snapshot.standings.entries.retain(|s| self.wants_transponder(&s.transponder));
+        snapshot.recent_passings.retain(|p| self.wants_passing(p));
+        snapshot.suppressed.retain(|r| self.wants_read(&r.transponder, &r.timing_point));
+        snapshot
+    }
+}
+
+/// Query string accepted by `/ws` and `/events`.
+#[derive(Debug, Default, Deserialize)]
+pub struct FeedParams {
+    /// `?format=legacy` keeps the original untagged messages.
+    #[serde(default)]
+    pub format: WireFormat,
+    /// Comma-separated filters, e.g. `?groups=junior&types=passing,status`.
+    types: Option<String>,
+    transponders: Option<String>,
+    groups: Option<String>,
+    timing_points: Option<String>,
+}
+
+impl FeedParams {
+    pub fn subscription(&self, groups: &Groups) -> Result<Subscription, String> {
+        let types = match list(&self.types) {
+            Some(names) => Some(
+                names
+                    .iter()
+                    .map(|name| {
+                        serde_json::from_value::<MessageKind>(serde_json::Value::from(name.as_str()))
+                            .map_err(|_| format!("Unknown message type '{}'", name))
+                    })
+                    .collect::<Result<Vec<_>, _>>()?,
+            ),
+            None => None,
+        };
+
+        SubscriptionRequest {
+            types,
+            transponders: list(&self.transponders),
+            groups: list(&self.groups),
+            timing_points: list(&self.timing_points),
+        }
+        .resolve(groups)
+    }
+}
+
+fn list(param: &Option<String>) -> Option<Vec<String>> {
+    param.as_ref().map(|p| {
+        p.split(',')
+            .map(str::trim)
+            .filter(|s| !s.is_empty())
+            .map(String::from)
+            .collect()
+    })
+}
@@ -0,0 +1,43 @@
+//! Fixtures shared by the unit tests.
+
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use crate::db::{Database, SharedDatabase};
+use crate::messages::{LapTime, Passing, Timestamp};
+use crate::session::{Limit, SessionKind, SessionSettings};
+use crate::state::{LiveState, SessionControl};
+
+/// When test sessions start: 2024-01-12 09:00 at +01:00.
+pub fn start() -> Timestamp {
+    chrono::DateTime::parse_from_rfc3339("2024-01-12T09:00:00+01:00").unwrap()
+}
+
+/// A read of `transponder`, `millis` after [`start`].
+pub fn passing(transponder: &str, millis: i64) -> Passing {
+    Passing {
+        passing_number: 0,
+        transponder: transponder.to_string(),
+        timestamp: start() + LapTime::milliseconds(millis),
+        strength: 0,
+        tran_code: String::new(),
+        noise: 0,
+        hits: 0,
+        timing_point: None,
+    }
+}
+
+/// "Heat 1 / Final", a finished 2-lap race between "Max & Co" (transponder 1)
+/// and an unnamed transponder 2, stored through the live state.
+pub fn finished_race() -> SharedDatabase {
+    let db = Arc::new(Database::in_memory().unwrap());
+    db.save_mapping(&HashMap::from([("1".to_string(), "Max & Co".to_string())])).unwrap();
+    let mut state = LiveState::default();
+    state.attach_database(db.clone()).unwrap();
+    let settings = SessionSettings { name: "Heat 1 / Final".to_string(), kind: SessionKind::Race, limit: Some(Limit::Laps(2)), ..Default::default() };
+    state.control_session(SessionControl::New(settings)).unwrap();
+    for (transponder, second) in [("1", 0), ("2", 1), ("1", 30), ("2", 32), ("1", 58), ("2", 59)] {
+        state.record_passing(&passing(transponder, second * 1000));
+    }
+    db
+}
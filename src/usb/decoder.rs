@@ -1,9 +1,6 @@
 use futures::{SinkExt, StreamExt};
-use serde::{Deserialize, Serialize};
 use std::time::Duration;
-use tokio::sync::broadcast;
 use tokio_util::codec::{Framed, LinesCodec};
-use std::sync::{atomic::{AtomicBool, Ordering}, Arc};
 use tokio_serial::{SerialPort, SerialPortBuilderExt, SerialStream};
 
 // Local definitions removed in favor of crate::messages 
@@ -30,7 +27,30 @@ pub struct Passing {
 }
 */
 
-use crate::messages::{WsMessage, Passing};
+use crate::messages::{self, Passing, Timestamp};
+use crate::state::PassingSender;
+use crate::status::{SourceState, StatusHandle};
+
+/// Delay between attempts to (re)open the serial port.
+const RETRY_DELAY: Duration = Duration::from_secs(5);
+/// The box resets when the port opens and ignores commands until its bootloader is done.
+const BOOTLOADER_DELAY: Duration = Duration::from_secs(3);
+/// Longest it takes from opening the port until the box is connected, or the handshake has failed.
+pub const HANDSHAKE_TIME: Duration = Duration::from_secs(4);
+
+/// Opens a USB timing box port with the settings it expects.
+pub fn open(port_name: &str) -> Result<SerialStream, tokio_serial::Error> {
+    #[allow(unused_mut)]
+    let mut port = tokio_serial::new(port_name, 19200).open_native_async()?;
+
+    #[cfg(unix)]
+    port.set_exclusive(false)?;
+
+    // DTR Low to start (avoid reset)
+    port.write_data_terminal_ready(false)?;
+
+    Ok(port)
+}
 
 pub struct UsbBox {
     port_name: String,
@@ -53,48 +73,43 @@ impl UsbBox {
         }
     }
 
-    pub async fn run(mut self, tx: broadcast::Sender<WsMessage>, is_connected: Arc<AtomicBool>) {
-        println!("Opening serial port {}", self.port_name);
-        
-        let mut port = match tokio_serial::new(&self.port_name, 19200).open_native_async() {
-            Ok(p) => p,
-            Err(e) => {
-                eprintln!("Failed to open serial port: {}", e);
-                return;
-            }
-        };
-
-        #[cfg(unix)]
-        port.set_exclusive(false).expect("Unable to set serial port exclusive to false");
-
-        // DTR Low to start (avoid reset)
-        port.write_data_terminal_ready(false).expect("Failed to set DTR low");
+    pub async fn run(mut self, passings: PassingSender, status: StatusHandle) {
+        loop {
+            status.set_state(SourceState::Connecting);
+            println!("Opening serial port {}", self.port_name);
 
-        println!("Connected to serial port");
-        
-        // Status: Connected
-        is_connected.store(true, Ordering::SeqCst);
-        let _ = tx.send(WsMessage::Status { event: "connected".to_string() });
+            match self.open_port() {
+                Ok(port) => {
+                    println!("Connected to serial port");
+                    if let Err(e) = self.handle_connection(port, &passings, &status).await {
+                        eprintln!("Connection error: {}", e);
+                        status.set_error(SourceState::Retrying, e);
+                    }
+                }
+                Err(e) => {
+                    eprintln!("Failed to open serial port: {}", e);
+                    status.set_error(SourceState::Retrying, e);
+                }
+            }
 
-        if let Err(e) = self.handle_connection(port, &tx).await {
-            eprintln!("Connection error: {}", e);
+            tokio::time::sleep(RETRY_DELAY).await;
         }
+    }
 
-        // Status: Disconnected
-        if is_connected.load(Ordering::SeqCst) {
-            is_connected.store(false, Ordering::SeqCst);
-            let _ = tx.send(WsMessage::Status { event: "disconnected".to_string() });
-        }
+    fn open_port(&self) -> Result<SerialStream, tokio_serial::Error> {
+        open(&self.port_name)
     }
 
     async fn handle_connection(
         &mut self,
-        mut port: SerialStream,
-        tx: &broadcast::Sender<WsMessage>,
+        port: SerialStream,
+        passings: &PassingSender,
+        status: &StatusHandle,
     ) -> Result<(), Box<dyn std::error::Error>> {
-        // Wait 3 seconds for bootloader
-        println!("Waiting 3s for bootloader...");
-        tokio::time::sleep(Duration::from_secs(3)).await;
+        status.set_state(SourceState::Handshaking);
+
+        println!("Waiting {}s for bootloader...", BOOTLOADER_DELAY.as_secs());
+        tokio::time::sleep(BOOTLOADER_DELAY).await;
 
         let mut framed = Framed::new(port, LinesCodec::new());
 
@@ -120,6 +135,7 @@ impl UsbBox {
         
         // Give it a moment
         tokio::time::sleep(Duration::from_millis(100)).await;
+        status.set_state(SourceState::Connected);
 
         // Step 4: Get existing passings (just in case)
         // framed.send("PASSINGGET;00000000").await?; // We'll let the loop handle this
@@ -139,7 +155,7 @@ impl UsbBox {
                 msg = framed.next() => {
                     match msg {
                         Some(Ok(msg)) => {
-                            self.process_message(&msg, tx);
+                            self.process_message(&msg, passings, status);
                         }
                         Some(Err(e)) => return Err(Box::new(e)),
                         None => return Err("Connection closed".into()),
@@ -149,7 +165,7 @@ impl UsbBox {
         }
     }
 
-    fn process_message(&mut self, msg: &str, tx: &broadcast::Sender<WsMessage>) {
+    fn process_message(&mut self, msg: &str, passings: &PassingSender, status: &StatusHandle) {
         // println!("Received: {}", msg);
         let parts: Vec<&str> = msg.split(';').collect();
         if parts.is_empty() {
@@ -234,52 +250,71 @@ impl UsbBox {
             // Increment passing index for every valid passing received
             self.next_passing_index += 1;
             
-            let mut date_str = "".to_string();
-            let mut time_str = "".to_string();
-            
-            if let Ok(ts_ticks) = u64::from_str_radix(timestamp_hex, 16) {
-                // Calculate real time
-                // Computer_Time = ref_computer_time + ((pass_time_stamp - ref_time_stamp) / 256.0)
-                if let (Some(ref_comp), Some(ref_int)) = (self.ref_computer_time, self.ref_internal_time) {
-                    // Use signed arithmetic to handle passings before the sync point (stored passings)
-                    let diff_ticks = (ts_ticks as i64) - (ref_int as i64);
-                    
-                    // Ticks are 1/256 sec (Standard Format)
-                    let diff_seconds = diff_ticks as f64 / 256.0;
-                    let passing_time_unix = ref_comp as f64 + diff_seconds;
-                    
-                    let secs = passing_time_unix as i64;
-                    let nsecs = ((passing_time_unix - secs as f64) * 1_000_000_000.0) as u32;
-                    
-                    if let Some(dt) = chrono::DateTime::from_timestamp(secs, nsecs) {
-                        let local_dt: chrono::DateTime<chrono::Local> = chrono::DateTime::from(dt);
-                        date_str = local_dt.format("%Y-%m-%dT%H:%M:%S.%3f").to_string(); // ISO-ish
-                        time_str = local_dt.format("%H:%M:%S.%3f").to_string();
-                    }
-                } else {
+            let timestamp = match u64::from_str_radix(timestamp_hex, 16) {
+                Ok(ts_ticks) => match (self.ref_computer_time, self.ref_internal_time) {
+                    (Some(ref_comp), Some(ref_int)) => ticks_to_timestamp(ref_comp, ref_int, ts_ticks),
                     // Fallback to current time if sync not yet established
-                    let now = chrono::Local::now();
-                    date_str = now.format("%Y-%m-%dT%H:%M:%S.%3f").to_string();
-                    time_str = now.format("%H:%M:%S.%3f").to_string();
-                }
+                    _ => None,
+                },
+                Err(_) => None,
             }
+            .unwrap_or_else(messages::now);
 
             let passing = Passing {
                 passing_number: self.next_passing_index as u32,
                 transponder,
-                rtc_time: format!("{}T{}", date_str, time_str),
+                timestamp,
                 strength: u32::from_str_radix(parts[4], 16).unwrap_or(0),
                 tran_code: parts[11].to_string(),
                 noise: 0, // Not provided by USB protocol explicitly?
                 hits: u32::from_str_radix(parts[3], 16).unwrap_or(0),
-                date: date_str,
-                time: time_str,
+                timing_point: Some(parts[8].to_string()).filter(|loop_id| !loop_id.is_empty()),
             };
             
             println!("Passing: {:?}", passing);
-            if let Err(e) = tx.send(WsMessage::Passing(passing)) {
-                eprintln!("Error broadcasting passing: {}", e);
+            status.record_passing(&passing);
+            if let Err(e) = passings.send(passing) {
+                eprintln!("Error recording passing: {}", e);
             }
         }
     }
 }
+
+/// Computer_Time = ref_computer_time + ((pass_time_stamp - ref_time_stamp) / 256.0)
+///
+/// Ticks are 1/256 sec (Standard Format), i.e. exactly 3_906_250 ns each, so the
+/// offset is computed in integer nanoseconds to keep sub-millisecond precision.
+/// Signed arithmetic handles passings stored before the sync point.
+fn ticks_to_timestamp(ref_comp: i64, ref_int: u64, ts_ticks: u64) -> Option<Timestamp> {
+    let diff_ticks = (ts_ticks as i64) - (ref_int as i64);
+    let reference = chrono::DateTime::from_timestamp(ref_comp, 0)?;
+    let passing_time = reference + chrono::TimeDelta::nanoseconds(diff_ticks.checked_mul(3_906_250)?);
+    Some(messages::to_local(passing_time))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn utc(ts: Timestamp) -> String {
+        ts.with_timezone(&chrono::Utc).format("%Y-%m-%dT%H:%M:%S%.6f").to_string()
+    }
+
+    #[test]
+    fn test_ticks_to_timestamp() {
+        // 2024-01-12T09:06:35Z synced at tick 1000
+        let reference = 1_705_050_395;
+        assert_eq!(utc(ticks_to_timestamp(reference, 1000, 1000).unwrap()), "2024-01-12T09:06:35.000000");
+        assert_eq!(utc(ticks_to_timestamp(reference, 1000, 1000 + 256 * 5 + 1).unwrap()), "2024-01-12T09:06:40.003906");
+        assert_eq!(utc(ticks_to_timestamp(reference, 1000, 1000 - 128).unwrap()), "2024-01-12T09:06:34.500000");
+    }
+
+    #[test]
+    fn test_ticks_to_timestamp_across_midnight() {
+        // synced at 2024-01-12T23:59:59Z; two seconds later is the next day
+        let reference = 1_705_103_999;
+        assert_eq!(utc(ticks_to_timestamp(reference, 0, 512).unwrap()), "2024-01-13T00:00:01.000000");
+        // and a passing stored before a sync just after midnight lands on the day before
+        assert_eq!(utc(ticks_to_timestamp(reference + 1, 512, 0).unwrap()), "2024-01-12T23:59:58.000000");
+    }
+}
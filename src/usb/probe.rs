@@ -0,0 +1,66 @@
+use futures::StreamExt;
+use std::error::Error;
+use std::time::Duration;
+use tokio_serial::SerialPortType;
+use tokio_util::codec::{Framed, LinesCodec};
+
+use super::decoder;
+
+/// Without a port, lists the serial ports found. With one, opens it the way
+/// the USB source does and prints whatever arrives for `seconds`, without
+/// sending any commands to the box.
+pub async fn run(port: Option<String>, seconds: u64) -> Result<(), Box<dyn Error>> {
+    let Some(port_name) = port else {
+        return list_ports();
+    };
+
+    let port = decoder::open(&port_name)?;
+    println!("Opened {}, listening for {}s...", port_name, seconds);
+
+    let mut framed = Framed::new(port, LinesCodec::new());
+    let deadline = tokio::time::sleep(Duration::from_secs(seconds));
+    tokio::pin!(deadline);
+
+    let mut lines = 0;
+    loop {
+        tokio::select! {
+            _ = &mut deadline => break,
+            msg = framed.next() => match msg {
+                Some(Ok(line)) => {
+                    println!("< {}", line);
+                    lines += 1;
+                }
+                Some(Err(e)) => return Err(Box::new(e)),
+                None => return Err("Port closed".into()),
+            }
+        }
+    }
+
+    if lines == 0 {
+        println!("Port works, but nothing was received. The box only pushes passings once the USB source has set it up.");
+    } else {
+        println!("Received {} lines", lines);
+    }
+    Ok(())
+}
+
+fn list_ports() -> Result<(), Box<dyn Error>> {
+    let ports = tokio_serial::available_ports()?;
+    if ports.is_empty() {
+        println!("No serial ports found");
+    }
+    for port in ports {
+        match port.port_type {
+            SerialPortType::UsbPort(usb) => println!(
+                "{}  USB {:04x}:{:04x} {} {}",
+                port.port_name,
+                usb.vid,
+                usb.pid,
+                usb.manufacturer.unwrap_or_default(),
+                usb.product.unwrap_or_default()
+            ),
+            _ => println!("{}", port.port_name),
+        }
+    }
+    Ok(())
+}
@@ -1,70 +1,179 @@
-use crate::messages::WsMessage;
+use crate::clients::{ClientGuard, ClientRegistry};
+use crate::commands;
+use crate::events::EventLog;
+use crate::messages::{WireFormat, WsMessage};
+use crate::state::{self, SharedState};
+use crate::sources::SharedGroups;
+use crate::status::StatusHandle;
+use crate::subscription::{FeedParams, Subscription};
+use futures::stream::SplitSink;
 use futures::{SinkExt, StreamExt};
-use std::sync::{atomic::{AtomicBool, Ordering}, Arc};
+use std::net::SocketAddr;
+use std::time::Duration;
 use tokio::sync::broadcast;
+use tokio::time::Instant;
 use warp::Filter;
 
-pub fn ws_routes(
-    tx: broadcast::Sender<WsMessage>,
-    is_connected: Arc<AtomicBool>,
-) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
-    let tx = warp::any().map(move || tx.clone());
-    let is_connected = warp::any().map(move || is_connected.clone());
+/// How often idle clients are pinged.
+const PING_INTERVAL: Duration = Duration::from_secs(10);
+/// A client that sends nothing (not even a pong) for this long is dropped.
+const CLIENT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Shared handles every live feed connection (`/ws` and `/events`) needs.
+#[derive(Clone)]
+pub struct WsContext {
+    pub tx: broadcast::Sender<WsMessage>,
+    pub status: StatusHandle,
+    pub state: SharedState,
+    pub clients: ClientRegistry,
+    /// Shared with the source manager, so saved groups apply to new subscriptions.
+    pub groups: SharedGroups,
+    pub events: EventLog,
+}
+
+pub fn ws_routes(ctx: WsContext) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+    let ctx = warp::any().map(move || ctx.clone());
 
     warp::path("ws")
         .and(warp::ws())
-        .and(tx)
-        .and(is_connected)
-        .map(|ws: warp::ws::Ws, tx, is_connected| {
-            ws.on_upgrade(move |socket| handle_connection(socket, tx, is_connected))
-        })
+        .and(warp::query::<FeedParams>())
+        .and(warp::addr::remote())
+        .and(warp::header::optional::<String>("user-agent"))
+        .and(ctx)
+        .map(
+            |ws: warp::ws::Ws,
+             params: FeedParams,
+             addr: Option<SocketAddr>,
+             user_agent: Option<String>,
+             ctx: WsContext| {
+                let subscription = match params.subscription(&ctx.groups.read().unwrap()) {
+                    Ok(subscription) => subscription,
+                    Err(e) => {
+                        return Box::new(warp::reply::with_status(e, warp::http::StatusCode::BAD_REQUEST))
+                            as Box<dyn warp::Reply>;
+                    }
+                };
+                let client = ctx.clients.register(addr, user_agent, params.format);
+                Box::new(ws.on_upgrade(move |socket| {
+                    handle_connection(socket, params.format, subscription, client, ctx)
+                }))
+            },
+        )
 }
 
 async fn handle_connection(
     ws: warp::ws::WebSocket,
-    tx: broadcast::Sender<WsMessage>,
-    is_connected: Arc<AtomicBool>,
+    format: WireFormat,
+    mut subscription: Subscription,
+    client: ClientGuard,
+    ctx: WsContext,
 ) {
-    let (mut ws_tx, _) = ws.split();
-    let mut rx = tx.subscribe();
+    let WsContext { tx, status, state, groups, .. } = ctx;
+    let (mut ws_tx, mut ws_rx) = ws.split();
+    let (mut rx, snapshot) = state::subscribe(&tx, &state);
 
-    println!("New WebSocket client connected");
+    let mut ping_interval = tokio::time::interval_at(Instant::now() + PING_INTERVAL, PING_INTERVAL);
+    let mut last_seen = Instant::now();
+
+    println!("New WebSocket client {} connected ({:?} format)", client.id(), format);
 
     // Send initial status
-    let status = if is_connected.load(Ordering::SeqCst) {
-        "connected"
-    } else {
-        "disconnected"
-    };
-    
-    let initial_msg = WsMessage::Status {
-        event: status.to_string(),
-    };
+    let initial_msg = WsMessage::Status(status.snapshot());
 
-    if let Ok(json) = serde_json::to_string(&initial_msg) {
-        if let Err(e) = ws_tx.send(warp::ws::Message::text(json)).await {
-            eprintln!("WebSocket send error (initial status): {}", e);
-            return;
-        }
+    if let Err(e) = send_filtered(&mut ws_tx, &initial_msg, &subscription, format).await {
+        eprintln!("WebSocket send error (initial status): {}", e);
+        return;
     }
 
-    while let Ok(msg) = rx.recv().await {
-        if let Ok(json) = serde_json::to_string(&msg) {
-            if let Err(e) = ws_tx.send(warp::ws::Message::text(json)).await {
-                // Ignore broken pipe or connection reset errors, as they just mean the client disconnected
-                if is_disconnect_error(&e) {
+    // Bring late joiners up to date with the running session
+    if let Err(e) = send_filtered(&mut ws_tx, &WsMessage::Snapshot(snapshot), &subscription, format).await {
+        eprintln!("WebSocket send error (snapshot): {}", e);
+        return;
+    }
+
+    loop {
+        tokio::select! {
+            msg = rx.recv() => {
+                let result = match msg {
+                    Ok(msg) => match subscription.apply(&msg) {
+                        Some(msg) => send(&mut ws_tx, &msg, format).await,
+                        None => continue,
+                    },
+                    Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                        // Too slow to keep up; tell the client and resync it from the live state,
+                        // carrying on from a fresh receiver so nothing in the snapshot comes again
+                        println!("WebSocket client lagged behind by {} messages, resyncing", skipped);
+                        let snapshot;
+                        (rx, snapshot) = state::subscribe(&tx, &state);
+                        match send(&mut ws_tx, &WsMessage::Lagged { skipped }, format).await {
+                            Ok(()) => send_filtered(&mut ws_tx, &WsMessage::Snapshot(snapshot), &subscription, format).await,
+                            Err(e) => Err(e),
+                        }
+                    }
+                    Err(broadcast::error::RecvError::Closed) => break,
+                };
+                if let Err(e) = result {
+                    // Any send failure means the client is gone
+                    println!("WebSocket client {} send failed: {}", client.id(), e);
+                    break;
+                }
+            }
+            _ = ping_interval.tick() => {
+                if last_seen.elapsed() > CLIENT_TIMEOUT {
+                    println!("WebSocket client {} timed out, dropping", client.id());
+                    break;
+                }
+                if let Err(e) = ws_tx.send(warp::ws::Message::ping(Vec::new())).await {
+                    println!("WebSocket client {} ping failed: {}", client.id(), e);
+                    break;
+                }
+            }
+            incoming = ws_rx.next() => {
+                let frame = match incoming {
+                    Some(Ok(frame)) => frame,
+                    Some(Err(e)) => {
+                        println!("WebSocket client {} receive failed: {}", client.id(), e);
+                        break;
+                    }
+                    None => break,
+                };
+                last_seen = Instant::now();
+                client.touch();
+                if frame.is_close() {
+                    break;
+                }
+                let Ok(text) = frame.to_str() else { continue };
+
+                let reply = commands::handle(text, &state, &tx, &groups.read().unwrap(), &mut subscription);
+                if let Err(e) = send(&mut ws_tx, &WsMessage::Reply(reply), format).await {
+                    eprintln!("WebSocket send error (reply): {}", e);
                     break;
                 }
-                eprintln!("WebSocket send error: {}", e);
-                break;
             }
         }
     }
-    println!("WebSocket client disconnected");
+    println!("WebSocket client {} disconnected", client.id());
 }
 
-fn is_disconnect_error(e: &warp::Error) -> bool {
-    let msg = e.to_string();
-    // println!("DEBUG: Checking error: '{}'", msg);
-    msg.contains("Broken pipe") || msg.contains("Connection reset") || msg.contains("os error 32") || msg.contains("os error 54")
+async fn send_filtered(
+    ws_tx: &mut SplitSink<warp::ws::WebSocket, warp::ws::Message>,
+    msg: &WsMessage,
+    subscription: &Subscription,
+    format: WireFormat,
+) -> Result<(), warp::Error> {
+    match subscription.apply(msg) {
+        Some(msg) => send(ws_tx, &msg, format).await,
+        None => Ok(()),
+    }
+}
+
+async fn send(
+    ws_tx: &mut SplitSink<warp::ws::WebSocket, warp::ws::Message>,
+    msg: &WsMessage,
+    format: WireFormat,
+) -> Result<(), warp::Error> {
+    match msg.encode(format) {
+        Some(json) => ws_tx.send(warp::ws::Message::text(json)).await,
+        None => Ok(()),
+    }
 }
@@ -1,15 +1,20 @@
 // Configuration
-const WS_URL = 'ws://localhost:8080/ws';
+// Same host and port the page was served from, so LAN displays work too
+const WS_URL = `${location.protocol === 'https:' ? 'wss' : 'ws'}://${location.host}/ws`;
+const PROTOCOL_VERSION = 1;
 
 // State
 const transponders = new Map(); // code -> { lastPassingTime: Date, lastLapTime: string, lapCount: number }
 const transponderNames = new Map(); // code -> name
+const standings = new Map(); // code -> { position, laps, gap, interval, laps_down, counted_laps, finished }
+let ranking = 'laps';
 let ws = null;
 let reconnectInterval = null;
 
 // DOM Elements
 const statusIndicator = document.getElementById('status-indicator');
 const transponderList = document.getElementById('transponder-list');
+const sessionBanner = document.getElementById('session-banner');
 const mappingFileInput = document.getElementById('mapping-file');
 
 // Load Mapping on Start
@@ -47,6 +52,23 @@ function setStatus(status) {
     statusIndicator.className = 'status-indicator ' + status;
 }
 
+// Source status: { source, state, last_error, since, last_passing }
+function updateSourceStatus(source) {
+    let detail = `${source.source}: ${source.state}`;
+    if (source.state === 'connected') {
+        setStatus(Status.CONNECTED_READY);
+        detail += source.last_passing
+            ? `, last passing ${new Date(source.last_passing).toLocaleTimeString()}`
+            : ', no passings yet';
+    } else {
+        setStatus(Status.CONNECTED_NO_DATA);
+        if (source.last_error) {
+            detail += ` (${source.last_error})`;
+        }
+    }
+    statusIndicator.title = detail;
+}
+
 // Time Formatting
 function formatTime(ms) {
     let seconds = ms / 1000;
@@ -57,26 +79,14 @@ function formatTime(ms) {
 }
 
 // Transponder Management
+// Laps are computed by the server; passings only make new transponders show up right away
 function updateTransponder(data) {
     const code = data.transponder;
-    const passingTime = new Date(data.date);
-
-    // Debug log
-    console.log(`Received passing for ${code}:`, data.date, passingTime);
-
-    let lapTime = null;
+    const passingTime = new Date(data.timestamp);
 
-    // Handle Impulse Marker (Start Signal)
+    // Impulse marker (start signal) is not a competitor
     if (code === "00000127") {
-        console.log("Impulse Marker received at:", passingTime);
-        // Update all existing transponders' lastPassingTime to this marker time
-        for (const [tCode, tData] of transponders.entries()) {
-            tData.lastPassingTime = passingTime;
-            // Reset lap counts on start signal? Usually yes for a new race, 
-            // but let's keep it simple: just sync time.
-            // If user wants reset, they can refresh page.
-        }
-        return; // Do not display the marker itself
+        return;
     }
 
     // Hide title if visible
@@ -86,37 +96,117 @@ function updateTransponder(data) {
     }
 
     if (transponders.has(code)) {
-        const lastData = transponders.get(code);
-        const diff = passingTime - lastData.lastPassingTime;
-
-        // Lower debounce to 500ms to allow fast laps (mock decoder is 1s)
-        if (diff > 500) {
-            lapTime = formatTime(diff);
-            // Increment lap count
-            const newLapCount = (lastData.lapCount || 0) + 1;
-
-            transponders.set(code, {
-                lastPassingTime: passingTime,
-                lastLapTime: lapTime,
-                lapCount: newLapCount
-            });
-            updateDOM(code, lapTime, newLapCount);
-        } else {
-            transponders.get(code).lastPassingTime = passingTime;
-        }
+        transponders.get(code).lastPassingTime = passingTime;
     } else {
-        // First passing
+        // First passing opens the first lap
         transponders.set(code, {
             lastPassingTime: passingTime,
             lastLapTime: '0.00',
-            lapCount: 0 // Start at 0 or 1? Usually 0 completed laps if this is start line interaction? 
-            // Or if it's first detection. Let's say 0 laps completed (waiting for first full lap).
-            // Or is this a finish line passing? If 0, then 1st lap starts.
+            lapCount: 0
         });
         updateDOM(code, '0.00', 0);
     }
 }
 
+// Completed lap from the server: { transponder, lap_number, lap_time, best_lap, is_best, total_time, timestamp }
+function updateLap(lap) {
+    const lapTime = formatTime(lap.lap_time * 1000);
+    transponders.set(lap.transponder, {
+        lastPassingTime: new Date(lap.timestamp),
+        lastLapTime: lapTime,
+        lapCount: lap.lap_number
+    });
+    updateDOM(lap.transponder, lapTime, lap.lap_number);
+}
+
+// Server leaderboard, leader first
+function updateStandings(data) {
+    ranking = data.ranking;
+    standings.clear();
+    for (const entry of data.entries) {
+        standings.set(entry.transponder, entry);
+        const info = document.querySelector(`#transponder-${entry.transponder} .lap-info`);
+        if (info) {
+            info.textContent = lapInfo(entry.transponder, entry.laps);
+        }
+    }
+}
+
+// "P2 · Laps: 5 · +2.50", "+1 lap" when lapped, plus the counted lap(s) when ranked on lap times
+function lapInfo(code, lapCount) {
+    const entry = standings.get(code);
+    if (!entry) {
+        return `Laps: ${lapCount}`;
+    }
+    let info = `P${entry.position} · Laps: ${lapCount}`;
+    if (entry.laps_down > 0) {
+        info += ` · +${entry.laps_down} lap${entry.laps_down > 1 ? 's' : ''}`;
+    } else if (entry.gap !== null) {
+        info += ` · +${entry.gap.toFixed(2)}`;
+    }
+    const counted = entry.counted_laps;
+    if (ranking !== 'laps' && counted.length === 1) {
+        info += ` · lap ${counted[0]}`;
+    } else if (ranking !== 'laps' && counted.length > 1) {
+        info += ` · laps ${counted[0]}-${counted[counted.length - 1]}`;
+    }
+    if (entry.finished) {
+        info += ' · 🏁';
+    }
+    return info;
+}
+
+function clearTransponders() {
+    transponders.clear();
+    standings.clear();
+    transponderList.innerHTML = '';
+}
+
+// Server snapshot of the running session, sent on (re)connect
+function applySnapshot(snapshot) {
+    clearTransponders();
+    updateStandings(snapshot.standings);
+
+    // Oldest first so the most recent ends up on top
+    const sorted = [...snapshot.transponders]
+        .sort((a, b) => new Date(a.last_passing) - new Date(b.last_passing));
+
+    for (const t of sorted) {
+        const lapTime = t.last_lap !== null ? formatTime(t.last_lap * 1000) : '0.00';
+        transponders.set(t.transponder, {
+            lastPassingTime: new Date(t.last_passing),
+            lastLapTime: lapTime,
+            lapCount: t.laps
+        });
+        updateDOM(t.transponder, lapTime, t.laps);
+    }
+    updateSession(snapshot.session);
+
+    const title = document.querySelector('h1');
+    if (title && sorted.length > 0) {
+        title.style.display = 'none';
+    }
+}
+
+// Session: { name, kind, start, limit, phase, started_at, chequered_at, finished_at, finished }
+function updateSession(session) {
+    const parts = [session.name || session.kind.charAt(0).toUpperCase() + session.kind.slice(1)];
+    if (session.limit && session.limit.laps) {
+        parts.push(`${session.limit.laps} laps`);
+    } else if (session.limit && session.limit.time) {
+        parts.push(`${Math.round(session.limit.time / 60)} min`);
+    }
+    const phases = {
+        waiting: 'Waiting for start',
+        running: 'Running',
+        chequered: 'Chequered flag',
+        finished: 'Finished'
+    };
+    parts.push(phases[session.phase] || session.phase);
+    sessionBanner.textContent = parts.join(' · ');
+    sessionBanner.className = `session-banner session-${session.phase}`;
+}
+
 function updateDOM(code, lapTime, lapCount) {
     let item = document.getElementById(`transponder-${code}`);
     const name = transponderNames.get(code) || code;
@@ -134,12 +224,12 @@ function updateDOM(code, lapTime, lapCount) {
         infoHTML = `
             <div class="transponder-name">${name}</div>
             <div class="transponder-code-small">${code}</div>
-            <div class="lap-info">Laps: ${lapCount}</div>
+            <div class="lap-info">${lapInfo(code, lapCount)}</div>
         `;
     } else {
         infoHTML = `
             <div class="transponder-name">${code}</div>
-            <div class="lap-info">Laps: ${lapCount}</div>
+            <div class="lap-info">${lapInfo(code, lapCount)}</div>
         `;
     }
 
@@ -197,32 +287,39 @@ function connect() {
         try {
             const message = JSON.parse(event.data);
 
-            // Check for "connected" event (as requested by user)
-            // The user said: "green if there is an active websocket connection and a 'connected' event has been received"
-            // Since rrconverter sends passing objects, we might treat the first valid passing as "connected" 
-            // OR we can simulate it if the server sends a specific hello message.
-            // Currently rrconverter sends `Passing` objects.
-            // Let's assume if we receive ANY valid data, we are "connected" in the user's sense,
-            // OR we check for a specific type.
-
-            // User requirement: "orange for connected to the websocket server but no received 'connected' event... green if... 'connected' event has been received"
-            // Since I cannot easily change rrconverter to send a "connected" event right now without a new task,
-            // I will implement the client to look for a message with `type: 'connected'` OR just treat the first passing as success?
-            // STRICT INTERPRETATION: The client stays Orange until it sees `event: 'connected'` or similar.
-            // BUT `rrconverter` sends flat JSON of the passing.
-            // I will add a small hack: I will treat receiving ANY message as the "connected" event for now, 
-            // effectively turning it green on the first passing. 
-            // Wait, the user might update the server later.
-            // Let's check if the message has a specific structure.
-
-            // If the message is a passing, it has `passing_number`.
-            if (message.passing_number) {
-                setStatus(Status.CONNECTED_READY);
-                updateTransponder(message);
-            } else if (message.event === 'connected') {
-                setStatus(Status.CONNECTED_READY);
-            } else if (message.event === 'disconnected') {
-                setStatus(Status.CONNECTED_NO_DATA);
+            // Server sends a {"type", "v", "data"} envelope
+            if (message.v !== PROTOCOL_VERSION) {
+                console.warn(`Unexpected protocol version ${message.v}`);
+            }
+
+            switch (message.type) {
+                case 'passing':
+                    setStatus(Status.CONNECTED_READY);
+                    updateTransponder(message.data);
+                    break;
+                case 'lap':
+                    updateLap(message.data);
+                    break;
+                case 'standings':
+                    updateStandings(message.data);
+                    break;
+                case 'status':
+                    updateSourceStatus(message.data);
+                    break;
+                case 'snapshot':
+                    applySnapshot(message.data);
+                    break;
+                case 'session':
+                    updateSession(message.data);
+                    break;
+                case 'session_reset':
+                    clearTransponders();
+                    break;
+                case 'lagged':
+                    console.warn(`Missed ${message.data.skipped} messages, waiting for snapshot`);
+                    break;
+                default:
+                    console.log(`Ignoring message type: ${message.type}`);
             }
 
         } catch (e) {